@@ -1,28 +1,463 @@
-use std::collections::HashMap;
-use std::fmt;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::{self, Write};
 
-use crate::instruction::Instruction;
+use crate::cpu::DEFAULT_STACK_DEPTH;
+#[cfg(test)]
+use crate::instruction::encode;
 use crate::instruction::Instruction::*;
+use crate::instruction::{disassemble, Chip8Error, Instruction, Reg};
+use crate::symbols::Symbols;
 
-type SrcProgram<'a> = &'a [(u16, Result<Instruction, String>)];
 type Pc = u16;
+type SrcProgram<'a> = &'a [(Pc, Result<Instruction, Chip8Error>)];
 
-pub fn analyze(prog: SrcProgram) {
+pub fn analyze(prog: SrcProgram, register: Option<Reg>, stats: bool, symbols: &Symbols) {
+    print!("{}", analysis(prog, register, stats, symbols));
+}
+
+/// Everything `analyze` prints. The output only depends on the program, so it can be compared
+/// against a saved copy
+fn analysis(prog: SrcProgram, register: Option<Reg>, stats: bool, symbols: &Symbols) -> String {
     let mut flow_graph = CFG::from_rom(prog.iter().map(|(_, m_instr)| match m_instr {
         Ok(instr) => Some(*instr),
         Err(_) => None,
     }));
 
+    let mut out = String::new();
+    writeln!(out, "{}", decode_coverage(prog)).unwrap();
+
+    // Needs every instruction in its own block, so return addresses are still block starts
+    let stack_issues = flow_graph.stack_imbalances(0x200);
+    let probable_data = flow_graph.probable_data();
+
     flow_graph.reduce();
     flow_graph.reachability_analysis(0x200);
 
-    println!("Control flow graph:");
-    flow_graph.debug_print(true, false);
+    writeln!(out, "Control flow graph:").unwrap();
+    flow_graph
+        .debug_print(&mut out, true, false, symbols)
+        .unwrap();
     flow_graph.assert_valid();
+
+    if stack_issues.is_empty() {
+        writeln!(out, "CALL/RTS balanced on every path").unwrap();
+    }
+    for issue in stack_issues {
+        writeln!(out, "{}", issue).unwrap();
+    }
+
+    if !probable_data.is_empty() {
+        writeln!(
+            out,
+            "Probably data, not SYS: {}",
+            format_pcs(&probable_data)
+        )
+        .unwrap();
+    }
+
+    if let Some(reg) = register {
+        let (reads, writes) = flow_graph.register_uses(reg);
+        writeln!(out, "v{:X} read at: {}", reg, format_pcs(&reads)).unwrap();
+        writeln!(out, "v{:X} written at: {}", reg, format_pcs(&writes)).unwrap();
+    }
+
+    if stats {
+        writeln!(out, "{}", flow_graph.stats()).unwrap();
+    }
+    out
+}
+
+/// A path through the program where `CALL`s and `RTS`s don't match up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackIssue {
+    /// `RTS` reached with nothing on the stack
+    EmptyReturn(Pc),
+    /// Reached with different numbers of return addresses on the stack, so some path leaks a
+    /// frame (e.g. jumps out of a subroutine) or returns too many times
+    Unbalanced { pc: Pc, depths: (usize, usize) },
+    /// `CALL` reached with a full stack, from subroutines nested too deep
+    Overflow(Pc),
+}
+
+impl fmt::Display for StackIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StackIssue::EmptyReturn(pc) => write!(f, "{:#x}: RTS with an empty stack", pc),
+            StackIssue::Unbalanced { pc, depths } => write!(
+                f,
+                "{:#x}: reached with both {} and {} return addresses on the stack",
+                pc, depths.0, depths.1
+            ),
+            StackIssue::Overflow(pc) => write!(f, "{:#x}: CALL with a full stack", pc),
+        }
+    }
+}
+
+/// What following a routine from its entry found
+#[derive(Debug, Clone, Default)]
+struct RoutineSummary {
+    /// Some path reaches an `RTS`
+    returns: bool,
+    /// The `CALL`s in the deepest chain of nested calls it makes, outermost first
+    calls: Vec<Pc>,
+}
+
+/// State for `CFG::stack_imbalances`
+struct StackWalk<'a> {
+    cfg: &'a CFG,
+    start: Pc,
+    /// The entry of the routine each instruction was first reached in, and how many return
+    /// addresses were on the stack when that routine was first entered
+    owner: HashMap<Pc, (Pc, usize)>,
+    summaries: HashMap<Pc, RoutineSummary>,
+    issues: BTreeMap<Pc, StackIssue>,
+}
+
+impl StackWalk<'_> {
+    /// Follow the routine at `entry`, first entered with `depth` return addresses on the stack
+    fn routine(&mut self, entry: Pc, depth: usize) -> RoutineSummary {
+        if let Some(summary) = self.summaries.get(&entry) {
+            return summary.clone();
+        }
+        // Stands in for the summary while we're still following the routine
+        let recursive = RoutineSummary {
+            returns: true,
+            calls: Vec::new(),
+        };
+        self.summaries.insert(entry, recursive);
+
+        let mut summary = RoutineSummary::default();
+        let mut worklist = vec![entry];
+        while let Some(pc) = worklist.pop() {
+            match self.owner.get(&pc) {
+                Some(&(owner, _)) if owner == entry => continue,
+                Some(&(_, seen)) => {
+                    if seen != depth {
+                        self.issues.entry(pc).or_insert(StackIssue::Unbalanced {
+                            pc,
+                            depths: (seen.min(depth), seen.max(depth)),
+                        });
+                    }
+                    continue;
+                }
+                None => self.owner.insert(pc, (entry, depth)),
+            };
+
+            let Some(block) = self.cfg.contents.get(&pc) else {
+                continue;
+            };
+            match block.code.last().map(|instr| instr.instruction) {
+                Some(RTS) if entry == self.start => {
+                    self.issues.insert(pc, StackIssue::EmptyReturn(pc));
+                }
+                Some(RTS) => summary.returns = true,
+                Some(CALL(target)) => {
+                    let callee = self.routine(target, depth + 1);
+                    if callee.calls.len() + 1 > summary.calls.len() {
+                        summary.calls = [pc].into_iter().chain(callee.calls).collect();
+                    }
+                    if callee.returns {
+                        worklist.extend(block.return_address);
+                    }
+                }
+                _ => worklist.extend(&block.next),
+            }
+        }
+        self.summaries.insert(entry, summary.clone());
+        summary
+    }
+}
+
+/// Size of a ROM's control flow graph, as a rough measure of its complexity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CfgStats {
+    pub blocks: usize,
+    pub reachable_blocks: usize,
+    pub reachable_instructions: usize,
+    /// Reachable instructions with more than one successor (skips)
+    pub branch_points: usize,
+}
+
+impl fmt::Display for CfgStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Blocks: {}", self.blocks)?;
+        writeln!(f, "Reachable blocks: {}", self.reachable_blocks)?;
+        writeln!(f, "Reachable instructions: {}", self.reachable_instructions)?;
+        write!(f, "Branch points: {}", self.branch_points)
+    }
+}
+
+/// How much of a ROM decodes to valid instructions, counting every word whether or not it is
+/// reachable. Mostly valid means it's probably CHIP-8 code; a lot of invalid words means it's
+/// data-heavy, or for another machine (like SUPER-CHIP)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeCoverage {
+    pub words: usize,
+    /// Addresses of the words that don't decode
+    pub invalid: Vec<Pc>,
+}
+
+impl DecodeCoverage {
+    /// Fraction of words that decode, between 0 and 1
+    pub fn valid_fraction(&self) -> f64 {
+        if self.words == 0 {
+            1.
+        } else {
+            (self.words - self.invalid.len()) as f64 / self.words as f64
+        }
+    }
+}
+
+impl fmt::Display for DecodeCoverage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Valid instructions: {}/{} words ({:.1}%)",
+            self.words - self.invalid.len(),
+            self.words,
+            self.valid_fraction() * 100.
+        )?;
+        if !self.invalid.is_empty() {
+            write!(f, "\nInvalid words at: {}", format_pcs(&self.invalid))?;
+        }
+        Ok(())
+    }
+}
+
+pub fn decode_coverage(prog: SrcProgram) -> DecodeCoverage {
+    DecodeCoverage {
+        words: prog.len(),
+        invalid: prog
+            .iter()
+            .filter(|(_, instr)| instr.is_err())
+            .map(|&(pc, _)| pc)
+            .collect(),
+    }
+}
+
+/// A sprite referenced by a `DRAW` instruction: `height` bytes starting at `addr`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Sprite {
+    pub addr: Pc,
+    pub height: u8,
+}
+
+impl Sprite {
+    /// The sprite's rows, one byte each. Bytes outside the ROM are blank.
+    pub fn rows(&self, rom: &[u8]) -> Vec<u8> {
+        (0..self.height as usize)
+            .map(|row| {
+                (self.addr as usize + row)
+                    .checked_sub(0x200)
+                    .and_then(|offset| rom.get(offset))
+                    .copied()
+                    .unwrap_or(0)
+            })
+            .collect()
+    }
+
+    /// Render the sprite as text, one line per row. Bytes outside the ROM render as blank.
+    pub fn render(&self, rom: &[u8]) -> String {
+        let mut out = String::new();
+        for byte in self.rows(rom) {
+            for bitidx in 0..8 {
+                out.push(if byte & (1 << (7 - bitidx)) != 0 {
+                    '█'
+                } else {
+                    '·'
+                });
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Find every sprite drawn by the reachable code, resolving the index register at each `DRAW`
+/// through constant propagation. `DRAW`s where the index can't be determined are skipped.
+pub fn sprites(rom: &[u8]) -> Vec<Sprite> {
+    sprite_draws(rom)
+        .into_values()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// The sprite drawn by each reachable `DRAW` whose index register is known
+fn sprite_draws(rom: &[u8]) -> BTreeMap<Pc, Sprite> {
+    let mut flow_graph = CFG::from_rom(disassemble(rom, 0x200).map(|(_, instr)| instr.ok()));
+    flow_graph.reduce();
+
+    let states = flow_graph.constant_propagation(0x200);
+    let mut draws = BTreeMap::new();
+    for block in flow_graph.contents.values() {
+        for instr in &block.code {
+            if let (DRAW(_, _, n), Some(state)) = (instr.instruction, states.get(&instr.pc)) {
+                if let Known(addr) = state.idx {
+                    draws.insert(instr.pc, Sprite { addr, height: n });
+                }
+            }
+        }
+    }
+    draws
+}
+
+/// A word that differs between two versions of a ROM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomChange {
+    pub addr: Pc,
+    /// The bytes at `addr` in each version. Shorter than a word at the end of an odd-sized ROM,
+    /// and empty past the end
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
+    /// Whether `addr` is reachable code in either version. Otherwise it's data
+    pub code: bool,
+}
+
+/// The instruction in `bytes`, or the raw bytes if they aren't a whole valid instruction
+fn describe_word(bytes: &[u8], code: bool) -> String {
+    let instr = match bytes {
+        [high, low] if code => Instruction::try_from(u16::from_be_bytes([*high, *low])).ok(),
+        _ => None,
+    };
+    match instr {
+        Some(instr) => instr.to_string(),
+        None if bytes.is_empty() => "(none)".to_string(),
+        None => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+    }
+}
+
+impl fmt::Display for RomChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#05x}: {}{} -> {}",
+            self.addr,
+            if self.code { "" } else { "data " },
+            describe_word(&self.old, self.code),
+            describe_word(&self.new, self.code)
+        )
+    }
+}
+
+/// Every word that differs between two versions of a ROM, lined up by address (CHIP-8 code
+/// isn't relocatable, so nothing moves unless the whole program does)
+pub fn rom_diff(old: &[u8], new: &[u8]) -> Vec<RomChange> {
+    let (old_code, new_code) = (reachable_addresses(old), reachable_addresses(new));
+    let word = |rom: &[u8], i: usize| rom.chunks(2).nth(i).unwrap_or_default().to_vec();
+    (0..old.len().max(new.len()).div_ceil(2))
+        .filter_map(|i| {
+            let addr = 0x200 + 2 * i as Pc;
+            let (old, new) = (word(old, i), word(new, i));
+            (old != new).then(|| RomChange {
+                addr,
+                old,
+                new,
+                code: old_code.contains(&addr) || new_code.contains(&addr),
+            })
+        })
+        .collect()
+}
+
+/// Addresses of the instructions that can run when starting from 0x200, following jumps, calls
+/// and skips
+pub fn reachable_addresses(rom: &[u8]) -> BTreeSet<Pc> {
+    let mut flow_graph = CFG::from_rom(disassemble(rom, 0x200).map(|(_, instr)| instr.ok()));
+    if !flow_graph.contents.contains_key(&0x200) {
+        return BTreeSet::new();
+    }
+    // Not reduced, so every instruction is still its own block
+    flow_graph
+        .reachability_analysis(0x200)
+        .into_iter()
+        .filter(|pc| !flow_graph.contents[pc].code.is_empty())
+        .collect()
+}
+
+/// Every address a `JUMP`, `CALL` or `JUMPI` in the ROM goes to, for labelling. For `JUMPI`
+/// that's the base address, before the register is added
+pub fn control_targets(rom: &[u8]) -> BTreeSet<Pc> {
+    disassemble(rom, 0x200)
+        .filter_map(|(pc, instr)| match instr.ok()? {
+            instr @ (JUMP(_) | CALL(_)) => AnalyzeInstruction::new(pc, instr).next_pc(pc).pop(),
+            JUMPI(addr) => Some(addr),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Addresses that jump, call or skip to each instruction. Falling through from the previous
+/// instruction doesn't count.
+fn cross_references(rom: &[u8]) -> HashMap<Pc, Vec<Pc>> {
+    let flow_graph = CFG::from_rom(disassemble(rom, 0x200).map(|(_, instr)| instr.ok()));
+    flow_graph
+        .contents
+        .iter()
+        .map(|(&pc, block)| {
+            let mut refs: Vec<Pc> = block
+                .prev
+                .iter()
+                .copied()
+                .filter(|&prev| {
+                    let jumps = flow_graph.contents[&prev]
+                        .code
+                        .iter()
+                        .any(|instr| matches!(instr.instruction, JUMP(_) | CALL(_)));
+                    jumps || prev + 2 != pc
+                })
+                .collect();
+            refs.sort();
+            (pc, refs)
+        })
+        .filter(|(_, refs)| !refs.is_empty())
+        .collect()
+}
+
+/// Disassembly of the ROM, one instruction per line, with names from `symbols`. With `xref`,
+/// lines that other instructions jump, call or skip to list where from. With `render_sprites`,
+/// `DRAW`s whose sprite is known are followed by the sprite.
+pub fn listing(rom: &[u8], xref: bool, render_sprites: bool, symbols: &Symbols) -> String {
+    let xrefs = if xref {
+        cross_references(rom)
+    } else {
+        HashMap::new()
+    };
+    let draws = if render_sprites {
+        sprite_draws(rom)
+    } else {
+        BTreeMap::new()
+    };
+    let mut out = String::new();
+    for (addr, m_instruction) in disassemble(rom, 0x200) {
+        let offset = addr as usize - 0x200;
+        let bits = u16::from_be_bytes([rom[offset], rom[offset + 1]]);
+        match m_instruction {
+            Ok(i) => out += &format!("{:#x}: {:x} - {}", addr, bits, i.format_with(symbols)),
+            Err(_) => out += &format!("{:#x}: {:x} - ????", addr, bits),
+        }
+        if let Some(refs) = xrefs.get(&addr) {
+            out += &format!("    ; referenced by: {}", format_pcs(refs));
+        }
+        out.push('\n');
+        if let Some(sprite) = draws.get(&addr) {
+            for row in sprite.render(rom).lines() {
+                out += &format!("        {}\n", row);
+            }
+        }
+    }
+    out
+}
+
+fn format_pcs(pcs: &[Pc]) -> String {
+    pcs.iter()
+        .map(|pc| format!("{:#x}", pc))
+        .collect::<Vec<_>>()
+        .join(" ")
 }
 
 // ---------
 
+#[allow(clippy::upper_case_acronyms)]
 struct CFG {
     contents: HashMap<Pc, Block>,
 }
@@ -39,8 +474,92 @@ struct Block {
     reachable: bool,
 }
 
+/// Statically known value of a register
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum ConstVal {
+    Known(u16),
+    Unknown,
+}
+use ConstVal::*;
+
+impl ConstVal {
+    fn meet(self, other: ConstVal) -> ConstVal {
+        if self == other {
+            self
+        } else {
+            Unknown
+        }
+    }
+
+    fn map2(self, other: ConstVal, f: impl Fn(u16, u16) -> u16) -> ConstVal {
+        match (self, other) {
+            (Known(a), Known(b)) => Known(f(a, b)),
+            _ => Unknown,
+        }
+    }
+}
+
+/// Known register values at a point in the program
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct ConstState {
+    reg: [ConstVal; 16],
+    idx: ConstVal,
+}
+
+impl ConstState {
+    /// State of a freshly reset CPU
+    fn initial() -> Self {
+        ConstState {
+            reg: [Known(0); 16],
+            idx: Known(0),
+        }
+    }
+
+    fn unknown() -> Self {
+        ConstState {
+            reg: [Unknown; 16],
+            idx: Unknown,
+        }
+    }
+
+    fn meet(&self, other: &ConstState) -> ConstState {
+        let mut reg = self.reg;
+        for (r, o) in reg.iter_mut().zip(other.reg) {
+            *r = r.meet(o);
+        }
+        ConstState {
+            reg,
+            idx: self.idx.meet(other.idx),
+        }
+    }
+
+    /// State after executing `instr`
+    fn transfer(&self, instr: Instruction) -> ConstState {
+        let mut next = *self;
+        for r in instr.writes() {
+            next.reg[r as usize] = Unknown;
+        }
+
+        match instr {
+            LOAD(x, n) => next.reg[x as usize] = Known(n as u16),
+            ADD(x, n) => {
+                next.reg[x as usize] =
+                    self.reg[x as usize].map2(Known(n as u16), |a, b| (a + b) & 0xFF)
+            }
+            MOVE(x, y) => next.reg[x as usize] = self.reg[y as usize],
+            LOADI(addr) => next.idx = Known(addr),
+            ADDI(x) => next.idx = self.idx.map2(self.reg[x as usize], |a, b| a + b),
+            LDSPR(x) => next.idx = self.reg[x as usize].map2(Known(5), |a, b| a * b),
+            STOR(x) | READ(x) => next.idx = self.idx.map2(Known(x as u16 + 1), |a, b| a + b),
+            _ => {}
+        }
+        next
+    }
+}
+
 #[derive(Clone, Copy)]
 struct AnalyzeInstruction {
+    pc: Pc,
     instruction: Instruction,
 }
 
@@ -54,7 +573,7 @@ impl CFG {
                 if let Some(instr) = m_instr {
                     (
                         this_pc,
-                        Block::from_single(this_pc, AnalyzeInstruction::new(instr)),
+                        Block::from_single(this_pc, AnalyzeInstruction::new(this_pc, instr)),
                     )
                 } else {
                     (this_pc, Block::new_empty())
@@ -64,7 +583,7 @@ impl CFG {
 
         for (pc, block) in contents.clone().iter_mut() {
             for next_pc in block.next.iter_mut() {
-                match contents.get_mut(&next_pc) {
+                match contents.get_mut(next_pc) {
                     Some(next) => {
                         next.prev.push(*pc);
                     }
@@ -82,55 +601,81 @@ impl CFG {
         cfg
     }
 
-    fn debug_print(&self, terse: bool, skip_unreachable: bool) {
+    /// One entry per block, sorted by address:
+    ///
+    /// ```text
+    /// 0x200 [R-] <- START 0x20a
+    ///   0x200  LOAD v0, 0x01
+    ///   -> 0x202 0x204
+    /// ```
+    ///
+    /// The flags are `R` for reachable and `C` for ending in a `CALL`, `-` if not set. Addresses
+    /// are always 3 hex digits and sorted, so the output can be diffed and grepped.
+    fn debug_print(
+        &self,
+        out: &mut impl Write,
+        terse: bool,
+        skip_unreachable: bool,
+        symbols: &Symbols,
+    ) -> fmt::Result {
         let mut block_pcs = self.contents.keys().collect::<Vec<_>>();
         block_pcs.sort();
         for start in block_pcs {
             let block = &self.contents[start];
-            if terse {
-                if (block.prev.is_empty() || block.code.is_empty()) && *start != 0x200 {
-                    continue;
-                }
+            // Blocks after a CALL have no prev of their own, but are reachable through the RTS
+            let orphan = block.prev.is_empty() && !block.reachable;
+            if terse && (orphan || block.code.is_empty()) && *start != 0x200 {
+                continue;
             }
 
             if skip_unreachable && !block.reachable {
                 continue;
             }
 
-            print!("{:#x}:", start);
-
-            print!(" <- ");
+            let reachable = if block.reachable { 'R' } else { '-' };
+            let call = if block.return_address.is_some() {
+                'C'
+            } else {
+                '-'
+            };
+            write!(
+                out,
+                "{} [{}{}] <-",
+                symbols.describe(*start),
+                reachable,
+                call
+            )?;
             if *start == 0x200 {
-                print!("START ");
+                write!(out, " START")?;
             }
-
-            for pc in &block.prev {
-                print!("{:#x} ", pc);
+            let mut prev = block.prev.clone();
+            prev.sort();
+            for pc in prev {
+                write!(out, " {:#05x}", pc)?;
             }
-
-            // Flags
-            print!(" | ");
-            if block.reachable {
-                print!(" R")
-            } else {
-                print!("!R")
-            }
-
-            println!();
+            writeln!(out)?;
 
             for instr in &block.code {
-                println!("  {}", instr);
+                writeln!(
+                    out,
+                    "  {:#05x}  {}",
+                    instr.pc,
+                    instr.instruction.format_with(symbols)
+                )?;
             }
 
-            print!("  -> ");
+            write!(out, "  ->")?;
             if let Some(ra) = block.return_address {
-                print!("[{:#x}] ", ra);
+                write!(out, " [{:#05x}]", ra)?;
             }
-            for pc in &block.next {
-                print!("{:#x} ", pc);
+            let mut next = block.next.clone();
+            next.sort();
+            for pc in next {
+                write!(out, " {:#05x}", pc)?;
             }
-            println!("\n");
+            writeln!(out, "\n")?;
         }
+        Ok(())
     }
 
     fn assert_valid(&self) -> &Self {
@@ -138,7 +683,7 @@ impl CFG {
             for next in &block.next {
                 // Next exists
                 assert!(
-                    self.contents.contains_key(&next),
+                    self.contents.contains_key(next),
                     "Invalid CFG: {:#x} -> {:#x}, which does not exist",
                     pc,
                     next
@@ -146,7 +691,7 @@ impl CFG {
 
                 // Next and prev pointers match
                 assert!(
-                    self.contents[next].prev.contains(&pc),
+                    self.contents[next].prev.contains(pc),
                     "Invalid CFG: {:#x} -> {:#x} but not the other way",
                     pc,
                     next
@@ -160,10 +705,14 @@ impl CFG {
         let mut progress = true;
         while progress {
             self.assert_valid();
-            let keys: Vec<u16> = self.contents.keys().map(|k| *k).collect();
+            let keys: Vec<u16> = self.contents.keys().copied().collect();
             progress = false;
             'step: for master_pc in keys {
-                if self.contents.get(&master_pc).map_or(false, |b| b.can_absorb()) {
+                if self
+                    .contents
+                    .get(&master_pc)
+                    .is_some_and(|b| b.can_absorb())
+                {
                     let absorb_pc = self.contents.get(&master_pc).unwrap().next[0];
                     let absorb_block = self.contents.get(&absorb_pc).unwrap();
                     if absorb_block.prev.len() == 1 {
@@ -192,35 +741,167 @@ impl CFG {
 
     #[allow(dead_code)]
     fn get_block(&self, pc: Pc) -> &Block {
-        self.contents.get(&pc).expect(&format!("Block {}", pc))
+        self.contents
+            .get(&pc)
+            .unwrap_or_else(|| panic!("Block {}", pc))
     }
 
     #[allow(dead_code)]
     fn get_block_mut(&mut self, pc: Pc) -> &mut Block {
-        self.contents.get_mut(&pc).expect(&format!("Block {}", pc))
+        self.contents
+            .get_mut(&pc)
+            .unwrap_or_else(|| panic!("Block {}", pc))
     }
 
     #[allow(dead_code)]
     fn keys(&self) -> Vec<Pc> {
-        self.contents.keys().map(|k| *k).collect()
+        self.contents.keys().copied().collect()
     }
 
-    fn reachability_analysis(&mut self, start: Pc) {
-        let block = self
-            .contents
-            .get_mut(&start)
-            .expect(&format!("block {}", start));
-        // Already analysed
-        if block.reachable {
-            return;
+    /// Find every instruction that reads or writes `reg`. Returns `(reads, writes)`
+    fn register_uses(&self, reg: Reg) -> (Vec<Pc>, Vec<Pc>) {
+        let mut reads = Vec::new();
+        let mut writes = Vec::new();
+        for block in self.contents.values() {
+            for instr in &block.code {
+                if instr.instruction.reads().contains(&reg) {
+                    reads.push(instr.pc);
+                }
+                if instr.instruction.writes().contains(&reg) {
+                    writes.push(instr.pc);
+                }
+            }
+        }
+        reads.sort_unstable();
+        writes.sort_unstable();
+        (reads, writes)
+    }
+
+    /// Forward constant propagation from `start`. Returns the known state before each
+    /// instruction that can be reached. Subroutine calls are assumed to clobber everything.
+    fn constant_propagation(&self, start: Pc) -> HashMap<Pc, ConstState> {
+        let mut entry: HashMap<Pc, ConstState> = HashMap::new();
+        let mut before: HashMap<Pc, ConstState> = HashMap::new();
+        let mut worklist = vec![(start, ConstState::initial())];
+
+        while let Some((pc, incoming)) = worklist.pop() {
+            let state = match entry.get(&pc) {
+                Some(old) => {
+                    let merged = old.meet(&incoming);
+                    if merged == *old {
+                        continue;
+                    }
+                    merged
+                }
+                None => incoming,
+            };
+            entry.insert(pc, state);
+
+            let block = match self.contents.get(&pc) {
+                Some(block) => block,
+                None => continue,
+            };
+
+            let mut state = state;
+            for instr in &block.code {
+                before.insert(instr.pc, state);
+                state = state.transfer(instr.instruction);
+            }
+
+            for next in &block.next {
+                worklist.push((*next, state));
+            }
+            if let Some(ra) = block.return_address {
+                worklist.push((ra, ConstState::unknown()));
+            }
         }
-        block.reachable = true;
 
-        let nexts = block.next.clone();
+        before
+    }
 
-        for next in nexts {
-            self.reachability_analysis(next);
+    /// Follow every path from `start`, counting return addresses on the stack, and report where
+    /// they don't balance. Each subroutine is followed once from its entry, and a `CALL` carries
+    /// on after the call if the subroutine can return, so one called at several depths only
+    /// shows up once. Code is reported when it's reached from routines entered at different
+    /// depths, e.g. when a subroutine jumps back into its caller. Recursive calls are assumed to
+    /// return. Expects one instruction per block, i.e. before `reduce`.
+    fn stack_imbalances(&self, start: Pc) -> Vec<StackIssue> {
+        let mut walk = StackWalk {
+            cfg: self,
+            start,
+            owner: HashMap::new(),
+            summaries: HashMap::new(),
+            issues: BTreeMap::new(),
+        };
+        let calls = walk.routine(start, 0).calls;
+        if let Some(&pc) = calls.get(DEFAULT_STACK_DEPTH) {
+            walk.issues.insert(pc, StackIssue::Overflow(pc));
         }
+        walk.issues.into_values().collect()
+    }
+
+    /// `SYS nnn` (nnn != 0) instructions that can only be reached by falling through from an
+    /// instruction that doesn't branch, or not at all. Data in the 0x0nnn range decodes as `SYS`,
+    /// while real `SYS` calls are rare, so these are most likely data. Expects one instruction
+    /// per block, i.e. before `reduce`.
+    fn probable_data(&self) -> Vec<Pc> {
+        let falls_through = |prev: Pc, pc: Pc| {
+            prev + 2 == pc
+                && self.contents[&prev].code.iter().all(|instr| {
+                    !instr.branches() && !matches!(instr.instruction, JUMP(_) | CALL(_))
+                })
+        };
+        let mut data: Vec<Pc> = self
+            .contents
+            .iter()
+            .filter(|(_, block)| match block.code[..] {
+                [instr] => matches!(instr.instruction, SYS(addr) if addr != 0),
+                _ => false,
+            })
+            .filter(|(&pc, block)| block.prev.iter().all(|&prev| falls_through(prev, pc)))
+            .map(|(&pc, _)| pc)
+            .collect();
+        data.sort_unstable();
+        data
+    }
+
+    /// Only meaningful after `reachability_analysis`
+    fn stats(&self) -> CfgStats {
+        let reachable = || self.contents.values().filter(|b| b.reachable);
+        CfgStats {
+            blocks: self.contents.len(),
+            reachable_blocks: reachable().count(),
+            reachable_instructions: reachable().map(|b| b.code.len()).sum(),
+            branch_points: reachable()
+                .flat_map(|b| &b.code)
+                .filter(|instr| instr.branches())
+                .count(),
+        }
+    }
+
+    /// Mark every block reachable from `start`, and return their addresses
+    fn reachability_analysis(&mut self, start: Pc) -> BTreeSet<Pc> {
+        let mut reachable = BTreeSet::new();
+        let mut worklist = vec![start];
+        while let Some(pc) = worklist.pop() {
+            // Already analysed
+            if !reachable.insert(pc) {
+                continue;
+            }
+            let block = self
+                .contents
+                .get_mut(&pc)
+                .unwrap_or_else(|| panic!("block {}", pc));
+            block.reachable = true;
+            worklist.extend(&block.next);
+            // Calls come back to the instruction after them, if there is one
+            if let Some(ra) = block.return_address {
+                if self.contents.contains_key(&ra) {
+                    worklist.push(ra);
+                }
+            }
+        }
+        reachable
     }
 }
 
@@ -267,8 +948,8 @@ impl Block {
 }
 
 impl AnalyzeInstruction {
-    fn new(instruction: Instruction) -> AnalyzeInstruction {
-        Self { instruction }
+    fn new(pc: Pc, instruction: Instruction) -> AnalyzeInstruction {
+        Self { pc, instruction }
     }
 
     fn next_pc(&self, this_pc: Pc) -> Vec<Pc> {
@@ -282,6 +963,7 @@ impl AnalyzeInstruction {
             CALL(addr) => {
                 vec![addr]
             }
+            LOADILONG(_) => vec![this_pc + 4],
             // TODO: What should be the next of an RTS?
             RTS => {
                 vec![]
@@ -290,24 +972,17 @@ impl AnalyzeInstruction {
         }
     }
 
-    #[allow(dead_code)]
     fn branches(&self) -> bool {
         self.next_pc(0).len() > 1
     }
 
     #[allow(dead_code)]
     fn is_rts(&self) -> bool {
-        match self.instruction {
-            RTS => true,
-            _ => false,
-        }
+        matches!(self.instruction, RTS)
     }
 
     fn is_call(&self) -> bool {
-        match self.instruction {
-            CALL { .. } => true,
-            _ => false,
-        }
+        matches!(self.instruction, CALL { .. })
     }
 }
 
@@ -326,3 +1001,397 @@ fn addr_to_idx(addr: Pc) -> Option<usize> {
 fn idx_to_addr(idx: usize) -> Pc {
     200 * (idx * 2) as Pc
 }
+
+#[test]
+fn register_uses_v0() {
+    let cfg = CFG::from_rom(
+        [
+            LOAD(0, 5),  // 0x200
+            LOAD(1, 3),  // 0x202
+            ADDR(1, 0),  // 0x204
+            ADDR(0, 1),  // 0x206
+            MOVE(2, 1),  // 0x208
+            STOR(2),     // 0x20a
+            JUMPI(0x20), // 0x20c
+        ]
+        .into_iter()
+        .map(Some),
+    );
+
+    let (reads, writes) = cfg.register_uses(0);
+    assert_eq!(reads, vec![0x204, 0x206, 0x20a, 0x20c]);
+    assert_eq!(writes, vec![0x200, 0x206]);
+}
+
+#[test]
+fn sprites_two_draws() {
+    let mut rom = encode(&[
+        LOADI(0x20c),  // 0x200
+        DRAW(0, 1, 2), // 0x202
+        LOADI(0x20e),  // 0x204
+        DRAW(0, 1, 1), // 0x206
+        DRAW(0, 1, 1), // 0x208
+        JUMP(0x20a),   // 0x20a
+    ]);
+    rom.extend([0xF0, 0x90, 0xFF]);
+
+    let found = sprites(&rom);
+    assert_eq!(
+        found,
+        vec![
+            Sprite {
+                addr: 0x20c,
+                height: 2
+            },
+            Sprite {
+                addr: 0x20e,
+                height: 1
+            },
+        ]
+    );
+    assert_eq!(found[0].render(&rom), "████····\n█··█····\n");
+}
+
+#[test]
+fn listing_renders_sprites() {
+    let mut rom = encode(&[LOADI(0x206), DRAW(0, 1, 2), JUMP(0x204)]);
+    rom.extend([0xF0, 0x90]);
+
+    let listing = listing(&rom, false, true, &Symbols::default());
+    let lines: Vec<&str> = listing.lines().collect();
+    assert!(lines[1].ends_with("DRAW  v0, v1, 0x2"), "{}", lines[1]);
+    assert_eq!(lines[2], "        ████····");
+    assert_eq!(lines[3], "        █··█····");
+    assert!(lines[4].ends_with("JUMP  0x204"), "{}", lines[4]);
+    assert_eq!(lines.len(), 6);
+}
+
+#[test]
+fn decode_coverage_counts_every_word() {
+    // 0x5001 and 0xF0FF don't decode. The data after the JUMP is counted even though it never runs
+    let rom = [0x60, 0x01, 0x12, 0x02, 0x50, 0x01, 0xF0, 0xFF];
+    let prog: Vec<_> = disassemble(&rom, 0x200).collect();
+    let coverage = decode_coverage(&prog);
+    assert_eq!(
+        coverage,
+        DecodeCoverage {
+            words: 4,
+            invalid: vec![0x204, 0x206],
+        }
+    );
+    assert_eq!(coverage.valid_fraction(), 0.5);
+    assert_eq!(
+        coverage.to_string(),
+        "Valid instructions: 2/4 words (50.0%)\nInvalid words at: 0x204 0x206"
+    );
+}
+
+#[test]
+fn reachable_addresses_skip_dead_code() {
+    let rom = encode(&[
+        LOAD(0, 1),
+        JUMP(0x206),
+        LOAD(1, 1),
+        SKE(0, 1),
+        RTS,
+        JUMP(0x20a),
+    ]);
+    assert_eq!(
+        reachable_addresses(&rom),
+        BTreeSet::from([0x200, 0x202, 0x206, 0x208, 0x20a])
+    );
+    assert!(reachable_addresses(&[]).is_empty());
+}
+
+#[test]
+fn reachable_addresses_after_call() {
+    let rom = encode(&[
+        CALL(0x206),
+        LOAD(0, 1),
+        JUMP(0x208),
+        RTS,
+        // The last instruction, so there's nothing to return to
+        CALL(0x206),
+    ]);
+    assert_eq!(
+        reachable_addresses(&rom),
+        BTreeSet::from([0x200, 0x202, 0x204, 0x206, 0x208])
+    );
+}
+
+#[test]
+fn control_targets_jump_call_jumpi() {
+    let rom = encode(&[CALL(0x208), JUMP(0x200), JUMPI(0x300), SKE(0, 1), RTS]);
+    assert_eq!(control_targets(&rom), BTreeSet::from([0x200, 0x208, 0x300]));
+}
+
+#[test]
+fn sys_fallen_into_is_probable_data() {
+    let cfg = CFG::from_rom(
+        [
+            LOAD(0, 1),  // 0x200
+            SYS(0x123),  // 0x202, data after the code
+            SYS(0x456),  // 0x204
+            JUMP(0x20a), // 0x206
+            SYS(0x789),  // 0x208, never reached
+            SYS(0x0ab),  // 0x20a, jumped to, so maybe a real call
+            SYS(0),      // 0x20c, the usual way to end
+        ]
+        .into_iter()
+        .map(Some),
+    );
+    assert_eq!(cfg.probable_data(), vec![0x202, 0x204, 0x208]);
+}
+
+#[test]
+fn rom_diff_reports_changed_instruction() {
+    let assemble = |code: &[Instruction], data: &[u8]| -> Vec<u8> {
+        let mut rom = encode(code);
+        rom.extend(data);
+        rom
+    };
+    let old = assemble(&[LOAD(0, 1), LOADI(0x206), JUMP(0x204)], &[0xF0, 0x90, 0x7]);
+    let new = assemble(&[LOAD(0, 2), LOADI(0x206), JUMP(0x204)], &[0xF0, 0x80]);
+
+    let changes = rom_diff(&old, &new);
+    assert_eq!(
+        changes,
+        vec![
+            RomChange {
+                addr: 0x200,
+                old: vec![0x60, 0x01],
+                new: vec![0x60, 0x02],
+                code: true,
+            },
+            RomChange {
+                addr: 0x206,
+                old: vec![0xF0, 0x90],
+                new: vec![0xF0, 0x80],
+                code: false,
+            },
+            RomChange {
+                addr: 0x208,
+                old: vec![0x7],
+                new: vec![],
+                code: false,
+            },
+        ]
+    );
+    assert_eq!(
+        changes[0].to_string(),
+        "0x200: LOAD  v0, 0x1 -> LOAD  v0, 0x2"
+    );
+    assert_eq!(changes[1].to_string(), "0x206: data f090 -> f080");
+    assert_eq!(changes[2].to_string(), "0x208: data 07 -> (none)");
+    assert!(rom_diff(&old, &old).is_empty());
+}
+
+#[test]
+fn cfg_stats_small_program() {
+    let mut cfg = CFG::from_rom(
+        [
+            LOAD(0, 5),  // 0x200
+            SKE(0, 5),   // 0x202
+            JUMP(0x202), // 0x204
+            JUMP(0x206), // 0x206
+            LOAD(1, 1),  // 0x208, unreachable
+            RTS,         // 0x20a, unreachable
+        ]
+        .into_iter()
+        .map(Some),
+    );
+    cfg.reduce();
+    cfg.reachability_analysis(0x200);
+
+    assert_eq!(
+        cfg.stats(),
+        CfgStats {
+            blocks: 5,
+            reachable_blocks: 4,
+            reachable_instructions: 4,
+            branch_points: 1,
+        }
+    );
+}
+
+#[test]
+fn reachability_long_chain() {
+    const LEN: u16 = 30_000;
+    let mut cfg = CFG::from_rom((0..LEN).map(|_| Some(LOAD(0, 0))).chain([Some(RTS)]));
+
+    let reachable = cfg.reachability_analysis(0x200);
+    assert_eq!(reachable.len(), LEN as usize + 1);
+    assert_eq!(reachable.iter().next_back(), Some(&(0x200 + 2 * LEN)));
+    assert!(cfg.contents.values().all(|b| b.reachable));
+}
+
+#[test]
+fn stack_imbalance_missing_rts() {
+    let cfg = CFG::from_rom(
+        [
+            CALL(0x206), // 0x200
+            JUMP(0x202), // 0x202
+            RTS,         // 0x204, never reached
+            SKE(0, 0),   // 0x206
+            RTS,         // 0x208
+            JUMP(0x202), // 0x20a, leaves the subroutine without returning
+        ]
+        .into_iter()
+        .map(Some),
+    );
+    assert_eq!(
+        cfg.stack_imbalances(0x200),
+        vec![StackIssue::Unbalanced {
+            pc: 0x202,
+            depths: (0, 1)
+        }]
+    );
+}
+
+#[test]
+fn stack_balanced_and_empty_return() {
+    let balanced = CFG::from_rom(
+        [CALL(0x204), JUMP(0x202), SKE(0, 0), RTS, RTS]
+            .into_iter()
+            .map(Some),
+    );
+    assert_eq!(balanced.stack_imbalances(0x200), vec![]);
+
+    let empty = CFG::from_rom([LOAD(0, 1), RTS].into_iter().map(Some));
+    assert_eq!(
+        empty.stack_imbalances(0x200),
+        vec![StackIssue::EmptyReturn(0x202)]
+    );
+}
+
+#[test]
+fn stack_subroutine_called_at_different_depths() {
+    let cfg = CFG::from_rom(
+        [
+            CALL(0x208), // 0x200
+            CALL(0x20c), // 0x202
+            JUMP(0x204), // 0x204
+            RTS,         // 0x206, never reached
+            CALL(0x20c), // 0x208, called at depth 1
+            RTS,         // 0x20a
+            LOAD(0, 1),  // 0x20c, called at depths 1 and 2
+            RTS,         // 0x20e
+        ]
+        .into_iter()
+        .map(Some),
+    );
+    assert_eq!(cfg.stack_imbalances(0x200), vec![]);
+}
+
+#[test]
+fn stack_overflow_from_nested_calls() {
+    // Each subroutine calls the next, until the last CALL has a full stack
+    let depth = DEFAULT_STACK_DEPTH as u16;
+    let cfg = CFG::from_rom(
+        [CALL(0x204), JUMP(0x202)]
+            .into_iter()
+            .chain((1..=depth).flat_map(|n| [CALL(0x204 + 4 * n), RTS]))
+            .chain([LOAD(0, 1), RTS])
+            .map(Some),
+    );
+    assert_eq!(
+        cfg.stack_imbalances(0x200),
+        vec![StackIssue::Overflow(0x200 + 4 * depth)]
+    );
+}
+
+#[test]
+fn listing_cross_references() {
+    let rom = encode(&[
+        LOAD(0, 1),  // 0x200
+        SKE(0, 1),   // 0x202
+        JUMP(0x208), // 0x204
+        CALL(0x208), // 0x206
+        ADD(0, 1),   // 0x208
+        RTS,         // 0x20a
+    ]);
+
+    let lines: Vec<String> = listing(&rom, true, false, &Symbols::default())
+        .lines()
+        .map(String::from)
+        .collect();
+    assert_eq!(lines.len(), 6);
+    assert!(
+        lines[4].ends_with("; referenced by: 0x204 0x206"),
+        "{}",
+        lines[4]
+    );
+    // Reached by the skip, not just by falling through
+    assert!(lines[3].ends_with("; referenced by: 0x202"), "{}", lines[3]);
+    assert!(!lines[1].contains("referenced"));
+
+    assert!(!listing(&rom, false, false, &Symbols::default()).contains("referenced"));
+}
+
+#[test]
+fn analysis_golden_output() {
+    // A loop that calls a subroutine, then data that never runs
+    let mut rom = encode(&[
+        LOAD(0, 0),
+        CALL(0x20a),
+        SKE(0, 5),
+        JUMP(0x202),
+        JUMP(0x208),
+        ADD(0, 1),
+        RTS,
+    ]);
+    rom.extend([0xF0, 0xFF]);
+    let prog: Vec<_> = disassemble(&rom, 0x200).collect();
+    let expected = "\
+Valid instructions: 7/8 words (87.5%)
+Invalid words at: 0x20e
+Control flow graph:
+0x200 [R-] <- START
+  0x200  LOAD  v0, 0x0
+  -> 0x202
+
+0x202 [RC] <- 0x200 0x206
+  0x202  CALL  0x20a
+  -> [0x204] 0x20a
+
+0x204 [R-] <-
+  0x204  SKE   v0, 0x5
+  -> 0x206 0x208
+
+0x206 [R-] <- 0x204
+  0x206  JUMP  0x202
+  -> 0x202
+
+0x208 [R-] <- 0x204 0x208
+  0x208  JUMP  0x208
+  -> 0x208
+
+0x20a [R-] <- 0x202
+  0x20a  ADD   v0, 0x1
+  0x20c  RTS
+  ->
+
+CALL/RTS balanced on every path
+v0 read at: 0x204 0x20a
+v0 written at: 0x200 0x20a
+Blocks: 7
+Reachable blocks: 6
+Reachable instructions: 7
+Branch points: 1
+";
+    assert_eq!(
+        analysis(&prog, Some(0), true, &Symbols::default()),
+        expected
+    );
+}
+
+#[test]
+fn analysis_names_addresses() {
+    let rom = encode(&[LOAD(0, 1), CALL(0x206), JUMP(0x202), RTS]);
+    let prog: Vec<_> = disassemble(&rom, 0x200).collect();
+    let symbols: Symbols = "0x202 main_loop\n0x206 tick".parse().unwrap();
+    let out = analysis(&prog, None, false, &symbols);
+    assert!(out.contains("\n0x202 (main_loop) [RC] <-"), "{}", out);
+    assert!(out.contains("  0x202  CALL  tick\n"), "{}", out);
+    assert!(listing(&rom, false, false, &symbols).contains("JUMP  main_loop"));
+}
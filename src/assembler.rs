@@ -0,0 +1,76 @@
+pub mod octo;
+
+use std::collections::HashMap;
+
+use crate::instruction::{Addr, Instruction};
+
+/// Where programs are loaded, and so where the first emitted byte ends up
+pub const PROGRAM_START: Addr = 0x200;
+
+/// Bytecode being assembled, with labels that may be used before they are defined
+#[derive(Debug, Default)]
+pub struct Program {
+    bytes: Vec<u8>,
+    labels: HashMap<String, Addr>,
+    /// Offsets of instructions whose address field should be filled in with a label
+    fixups: Vec<(usize, String)>,
+}
+
+impl Program {
+    /// Address the next byte will be emitted at
+    pub fn here(&self) -> Addr {
+        PROGRAM_START + self.bytes.len() as Addr
+    }
+
+    pub fn emit(&mut self, instr: Instruction) {
+        self.bytes.extend(instr.to_bytes());
+    }
+
+    pub fn emit_byte(&mut self, byte: u8) {
+        self.bytes.push(byte);
+    }
+
+    /// Emit an instruction whose address is `label`, filled in once the label is known
+    pub fn emit_to_label(&mut self, instr: Instruction, label: &str) {
+        self.fixups.push((self.bytes.len(), label.to_string()));
+        self.emit(instr);
+    }
+
+    /// Overwrite the instruction emitted at `addr`
+    pub fn patch(&mut self, addr: Addr, instr: Instruction) {
+        let offset = (addr - PROGRAM_START) as usize;
+        let bytes = instr.to_bytes();
+        self.bytes[offset..offset + bytes.len()].copy_from_slice(&bytes);
+    }
+
+    pub fn define_label(&mut self, name: &str) -> Result<(), String> {
+        let here = self.here();
+        if self.labels.insert(name.to_string(), here).is_some() {
+            return Err(format!("Label {} defined twice", name));
+        }
+        Ok(())
+    }
+
+    pub fn label(&self, name: &str) -> Option<Addr> {
+        self.labels.get(name).copied()
+    }
+
+    /// Resolve all labels and return the bytecode
+    pub fn finish(mut self) -> Result<Vec<u8>, String> {
+        for (offset, label) in &self.fixups {
+            let addr = self
+                .labels
+                .get(label)
+                .ok_or_else(|| format!("Undefined label {}", label))?;
+            let word = u16::from_be_bytes([self.bytes[*offset], self.bytes[*offset + 1]]);
+            if word == u16::from(Instruction::LOADILONG(0)) {
+                // The whole second word is the address
+                self.bytes[*offset + 2..*offset + 4].copy_from_slice(&addr.to_be_bytes());
+                continue;
+            }
+            let word = (word & 0xF000) | (addr & 0x0FFF);
+            self.bytes[*offset..*offset + 2].copy_from_slice(&word.to_be_bytes());
+        }
+        Ok(self.bytes)
+    }
+}
@@ -0,0 +1,514 @@
+//! Front-end for Octo (`.8o`) source. Supports labels, `:const`, `:alias`, `:call`, the plain
+//! CHIP-8 statements, and `if`/`loop` blocks, which are lowered to skips and jumps. SUPER-CHIP and
+//! XO-CHIP statements, macros and `:calc` are not supported.
+
+use std::collections::HashMap;
+use std::iter::Peekable;
+
+use super::Program;
+use crate::instruction::{Addr, Instruction, Reg, RegVal};
+use Instruction::*;
+
+/// Assemble Octo source into bytecode to be loaded at 0x200
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    let tokens = source
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or(""))
+        .flat_map(str::split_whitespace);
+
+    let mut parser = Parser {
+        tokens: tokens.peekable(),
+        program: Program::default(),
+        consts: HashMap::new(),
+        aliases: HashMap::new(),
+        blocks: Vec::new(),
+    };
+    while parser.tokens.peek().is_some() {
+        parser.statement()?;
+    }
+    if !parser.blocks.is_empty() {
+        return Err("Unterminated if or loop block".to_string());
+    }
+    parser.program.finish()
+}
+
+/// Right hand side of a comparison
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    Reg(Reg),
+    Val(RegVal),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Cond {
+    Eq(Reg, Operand),
+    Ne(Reg, Operand),
+    /// Key in the register is pressed
+    Key(Reg),
+    NotKey(Reg),
+}
+
+impl Cond {
+    fn negate(self) -> Cond {
+        match self {
+            Cond::Eq(r, o) => Cond::Ne(r, o),
+            Cond::Ne(r, o) => Cond::Eq(r, o),
+            Cond::Key(r) => Cond::NotKey(r),
+            Cond::NotKey(r) => Cond::Key(r),
+        }
+    }
+
+    /// The instruction that skips the next one when this holds
+    fn skip(self) -> Instruction {
+        match self {
+            Cond::Eq(r, Operand::Val(v)) => SKE(r, v),
+            Cond::Eq(r, Operand::Reg(y)) => SKRE(r, y),
+            Cond::Ne(r, Operand::Val(v)) => SKNE(r, v),
+            Cond::Ne(r, Operand::Reg(y)) => SKRNE(r, y),
+            Cond::Key(r) => SKPR(r),
+            Cond::NotKey(r) => SKUP(r),
+        }
+    }
+}
+
+/// An open `if ... begin` or `loop`, waiting for its end
+enum Block {
+    /// Address of the jump over the current branch
+    If(Addr),
+    /// Start of the loop, and the `while` jumps out of it
+    Loop(Addr, Vec<Addr>),
+}
+
+struct Parser<'a, I: Iterator<Item = &'a str>> {
+    tokens: Peekable<I>,
+    program: Program,
+    consts: HashMap<&'a str, u16>,
+    aliases: HashMap<&'a str, Reg>,
+    blocks: Vec<Block>,
+}
+
+fn parse_number(token: &str) -> Option<i32> {
+    let (negative, digits) = match token.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, token),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i32::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        i32::from_str_radix(bin, 2).ok()?
+    } else {
+        digits.parse().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+impl<'a, I: Iterator<Item = &'a str>> Parser<'a, I> {
+    fn next(&mut self) -> Result<&'a str, String> {
+        self.tokens
+            .next()
+            .ok_or_else(|| "Unexpected end of input".to_string())
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        match self.next()? {
+            token if token == expected => Ok(()),
+            token => Err(format!("Expected {}, found {}", expected, token)),
+        }
+    }
+
+    fn as_reg(&self, token: &str) -> Option<Reg> {
+        if let Some(&reg) = self.aliases.get(token) {
+            return Some(reg);
+        }
+        let digit = token.strip_prefix(['v', 'V'])?;
+        match u8::from_str_radix(digit, 16) {
+            Ok(reg) if digit.len() == 1 => Some(reg),
+            _ => None,
+        }
+    }
+
+    fn reg(&mut self) -> Result<Reg, String> {
+        let token = self.next()?;
+        self.as_reg(token)
+            .ok_or_else(|| format!("Expected a register, found {}", token))
+    }
+
+    fn as_number(&self, token: &str) -> Option<i32> {
+        self.consts
+            .get(token)
+            .map(|&v| v as i32)
+            .or_else(|| parse_number(token))
+    }
+
+    fn byte(&mut self) -> Result<RegVal, String> {
+        let token = self.next()?;
+        match self.as_number(token) {
+            Some(v @ -128..=255) => Ok(v as RegVal),
+            Some(_) => Err(format!("{} does not fit in a byte", token)),
+            None => Err(format!("Expected a number, found {}", token)),
+        }
+    }
+
+    fn nibble(&mut self) -> Result<u8, String> {
+        let token = self.next()?;
+        match self.as_number(token) {
+            Some(v @ 0..=15) => Ok(v as u8),
+            _ => Err(format!("Expected a number from 0 to 15, found {}", token)),
+        }
+    }
+
+    /// Emit `instr` with the address given by the next token, which may be a label defined later
+    fn emit_with_addr(&mut self, instr: fn(Addr) -> Instruction) -> Result<(), String> {
+        let token = self.next()?;
+        // Only LOADILONG has a full 16-bit address
+        let max = if instr(0) == LOADILONG(0) {
+            0xFFFF
+        } else {
+            0xFFF
+        };
+        match self.as_number(token) {
+            Some(v) if (0..=max).contains(&v) => self.program.emit(instr(v as Addr)),
+            Some(_) => return Err(format!("Address {} out of range", token)),
+            None => self.program.emit_to_label(instr(0), token),
+        }
+        Ok(())
+    }
+
+    fn cond(&mut self) -> Result<Cond, String> {
+        let reg = self.reg()?;
+        let op = self.next()?;
+        match op {
+            "key" => return Ok(Cond::Key(reg)),
+            "-key" => return Ok(Cond::NotKey(reg)),
+            _ => {}
+        }
+        let rhs = self.tokens.peek().copied();
+        let operand = match rhs.and_then(|t| self.as_reg(t)) {
+            Some(y) => {
+                self.next()?;
+                Operand::Reg(y)
+            }
+            None => Operand::Val(self.byte()?),
+        };
+        match op {
+            "==" => Ok(Cond::Eq(reg, operand)),
+            "!=" => Ok(Cond::Ne(reg, operand)),
+            _ => Err(format!("Unsupported comparison {}", op)),
+        }
+    }
+
+    /// Emit a jump to be pointed somewhere once we know where, returning its address
+    fn placeholder_jump(&mut self) -> Addr {
+        let addr = self.program.here();
+        self.program.emit(JUMP(0));
+        addr
+    }
+
+    fn assignment(&mut self, x: Reg) -> Result<(), String> {
+        let op = self.next()?;
+        let rhs = *self
+            .tokens
+            .peek()
+            .ok_or_else(|| "Unexpected end of input".to_string())?;
+        let rhs_reg = self.as_reg(rhs);
+        if rhs_reg.is_some() {
+            self.next()?;
+        }
+
+        let instr = match (op, rhs_reg) {
+            (":=", Some(y)) => MOVE(x, y),
+            ("|=", Some(y)) => OR(x, y),
+            ("&=", Some(y)) => AND(x, y),
+            ("^=", Some(y)) => XOR(x, y),
+            ("+=", Some(y)) => ADDR(x, y),
+            ("-=", Some(y)) => SUB(x, y),
+            ("=-", Some(y)) => SUBN(x, y),
+            (">>=", Some(y)) => SHR(x, y),
+            ("<<=", Some(y)) => SHL(x, y),
+            (":=", None) => match rhs {
+                "random" => {
+                    self.next()?;
+                    RAND(x, self.byte()?)
+                }
+                "delay" => {
+                    self.next()?;
+                    MOVED(x)
+                }
+                "key" => {
+                    self.next()?;
+                    KEYD(x)
+                }
+                _ => LOAD(x, self.byte()?),
+            },
+            ("+=", None) => ADD(x, self.byte()?),
+            ("-=", None) => ADD(x, self.byte()?.wrapping_neg()),
+            _ => return Err(format!("Unsupported operation v{:X} {} {}", x, op, rhs)),
+        };
+        self.program.emit(instr);
+        Ok(())
+    }
+
+    fn statement(&mut self) -> Result<(), String> {
+        let token = self.next()?;
+        match token {
+            ":" => {
+                let name = self.next()?;
+                self.program.define_label(name)?;
+            }
+            ":const" => {
+                let name = self.next()?;
+                let value = self.next()?;
+                let value = self
+                    .as_number(value)
+                    .filter(|v| (0..=0xFFFF).contains(v))
+                    .ok_or_else(|| format!("Invalid constant value {}", value))?;
+                self.consts.insert(name, value as u16);
+            }
+            ":alias" => {
+                let name = self.next()?;
+                let reg = self.reg()?;
+                self.aliases.insert(name, reg);
+            }
+            ":call" => self.emit_with_addr(CALL)?,
+            "clear" => self.program.emit(CLR),
+            "lores" => self.program.emit(LORES),
+            "hires" => self.program.emit(HIRES),
+            "plane" => {
+                let n = self.nibble()?;
+                self.program.emit(PLANE(n));
+            }
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.program.emit(SCD(n));
+            }
+            "scroll-right" => self.program.emit(SCR),
+            "scroll-left" => self.program.emit(SCL),
+            "return" | ";" => self.program.emit(RTS),
+            "jump" => self.emit_with_addr(JUMP)?,
+            "jump0" => self.emit_with_addr(JUMPI)?,
+            "sprite" => {
+                let x = self.reg()?;
+                let y = self.reg()?;
+                let n = self.nibble()?;
+                self.program.emit(DRAW(x, y, n));
+            }
+            "bcd" => {
+                let x = self.reg()?;
+                self.program.emit(BCD(x));
+            }
+            "save" => {
+                let x = self.reg()?;
+                self.program.emit(STOR(x));
+            }
+            "load" => {
+                let x = self.reg()?;
+                self.program.emit(READ(x));
+            }
+            "delay" | "buzzer" => {
+                self.expect(":=")?;
+                let x = self.reg()?;
+                self.program
+                    .emit(if token == "delay" { LOADD(x) } else { LOADS(x) });
+            }
+            "i" => match self.next()? {
+                ":=" => {
+                    if self.tokens.peek() == Some(&"hex") {
+                        self.next()?;
+                        let x = self.reg()?;
+                        self.program.emit(LDSPR(x));
+                    } else if self.tokens.peek() == Some(&"long") {
+                        self.next()?;
+                        self.emit_with_addr(LOADILONG)?;
+                    } else {
+                        self.emit_with_addr(LOADI)?;
+                    }
+                }
+                "+=" => {
+                    let x = self.reg()?;
+                    self.program.emit(ADDI(x));
+                }
+                op => return Err(format!("Unsupported operation i {}", op)),
+            },
+            "if" => {
+                let cond = self.cond()?;
+                match self.next()? {
+                    // Skip the statement unless the condition holds
+                    "then" => self.program.emit(cond.negate().skip()),
+                    // Skip the jump past the block when the condition holds
+                    "begin" => {
+                        self.program.emit(cond.skip());
+                        let jump = self.placeholder_jump();
+                        self.blocks.push(Block::If(jump));
+                    }
+                    other => return Err(format!("Expected then or begin, found {}", other)),
+                }
+            }
+            "else" => match self.blocks.pop() {
+                Some(Block::If(jump)) => {
+                    let end_jump = self.placeholder_jump();
+                    self.program.patch(jump, JUMP(self.program.here()));
+                    self.blocks.push(Block::If(end_jump));
+                }
+                _ => return Err("else without if".to_string()),
+            },
+            "end" => match self.blocks.pop() {
+                Some(Block::If(jump)) => self.program.patch(jump, JUMP(self.program.here())),
+                _ => return Err("end without if".to_string()),
+            },
+            "loop" => {
+                let start = self.program.here();
+                self.blocks.push(Block::Loop(start, Vec::new()));
+            }
+            "while" => {
+                let cond = self.cond()?;
+                self.program.emit(cond.skip());
+                let jump = self.placeholder_jump();
+                match self.blocks.iter_mut().rev().find_map(|b| match b {
+                    Block::Loop(_, exits) => Some(exits),
+                    _ => None,
+                }) {
+                    Some(exits) => exits.push(jump),
+                    None => return Err("while outside of a loop".to_string()),
+                }
+            }
+            "again" => match self.blocks.pop() {
+                Some(Block::Loop(start, exits)) => {
+                    self.program.emit(JUMP(start));
+                    for exit in exits {
+                        self.program.patch(exit, JUMP(self.program.here()));
+                    }
+                }
+                _ => return Err("again without loop".to_string()),
+            },
+            _ => {
+                if let Some(x) = self.as_reg(token) {
+                    self.assignment(x)?;
+                } else if let Some(value) = self.as_number(token) {
+                    if !(-128..=255).contains(&value) {
+                        return Err(format!("{} does not fit in a byte", token));
+                    }
+                    self.program.emit_byte(value as u8);
+                } else if token.starts_with(':') {
+                    return Err(format!("Unsupported directive {}", token));
+                } else {
+                    // A bare label name calls it
+                    match self.program.label(token) {
+                        Some(addr) => self.program.emit(CALL(addr)),
+                        None => self.program.emit_to_label(CALL(0), token),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn octo_straight_line() {
+    let bytes = assemble(
+        "# draw a digit
+        : main
+            clear
+            v0 := 5   i := hex v0
+            v1 += 0x10
+            sprite v1 v1 5
+            delay := v0
+            return",
+    )
+    .unwrap();
+    assert_eq!(
+        bytes,
+        [0x00, 0xE0, 0x60, 0x05, 0xF0, 0x29, 0x71, 0x10, 0xD1, 0x15, 0xF0, 0x15, 0x00, 0xEE]
+    );
+}
+
+#[test]
+fn octo_subtraction() {
+    let bytes = assemble("v1 -= v2  v1 =- v2  v1 -= 1").unwrap();
+    assert_eq!(bytes, [0x81, 0x25, 0x81, 0x27, 0x71, 0xFF]);
+}
+
+#[test]
+fn octo_super_chip_display() {
+    let bytes = assemble("hires scroll-down 3 scroll-right scroll-left lores plane 2").unwrap();
+    assert_eq!(
+        bytes,
+        [0x00, 0xFF, 0x00, 0xC3, 0x00, 0xFB, 0x00, 0xFC, 0x00, 0xFE, 0xF2, 0x01]
+    );
+}
+
+#[test]
+fn octo_long_index() {
+    let bytes = assemble("i := long 0xBEEF  i := long data  : data").unwrap();
+    assert_eq!(bytes, [0xF0, 0x00, 0xBE, 0xEF, 0xF0, 0x00, 0x02, 0x08]);
+    assert!(assemble("i := 0x1000").is_err());
+}
+
+#[test]
+fn octo_labels_const_alias() {
+    let bytes = assemble(
+        ":const SPEED 3
+        :alias px v4
+        : main
+            px := SPEED
+            i := ball
+            jump main
+        : ball
+            0b11000000 0xC0",
+    )
+    .unwrap();
+    // ball is at 0x206
+    assert_eq!(bytes, [0x64, 0x03, 0xA2, 0x06, 0x12, 0x00, 0xC0, 0xC0]);
+}
+
+#[test]
+fn octo_forward_call() {
+    let bytes = assemble("sub jump 0x200 : sub ;").unwrap();
+    assert_eq!(bytes, [0x22, 0x04, 0x12, 0x00, 0x00, 0xEE]);
+}
+
+#[test]
+fn octo_if_then() {
+    let bytes = assemble("if v2 == 7 then v3 := 1  if v2 key then clear").unwrap();
+    // SKNE v2, 7; LOAD v3, 1; SKUP v2; CLR
+    assert_eq!(bytes, [0x42, 0x07, 0x63, 0x01, 0xE2, 0xA1, 0x00, 0xE0]);
+}
+
+#[test]
+fn octo_if_else() {
+    let bytes = assemble("if v0 != 1 begin v1 := 2 else v1 := 3 end").unwrap();
+    assert_eq!(
+        bytes,
+        [
+            0x40, 0x01, // 200: SKNE v0, 1
+            0x12, 0x08, // 202: JUMP else
+            0x61, 0x02, // 204
+            0x12, 0x0A, // 206: JUMP end
+            0x61, 0x03, // 208: else
+        ]
+    );
+}
+
+#[test]
+fn octo_loop_while() {
+    let bytes = assemble("loop v0 += 1 while v0 != 10 again clear").unwrap();
+    assert_eq!(
+        bytes,
+        [
+            0x70, 0x01, // 200: ADD v0, 1
+            0x40, 0x0A, // 202: SKNE v0, 10
+            0x12, 0x08, // 204: JUMP out
+            0x12, 0x00, // 206: JUMP loop
+            0x00, 0xE0, // 208: out
+        ]
+    );
+}
+
+#[test]
+fn octo_errors() {
+    assert!(assemble("jump nowhere").is_err());
+    assert!(assemble("loop v0 += 1").is_err());
+    assert!(assemble("v0 := 300").is_err());
+    assert!(assemble(": a : a").is_err());
+    assert!(assemble(":macro foo").is_err());
+}
@@ -0,0 +1,254 @@
+use std::fmt::{self, Debug, Display};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+/// Pitch of the beep played while the sound timer is running
+pub const BEEP_FREQ: f32 = 440.0;
+/// Pitches we accept for the beep, roughly what people can hear
+pub const BEEP_FREQ_RANGE: RangeInclusive<f32> = 20.0..=20_000.0;
+/// Loudness of the beep, from 0 (silent) to 1 (full scale)
+pub const BEEP_VOLUME: f32 = 0.1;
+
+/// What the sound timer plays
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Beep {
+    /// In Hz
+    pub freq: f32,
+    pub waveform: Waveform,
+    pub volume: f32,
+}
+
+impl Default for Beep {
+    fn default() -> Self {
+        Beep {
+            freq: BEEP_FREQ,
+            waveform: Waveform::default(),
+            volume: BEEP_VOLUME,
+        }
+    }
+}
+
+/// Shape of the beep
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Waveform {
+    #[default]
+    Square,
+    Sine,
+    Triangle,
+}
+
+impl Waveform {
+    pub const ALL: [Waveform; 3] = [Waveform::Square, Waveform::Sine, Waveform::Triangle];
+
+    /// Value `phase` (0 to 1) of the way through a cycle, between -1 and 1
+    #[cfg_attr(not(feature = "audio"), allow(dead_code))] // Only the cpal sink makes samples
+    pub fn sample(self, phase: f32) -> f32 {
+        match self {
+            Waveform::Square if phase < 0.5 => 1.0,
+            Waveform::Square => -1.0,
+            Waveform::Sine => (phase * std::f32::consts::TAU).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+        }
+    }
+}
+
+impl Display for Waveform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Waveform::Square => "square",
+            Waveform::Sine => "sine",
+            Waveform::Triangle => "triangle",
+        })
+    }
+}
+
+impl FromStr for Waveform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Waveform::ALL
+            .into_iter()
+            .find(|w| w.to_string() == s)
+            .ok_or_else(|| format!("Unknown waveform {} (expected square, sine or triangle)", s))
+    }
+}
+
+/// Makes samples of a waveform one at a time, keeping its place in the cycle when the frequency
+/// changes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Oscillator {
+    phase: f32,
+}
+
+impl Oscillator {
+    #[cfg_attr(not(feature = "audio"), allow(dead_code))] // Only the cpal sink makes samples
+    pub fn next(&mut self, waveform: Waveform, freq: f32, sample_rate: f32) -> f32 {
+        let sample = waveform.sample(self.phase);
+        self.phase = (self.phase + freq / sample_rate) % 1.0;
+        sample
+    }
+}
+
+/// Somewhere to send sound. The CPU only tells the sink what to play, so it doesn't depend on any
+/// particular audio library.
+pub trait AudioSink: Debug + Send {
+    /// Start (`active`) or stop playing `beep`
+    fn set_tone(&mut self, active: bool, beep: Beep);
+}
+
+#[cfg(feature = "audio")]
+pub use self::cpal_sink::CpalSink;
+
+#[cfg(feature = "audio")]
+mod cpal_sink {
+    use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+    use super::{AudioSink, Beep, Oscillator, Waveform};
+
+    /// Plays the tone on the default output device
+    #[derive(Debug)]
+    pub struct CpalSink {
+        active: Arc<AtomicBool>,
+        /// Bits of an f32
+        freq: Arc<AtomicU32>,
+        /// Index into `Waveform::ALL`
+        waveform: Arc<AtomicU8>,
+        /// Bits of an f32
+        volume: Arc<AtomicU32>,
+    }
+
+    impl CpalSink {
+        pub fn new() -> Result<CpalSink, String> {
+            let active = Arc::new(AtomicBool::new(false));
+            let freq = Arc::new(AtomicU32::new(super::BEEP_FREQ.to_bits()));
+            let waveform = Arc::new(AtomicU8::new(0));
+            let volume = Arc::new(AtomicU32::new(super::BEEP_VOLUME.to_bits()));
+            let (started_tx, started_rx) = mpsc::channel();
+
+            // The stream is not Send on every platform, so it lives on its own thread for the
+            // lifetime of the program
+            let tone = (
+                active.clone(),
+                freq.clone(),
+                waveform.clone(),
+                volume.clone(),
+            );
+            thread::spawn(move || {
+                match play_tone(tone) {
+                    Ok(_stream) => {
+                        started_tx.send(Ok(())).unwrap();
+                        loop {
+                            thread::park();
+                        }
+                    }
+                    Err(e) => started_tx.send(Err(e)).unwrap(),
+                };
+            });
+
+            started_rx
+                .recv()
+                .map_err(|e| e.to_string())?
+                .map(|_| CpalSink {
+                    active,
+                    freq,
+                    waveform,
+                    volume,
+                })
+        }
+    }
+
+    fn play_tone(
+        (active, freq, waveform, volume): (
+            Arc<AtomicBool>,
+            Arc<AtomicU32>,
+            Arc<AtomicU8>,
+            Arc<AtomicU32>,
+        ),
+    ) -> Result<cpal::Stream, String> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or("No audio output device")?;
+        let config = device
+            .default_output_config()
+            .map_err(|e| e.to_string())?
+            .config();
+        let sample_rate = config.sample_rate.0 as f32;
+        let channels = config.channels as usize;
+
+        let mut oscillator = Oscillator::default();
+        let stream = device
+            .build_output_stream(
+                &config,
+                move |data: &mut [f32], _| {
+                    let on = active.load(Ordering::Relaxed);
+                    let freq = f32::from_bits(freq.load(Ordering::Relaxed));
+                    let waveform = Waveform::ALL[waveform.load(Ordering::Relaxed) as usize];
+                    let volume = f32::from_bits(volume.load(Ordering::Relaxed));
+                    for frame in data.chunks_mut(channels) {
+                        let sample = oscillator.next(waveform, freq, sample_rate);
+                        frame.fill(if on { sample * volume } else { 0.0 });
+                    }
+                },
+                |e| println!("Audio error: {}", e),
+            )
+            .map_err(|e| e.to_string())?;
+        stream.play().map_err(|e| e.to_string())?;
+        Ok(stream)
+    }
+
+    impl AudioSink for CpalSink {
+        fn set_tone(&mut self, active: bool, beep: Beep) {
+            let index = Waveform::ALL
+                .iter()
+                .position(|&w| w == beep.waveform)
+                .unwrap();
+            self.freq.store(beep.freq.to_bits(), Ordering::Relaxed);
+            self.waveform.store(index as u8, Ordering::Relaxed);
+            self.volume.store(beep.volume.to_bits(), Ordering::Relaxed);
+            self.active.store(active, Ordering::Relaxed);
+        }
+    }
+}
+
+#[test]
+fn waveforms_repeat_with_frequency() {
+    // 125Hz at 8000 samples per second repeats every 64 samples. The phase step is exactly 1/64,
+    // so the square wave doesn't flip early from rounding
+    for waveform in Waveform::ALL {
+        let mut oscillator = Oscillator::default();
+        let samples: Vec<f32> = (0..192)
+            .map(|_| oscillator.next(waveform, 125.0, 8000.0))
+            .collect();
+        for i in 0..128 {
+            let (a, b) = (samples[i], samples[i + 64]);
+            assert!(
+                (a - b).abs() < 0.01,
+                "{} sample {}: {} vs {}",
+                waveform,
+                i,
+                a,
+                b
+            );
+        }
+        let max = samples.iter().cloned().fold(f32::MIN, f32::max);
+        let min = samples.iter().cloned().fold(f32::MAX, f32::min);
+        assert!(
+            max > 0.99 && min < -0.99,
+            "{} spans {}..{}",
+            waveform,
+            min,
+            max
+        );
+    }
+
+    assert_eq!(Waveform::Square.sample(0.25), 1.0);
+    assert_eq!(Waveform::Square.sample(0.75), -1.0);
+    assert_eq!(Waveform::Triangle.sample(0.25), 0.0);
+    assert_eq!(Waveform::Triangle.sample(0.5), 1.0);
+    assert!((Waveform::Sine.sample(0.25) - 1.0).abs() < 1e-6);
+    assert_eq!("sine".parse(), Ok(Waveform::Sine));
+}
@@ -0,0 +1,72 @@
+use std::fmt::Debug;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+/// Where the CPU timers and the rate limiter get the time from, so tests can control it
+pub trait Clock: Debug + Send {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+/// The real time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// Only moves when told to. Clones share the same time, so a test can keep one and give another
+/// to the CPU.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    start: Instant,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock {
+            start: Instant::now(),
+            elapsed: Arc::new(Mutex::new(Duration::ZERO)),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+
+    /// Sleeping just moves the time forward
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+#[test]
+fn manual_clock_shared_between_clones() {
+    let clock = ManualClock::new();
+    let other = clock.clone();
+    let start = clock.now();
+
+    other.advance(Duration::from_millis(5));
+    other.sleep(Duration::from_millis(10));
+    assert_eq!(clock.now() - start, Duration::from_millis(15));
+}
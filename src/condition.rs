@@ -0,0 +1,157 @@
+use std::str::FromStr;
+
+use crate::cpu::Chip8;
+
+/// Part of the CPU state that a condition can look at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Reg(u8),
+    Pc,
+    Idx,
+    StackLen,
+    Delay,
+    Sound,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// A predicate on the CPU state, like `v0 == 5`, `pc == 0x2A0` or `stack.len() > 3`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Condition {
+    pub operand: Operand,
+    pub comparison: Comparison,
+    pub value: u16,
+}
+
+impl Operand {
+    fn read(self, cpu: &Chip8) -> u16 {
+        match self {
+            Operand::Reg(r) => cpu.reg[r as usize] as u16,
+            Operand::Pc => cpu.pc,
+            Operand::Idx => cpu.idx,
+            Operand::StackLen => cpu.stack.len() as u16,
+            Operand::Delay => cpu.delay as u16,
+            Operand::Sound => cpu.sound as u16,
+        }
+    }
+}
+
+impl FromStr for Operand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let reg = s
+            .strip_prefix("reg[")
+            .and_then(|rest| rest.strip_suffix(']'))
+            .or_else(|| s.strip_prefix(['v', 'V']));
+        if let Some(reg) = reg {
+            return match u8::from_str_radix(reg, 16) {
+                Ok(r) if r < 16 => Ok(Operand::Reg(r)),
+                _ => Err(format!("Invalid register: {}", s)),
+            };
+        }
+
+        match s {
+            "pc" => Ok(Operand::Pc),
+            "i" | "idx" => Ok(Operand::Idx),
+            "stack" | "stack.len()" => Ok(Operand::StackLen),
+            "delay" => Ok(Operand::Delay),
+            "sound" => Ok(Operand::Sound),
+            _ => Err(format!("Unknown operand: {}", s)),
+        }
+    }
+}
+
+impl FromStr for Comparison {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "==" => Ok(Comparison::Eq),
+            "!=" => Ok(Comparison::Ne),
+            "<" => Ok(Comparison::Lt),
+            "<=" => Ok(Comparison::Le),
+            ">" => Ok(Comparison::Gt),
+            ">=" => Ok(Comparison::Ge),
+            _ => Err(format!("Unknown comparison: {}", s)),
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let [operand, comparison, value] = words[..] else {
+            return Err(format!(
+                "Expected <operand> <comparison> <value>, e.g. v0 == 5, found: {}",
+                s
+            ));
+        };
+        let value = match value.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => value.parse(),
+        }
+        .map_err(|_| format!("Invalid value: {}", value))?;
+
+        Ok(Condition {
+            operand: operand.parse()?,
+            comparison: comparison.parse()?,
+            value,
+        })
+    }
+}
+
+impl Condition {
+    pub fn holds(&self, cpu: &Chip8) -> bool {
+        let actual = self.operand.read(cpu);
+        match self.comparison {
+            Comparison::Eq => actual == self.value,
+            Comparison::Ne => actual != self.value,
+            Comparison::Lt => actual < self.value,
+            Comparison::Le => actual <= self.value,
+            Comparison::Gt => actual > self.value,
+            Comparison::Ge => actual >= self.value,
+        }
+    }
+}
+
+#[test]
+fn parse_conditions() {
+    assert_eq!(
+        "reg[0] == 5".parse(),
+        Ok(Condition {
+            operand: Operand::Reg(0),
+            comparison: Comparison::Eq,
+            value: 5
+        })
+    );
+    assert_eq!(
+        "pc == 0x2A0".parse(),
+        Ok(Condition {
+            operand: Operand::Pc,
+            comparison: Comparison::Eq,
+            value: 0x2A0
+        })
+    );
+    assert_eq!(
+        "stack.len() > 3".parse::<Condition>().map(|c| c.operand),
+        Ok(Operand::StackLen)
+    );
+    assert_eq!(
+        "vA <= 1".parse::<Condition>().map(|c| c.operand),
+        Ok(Operand::Reg(10))
+    );
+    assert!("v0 = 5".parse::<Condition>().is_err());
+    assert!("vG == 5".parse::<Condition>().is_err());
+    assert!("pc==5".parse::<Condition>().is_err());
+}
@@ -0,0 +1,512 @@
+//! Conformance checks for the CPU, grouped by feature, with a pass/fail scorecard. They're
+//! ignored by default; run them with `cargo test conformance -- --ignored --nocapture`.
+//!
+//! The well-known conformance suites aren't bundled, so the checks are small self-test ROMs (see
+//! `selftest.rs`), each checking one behaviour from the CHIP-8, SUPER-CHIP or XO-CHIP
+//! references, plus display hashes of the bundled test ROMs under each quirks profile.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+use crate::cpu::{Chip8, Chip8IO, RunStatus};
+use crate::instruction::Instruction::{self, *};
+use crate::quirks::Profile;
+use crate::rom;
+use crate::runner::run_headless;
+use crate::selftest::{self, self_test_rom};
+
+/// Give up on a check's ROM after this many instructions
+const MAX_STEPS: u64 = 10_000;
+
+/// Self-test table entry: register `r` holds `value`
+const fn v(r: u8, value: u8) -> [u8; 4] {
+    [b'V', 0, r, value]
+}
+
+/// Self-test table entry: `mem[addr]` holds `value`
+const fn m(addr: u16, value: u8) -> [u8; 4] {
+    let [hi, lo] = addr.to_be_bytes();
+    [b'M', hi, lo, value]
+}
+
+enum Test {
+    /// Run the instructions until they end, then check registers and memory against the table
+    Values(&'static [Instruction], &'static [[u8; 4]]),
+    /// Run the ROM until it ends or loops, then check the SHA-1 of the display's bitmap
+    DisplayHash(&'static [u8], &'static str),
+}
+
+struct Check {
+    feature: &'static str,
+    name: &'static str,
+    profile: Profile,
+    test: Test,
+}
+
+const TAPEWORM: &[u8] = include_bytes!("../GAMES/TEST/TAPEWORM.ch8");
+const TIMEBOMB: &[u8] = include_bytes!("../GAMES/TEST/TIMEBOMB.ch8");
+const X_MIRROR: &[u8] = include_bytes!("../GAMES/TEST/X-MIRROR.ch8");
+
+/// A bundled test ROM, which should look the same after `MAX_STEPS` under every profile
+const fn bundled(
+    name: &'static str,
+    profile: Profile,
+    rom: &'static [u8],
+    hash: &'static str,
+) -> Check {
+    Check {
+        feature: "quirks",
+        name,
+        profile,
+        test: Test::DisplayHash(rom, hash),
+    }
+}
+
+const CHECKS: &[Check] = &[
+    Check {
+        feature: "opcodes",
+        name: "arithmetic and logic",
+        profile: Profile::Chip8,
+        test: Test::Values(
+            &[
+                LOAD(0, 200),
+                ADD(0, 5),
+                LOAD(1, 100),
+                MOVE(2, 0),
+                OR(2, 1),
+                MOVE(3, 0),
+                AND(3, 1),
+                MOVE(4, 0),
+                XOR(4, 1),
+                SYS(0),
+            ],
+            &[v(0, 0xCD), v(2, 0xED), v(3, 0x44), v(4, 0xA9)],
+        ),
+    },
+    Check {
+        feature: "opcodes",
+        name: "STOR, READ and BCD",
+        profile: Profile::Chip8,
+        test: Test::Values(
+            &[
+                LOAD(0, 1),
+                LOAD(1, 2),
+                LOAD(2, 3),
+                LOADI(0x400),
+                STOR(2),
+                LOAD(5, 123),
+                LOADI(0x410),
+                BCD(5),
+                // So READ has something to overwrite
+                LOAD(0, 0),
+                LOAD(1, 0),
+                LOAD(2, 0),
+                READ(2),
+                SYS(0),
+            ],
+            &[
+                m(0x400, 1),
+                m(0x402, 3),
+                m(0x410, 1),
+                m(0x411, 2),
+                m(0x412, 3),
+                v(0, 1),
+                v(1, 2),
+                v(2, 3),
+            ],
+        ),
+    },
+    Check {
+        feature: "opcodes",
+        name: "CALL and RTS",
+        profile: Profile::Chip8,
+        test: Test::Values(
+            &[CALL(0x206), LOAD(1, 1), SYS(0), LOAD(0, 9), RTS],
+            &[v(0, 9), v(1, 1)],
+        ),
+    },
+    Check {
+        feature: "opcodes",
+        name: "skips",
+        profile: Profile::Chip8,
+        test: Test::Values(
+            &[
+                LOAD(0, 5),
+                SKE(0, 5),
+                LOAD(1, 1),
+                SKNE(0, 5),
+                LOAD(2, 1),
+                SKRE(0, 0),
+                LOAD(3, 1),
+                SYS(0),
+            ],
+            &[v(1, 0), v(2, 1), v(3, 0)],
+        ),
+    },
+    Check {
+        feature: "opcodes",
+        name: "LOADILONG (XO-CHIP)",
+        profile: Profile::Chip8,
+        test: Test::Values(
+            &[LOADILONG(0x8000), LOAD(0, 42), STOR(0), SYS(0)],
+            &[m(0x8000, 42)],
+        ),
+    },
+    Check {
+        feature: "flags",
+        name: "ADDR carry",
+        profile: Profile::Chip8,
+        test: Test::Values(
+            &[
+                LOAD(0, 0xFF),
+                LOAD(1, 2),
+                ADDR(0, 1),
+                MOVE(5, 0xF),
+                LOAD(2, 1),
+                LOAD(3, 1),
+                ADDR(2, 3),
+                MOVE(6, 0xF),
+                SYS(0),
+            ],
+            &[v(0, 1), v(5, 1), v(2, 2), v(6, 0)],
+        ),
+    },
+    Check {
+        feature: "flags",
+        name: "SUB and SUBN borrow",
+        profile: Profile::Chip8,
+        test: Test::Values(
+            &[
+                LOAD(0, 1),
+                LOAD(1, 2),
+                SUB(0, 1),
+                MOVE(5, 0xF),
+                SUBN(1, 0),
+                MOVE(6, 0xF),
+                SYS(0),
+            ],
+            &[v(0, 0xFF), v(5, 0), v(1, 0xFD), v(6, 1)],
+        ),
+    },
+    Check {
+        feature: "flags",
+        name: "shifted out bit",
+        profile: Profile::Chip8,
+        test: Test::Values(
+            &[
+                LOAD(0, 0x81),
+                SHR(0, 0),
+                MOVE(5, 0xF),
+                LOAD(1, 0x41),
+                SHL(1, 1),
+                MOVE(6, 0xF),
+                SYS(0),
+            ],
+            &[v(0, 0x40), v(5, 1), v(1, 0x82), v(6, 0)],
+        ),
+    },
+    Check {
+        feature: "flags",
+        name: "flag wins over result in VF",
+        profile: Profile::Chip8,
+        test: Test::Values(
+            &[LOAD(0xF, 0xFF), LOAD(1, 1), ADDR(0xF, 1), SYS(0)],
+            &[v(0xF, 1)],
+        ),
+    },
+    Check {
+        feature: "quirks",
+        name: "logic ops reset VF (VIP)",
+        profile: Profile::Vip,
+        test: Test::Values(&[LOAD(0xF, 5), LOAD(0, 1), OR(0, 0), SYS(0)], &[v(0xF, 0)]),
+    },
+    Check {
+        feature: "quirks",
+        name: "shifts use Vy (VIP)",
+        profile: Profile::Vip,
+        test: Test::Values(&[LOAD(1, 4), SHR(0, 1), SYS(0)], &[v(0, 2)]),
+    },
+    Check {
+        feature: "quirks",
+        name: "STOR increments I (VIP)",
+        profile: Profile::Vip,
+        test: Test::Values(
+            &[LOADI(0x400), LOAD(0, 7), STOR(0), STOR(0), SYS(0)],
+            &[m(0x401, 7)],
+        ),
+    },
+    Check {
+        feature: "quirks",
+        name: "logic ops leave VF (CHIP-8)",
+        profile: Profile::Chip8,
+        test: Test::Values(&[LOAD(0xF, 5), LOAD(0, 1), OR(0, 0), SYS(0)], &[v(0xF, 5)]),
+    },
+    Check {
+        feature: "quirks",
+        name: "shifts use Vx (CHIP-8)",
+        profile: Profile::Chip8,
+        test: Test::Values(&[LOAD(0, 6), LOAD(1, 4), SHR(0, 1), SYS(0)], &[v(0, 3)]),
+    },
+    Check {
+        feature: "quirks",
+        name: "STOR leaves I (CHIP-8)",
+        profile: Profile::Chip8,
+        test: Test::Values(
+            &[
+                LOADI(0x400),
+                LOAD(0, 7),
+                STOR(0),
+                LOAD(0, 9),
+                STOR(0),
+                SYS(0),
+            ],
+            &[m(0x400, 9), m(0x401, 0)],
+        ),
+    },
+    Check {
+        feature: "quirks",
+        name: "JUMPI adds V0 (CHIP-8)",
+        profile: Profile::Chip8,
+        test: Test::Values(
+            &[
+                LOAD(2, 4),
+                JUMPI(0x208),
+                SYS(0),
+                SYS(0),
+                LOAD(3, 1),
+                SYS(0),
+                // Where V2 would have taken it
+                LOAD(3, 2),
+                SYS(0),
+            ],
+            &[v(3, 1)],
+        ),
+    },
+    Check {
+        feature: "quirks",
+        name: "JUMPI adds Vx (SUPER-CHIP)",
+        profile: Profile::SuperChip,
+        test: Test::Values(
+            &[
+                LOAD(2, 4),
+                JUMPI(0x208),
+                SYS(0),
+                SYS(0),
+                // Where V0 would have taken it
+                LOAD(3, 1),
+                SYS(0),
+                LOAD(3, 2),
+                SYS(0),
+            ],
+            &[v(3, 2)],
+        ),
+    },
+    bundled(
+        "IBM logo (VIP)",
+        Profile::Vip,
+        include_bytes!("../GAMES/TEST/IBM.ch8"),
+        "075988f15b129f140e8fa743c10fbf6608a9ecc5",
+    ),
+    bundled(
+        "IBM logo (SUPER-CHIP)",
+        Profile::SuperChip,
+        include_bytes!("../GAMES/TEST/IBM.ch8"),
+        "075988f15b129f140e8fa743c10fbf6608a9ecc5",
+    ),
+    bundled(
+        "TAPEWORM (CHIP-8)",
+        Profile::Chip8,
+        TAPEWORM,
+        "2f91e9843010ba20f0cd4ae6fb3c05523375d9e6",
+    ),
+    bundled(
+        "TAPEWORM (VIP)",
+        Profile::Vip,
+        TAPEWORM,
+        "2f91e9843010ba20f0cd4ae6fb3c05523375d9e6",
+    ),
+    bundled(
+        "TAPEWORM (SUPER-CHIP)",
+        Profile::SuperChip,
+        TAPEWORM,
+        "2f91e9843010ba20f0cd4ae6fb3c05523375d9e6",
+    ),
+    bundled(
+        "TIMEBOMB (CHIP-8)",
+        Profile::Chip8,
+        TIMEBOMB,
+        "1035b544ad8ff02804fb7d85e74e354cb4bb2b5b",
+    ),
+    bundled(
+        "TIMEBOMB (VIP)",
+        Profile::Vip,
+        TIMEBOMB,
+        "1035b544ad8ff02804fb7d85e74e354cb4bb2b5b",
+    ),
+    bundled(
+        "TIMEBOMB (SUPER-CHIP)",
+        Profile::SuperChip,
+        TIMEBOMB,
+        "1035b544ad8ff02804fb7d85e74e354cb4bb2b5b",
+    ),
+    bundled(
+        "X-MIRROR (CHIP-8)",
+        Profile::Chip8,
+        X_MIRROR,
+        "d25e61de578fd3168032fca928219ab812fb939b",
+    ),
+    bundled(
+        "X-MIRROR (VIP)",
+        Profile::Vip,
+        X_MIRROR,
+        "d25e61de578fd3168032fca928219ab812fb939b",
+    ),
+    bundled(
+        "X-MIRROR (SUPER-CHIP)",
+        Profile::SuperChip,
+        X_MIRROR,
+        "d25e61de578fd3168032fca928219ab812fb939b",
+    ),
+    Check {
+        feature: "display",
+        name: "IBM logo",
+        profile: Profile::Chip8,
+        test: Test::DisplayHash(
+            include_bytes!("../GAMES/TEST/IBM.ch8"),
+            "075988f15b129f140e8fa743c10fbf6608a9ecc5",
+        ),
+    },
+    Check {
+        feature: "display",
+        name: "collision sets VF",
+        profile: Profile::Chip8,
+        test: Test::Values(
+            &[
+                LDSPR(0),
+                DRAW(1, 1, 5),
+                MOVE(5, 0xF),
+                DRAW(1, 1, 5),
+                MOVE(6, 0xF),
+                SYS(0),
+            ],
+            &[v(5, 0), v(6, 1)],
+        ),
+    },
+    Check {
+        feature: "display",
+        name: "sprites clip at the edge (SUPER-CHIP)",
+        profile: Profile::SuperChip,
+        test: Test::Values(
+            &[
+                LDSPR(0),
+                LOAD(1, 62),
+                DRAW(1, 2, 1),
+                // Would collide with the columns that wrapped around
+                DRAW(2, 2, 1),
+                SYS(0),
+            ],
+            &[v(0xF, 0)],
+        ),
+    },
+    Check {
+        feature: "quirks",
+        name: "sprites wrap at the edge (CHIP-8)",
+        profile: Profile::Chip8,
+        test: Test::Values(
+            &[LDSPR(0), LOAD(1, 62), DRAW(1, 2, 1), DRAW(2, 2, 1), SYS(0)],
+            &[v(0xF, 1)],
+        ),
+    },
+    Check {
+        feature: "display",
+        name: "16x16 sprites (SUPER-CHIP)",
+        profile: Profile::SuperChip,
+        test: Test::Values(
+            &[HIRES, DRAW(1, 1, 0), MOVE(5, 0xF), DRAW(1, 1, 0), SYS(0)],
+            &[v(5, 0), v(0xF, 1)],
+        ),
+    },
+];
+
+fn run_check(check: &Check) -> Result<(), String> {
+    let io = Arc::new(Mutex::new(Chip8IO::new()));
+    let build = |rom: &[u8]| {
+        let mut cpu = Chip8::new(rom, io.clone(), false);
+        cpu.quirks = check.profile.quirks();
+        cpu
+    };
+    match check.test {
+        Test::Values(code, table) => {
+            let rom = self_test_rom(code, table);
+            let assertions = selftest::parse_trailer(&rom)?.ok_or("no self-test table")?;
+            match selftest::run(&mut build(&rom), &assertions, MAX_STEPS)? {
+                failures if failures.is_empty() => Ok(()),
+                failures => Err(failures.join(", ")),
+            }
+        }
+        Test::DisplayHash(rom, expected) => {
+            let mut cpu = build(rom);
+            run_headless(&mut cpu, MAX_STEPS, 1000, None);
+            if let RunStatus::Halted(e) = cpu.status() {
+                return Err(e);
+            }
+            let hash = rom::hash(&io.lock().unwrap().as_bitmap());
+            if hash == expected {
+                Ok(())
+            } else {
+                Err(format!("display hash {}, expected {}", hash, expected))
+            }
+        }
+    }
+}
+
+/// Passes out of checks for each feature, then what went wrong with each failure
+fn scorecard(results: &[(&Check, Result<(), String>)]) -> String {
+    let mut scores: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+    for (check, result) in results {
+        let (passed, total) = scores.entry(check.feature).or_default();
+        *passed += result.is_ok() as usize;
+        *total += 1;
+    }
+    let mut out = String::new();
+    for (feature, (passed, total)) in &scores {
+        let mark = if passed == total { "PASS" } else { "FAIL" };
+        writeln!(out, "{} {:<8} {}/{}", mark, feature, passed, total).unwrap();
+    }
+    for (check, result) in results {
+        if let Err(e) = result {
+            writeln!(out, "{} / {}: {}", check.feature, check.name, e).unwrap();
+        }
+    }
+    out
+}
+
+#[test]
+#[ignore]
+fn conformance_scorecard() {
+    let results: Vec<_> = CHECKS.iter().map(|c| (c, run_check(c))).collect();
+    let card = scorecard(&results);
+    print!("{}", card);
+    assert!(results.iter().all(|(_, r)| r.is_ok()), "{}", card);
+}
+
+#[test]
+fn scorecard_counts_failures() {
+    const FAILING: Check = Check {
+        feature: "flags",
+        name: "wrong on purpose",
+        profile: Profile::Chip8,
+        test: Test::Values(&[LOAD(0, 1), SYS(0)], &[v(0, 2)]),
+    };
+    let results = [
+        (&CHECKS[0], run_check(&CHECKS[0])),
+        (&FAILING, run_check(&FAILING)),
+    ];
+    assert_eq!(
+        scorecard(&results),
+        "FAIL flags    0/1\n\
+         PASS opcodes  1/1\n\
+         flags / wrong on purpose: Expected v0 == 0x02, got 0x01\n"
+    );
+}
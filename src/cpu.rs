@@ -1,22 +1,110 @@
 use std::{
+    collections::{HashMap, VecDeque},
     fmt::{self, Display},
+    fs,
+    io::Write,
+    mem::{self, Discriminant},
+    ops::Range,
+    str::FromStr,
     sync::{Arc, Mutex},
     time,
 };
 
 use phf::phf_ordered_map;
 use rand::prelude::*;
-
-use crate::instruction::Instruction;
+use rand_pcg::Pcg32;
+
+use crate::audio::{AudioSink, Beep};
+#[cfg(test)]
+use crate::clock::ManualClock;
+use crate::clock::{Clock, SystemClock};
+use crate::display::Framebuffer;
+#[cfg(test)]
+use crate::display::{DISPLAY_COLS, DISPLAY_ROWS, HIRES_COLS, HIRES_ROWS};
+#[cfg(test)]
+use crate::instruction::encode;
+use crate::instruction::{self, Chip8Error, Instruction};
+use crate::lock_stats::{lock_timed, LockProfiler};
+use crate::quirks::{DiagnosticSink, Profile, QuirkUse, Quirks};
+use crate::replay::KeyReplay;
+use crate::rom;
+use crate::sidecar::RomConfig;
+use crate::symbols::Symbols;
+use crate::trace::{TraceFilter, TraceSink};
 use Instruction::*;
 
-pub const DISPLAY_ROWS: usize = 32;
-pub const DISPLAY_COLS: usize = 64;
+/// XO-CHIP's 64K. Only `LOADILONG` can point I past the first 4K
+pub const MEMORY_SIZE: usize = 0x10000;
+/// Longest ROM that fits in memory after 0x200, where ROMs are loaded
+pub const MAX_ROM_SIZE: usize = MEMORY_SIZE - 0x200;
+
+/// Call depth of the original COSMAC VIP interpreter
+pub const DEFAULT_STACK_DEPTH: usize = 16;
+
+/// How many executed instructions `Chip8::history` keeps by default
+pub const DEFAULT_HISTORY_LEN: usize = 64;
+/// Most instructions `Chip8::history` can be set to keep, so it can't take over the memory
+pub const MAX_HISTORY_LEN: usize = 1 << 16;
+
+/// Registers of a standard CHIP-8, v0 to vF
+pub const DEFAULT_REGISTERS: usize = 16;
+/// Most registers `Chip8Builder::registers` can add, for experimenting with extended machines
+pub const MAX_REGISTERS: usize = 64;
+
+/// How many displays `Chip8::previous_frame` can go back through
+pub const DISPLAY_HISTORY_LEN: usize = 32;
+
+/// Time between ticks of the delay and sound timers
+pub const TIMER_PERIOD: time::Duration = time::Duration::from_nanos(1_000_000_000 / 60);
+
+/// Most instructions `Chip8::run_to_first_draw` runs looking for a draw
+pub const FIRST_DRAW_MAX_STEPS: u64 = 1_000_000;
+
+/// Where the font is loaded by default
+pub const DEFAULT_FONT_BASE: u16 = 0x000;
+/// Where the COSMAC VIP interpreter kept the font. Some ROMs hard-code addresses in this range
+pub const VIP_FONT_BASE: u16 = 0x050;
+
+/// Start of every file from `Chip8::save_state`
+const STATE_MAGIC: &[u8; 4] = b"C8ST";
+/// Bumped whenever the `Chip8::save_state` format changes, so old files are refused
+const STATE_VERSION: u8 = 1;
+
+/// Built-in hexadecimal digit sprites, 5 bytes each
+const FONT: [u8; 80] = [
+    0b11110000, 0b10010000, 0b10010000, 0b10010000, 0b11110000, // 0
+    0b00100000, 0b01100000, 0b00100000, 0b00100000, 0b01110000, // 1
+    0b11110000, 0b00010000, 0b11110000, 0b10000000, 0b11110000, // 2
+    0b11110000, 0b00010000, 0b11110000, 0b00010000, 0b11110000, // 3
+    0b10010000, 0b10010000, 0b11110000, 0b00010000, 0b00010000, // 4
+    0b11110000, 0b10000000, 0b11110000, 0b00010000, 0b11110000, // 5
+    0b11110000, 0b10000000, 0b11110000, 0b10010000, 0b11110000, // 6
+    0b11110000, 0b00010000, 0b00100000, 0b01000000, 0b10000000, // 7
+    0b11110000, 0b10010000, 0b11110000, 0b10010000, 0b11110000, // 8
+    0b11110000, 0b10010000, 0b11110000, 0b00010000, 0b11110000, // 9
+    0b11110000, 0b10010000, 0b11110000, 0b10010000, 0b10010000, // A
+    0b11100000, 0b10010000, 0b11100000, 0b10010000, 0b11100000, // B
+    0b11110000, 0b10000000, 0b10000000, 0b10000000, 0b11110000, // C
+    0b11100000, 0b10010000, 0b10010000, 0b10010000, 0b11100000, // D
+    0b11110000, 0b10000000, 0b11110000, 0b10000000, 0b11110000, // E
+    0b11110000, 0b10000000, 0b11110000, 0b10000000, 0b10000000, // F
+];
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Chip8IO {
     pub keystate: [bool; 16],
-    pub display: [[bool; DISPLAY_COLS]; DISPLAY_ROWS],
+    /// Keys held by the display stream's client (see `stream.rs`), added to the keyboard's
+    pub remote_keys: [bool; 16],
+    /// Keys held by the `--keys` script (see `replay.rs`), also added to the keyboard's
+    pub replay_keys: [bool; 16],
+    /// The display, which also knows whether it's in SUPER-CHIP high resolution mode. With
+    /// XO-CHIP planes, this is the first
+    pub display: Framebuffer,
+    /// XO-CHIP's second display plane, blank unless a ROM selects it with `PLANE`
+    pub second_plane: Framebuffer,
+    /// Frames the CPU has ended (see `Chip8::end_frame`), so renderers can keep time with it
+    pub frames: u64,
 }
 
 /*******************\
@@ -51,32 +139,442 @@ pub const KEYPAD_TO_QWERTY: phf::OrderedMap<u8, char> = phf_ordered_map! {
   0xFu8 => 'V',
 };
 
+/// QWERTY key for each CHIP-8 key, 0 to F
+pub type Keymap = [char; 16];
+
+pub fn default_keymap() -> Keymap {
+    std::array::from_fn(|key| KEYPAD_TO_QWERTY[&(key as u8)])
+}
+
 impl Chip8IO {
     pub fn new() -> Chip8IO {
         Chip8IO {
             keystate: [false; 16],
-            display: [[false; DISPLAY_COLS]; DISPLAY_ROWS],
+            remote_keys: [false; 16],
+            replay_keys: [false; 16],
+            display: Framebuffer::new(),
+            second_plane: Framebuffer::new(),
+            frames: 0,
         }
     }
 
     pub fn reset(&mut self) {
         *self = Self::new();
     }
+
+    /// Set the keys held on this machine's keyboard, keeping the stream client's and the replay
+    /// script's held too
+    pub fn set_local_keys(&mut self, local: [bool; 16]) {
+        self.keystate =
+            std::array::from_fn(|key| local[key] || self.remote_keys[key] || self.replay_keys[key]);
+    }
+
+    /// Both display planes, for renderers to read through the lock without copying them out
+    pub fn planes(&self) -> (&Framebuffer, &Framebuffer) {
+        (&self.display, &self.second_plane)
+    }
+
+    /// Switch both planes between low and high resolution
+    pub fn set_hires(&mut self, hires: bool) {
+        self.display.set_hires(hires);
+        self.second_plane.set_hires(hires);
+    }
+
+    /// The planes in the bitmask `planes`, bit 0 for `display` and bit 1 for `second_plane`
+    pub fn planes_mut(&mut self, planes: u8) -> impl Iterator<Item = &mut Framebuffer> {
+        [&mut self.display, &mut self.second_plane]
+            .into_iter()
+            .enumerate()
+            .filter(move |(i, _)| planes & (1 << i) != 0)
+            .map(|(_, plane)| plane)
+    }
+
+    /// The display packed one bit per pixel, row by row, with the leftmost pixel of each byte in
+    /// the most significant bit (like sprites)
+    pub fn as_bitmap(&self) -> Vec<u8> {
+        let row_bytes = self.display.width() / 8;
+        (0..self.display.height())
+            .flat_map(|row| self.display.row(row).to_be_bytes()[..row_bytes].to_vec())
+            .collect()
+    }
+
+    /// Inverse of `as_bitmap`, to start from a saved screen. The bitmap must cover exactly the
+    /// whole display at its current resolution. The second plane is cleared
+    pub fn set_display_from_bitmap(&mut self, bitmap: &[u8]) -> Result<(), String> {
+        let (width, height) = (self.display.width(), self.display.height());
+        if bitmap.len() != width * height / 8 {
+            return Err(format!(
+                "Expected a {} byte bitmap for the {}x{} display, got {} bytes",
+                width * height / 8,
+                width,
+                height,
+                bitmap.len()
+            ));
+        }
+        self.second_plane.clear();
+        let row_bytes = self.display.width() / 8;
+        for (row, bytes) in bitmap.chunks_exact(row_bytes).enumerate() {
+            let mut bits = [0; 16];
+            bits[..row_bytes].copy_from_slice(bytes);
+            self.display.set_row(row, u128::from_be_bytes(bits));
+        }
+        Ok(())
+    }
+
+    /// The display as one line of `#` (on) and `.` (off) per row, for comparing in tests
+    #[cfg(test)]
+    pub fn to_text(&self) -> String {
+        let (width, height) = (self.display.width(), self.display.height());
+        let mut text = String::with_capacity(height * (width + 1));
+        for row in self.display.rows() {
+            text.extend(row.map(|pixel| if pixel { '#' } else { '.' }));
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Inverse of `to_text`, in high resolution if there are 64 rows. Keys start released
+    #[cfg(test)]
+    pub fn from_text(text: &str) -> Result<Chip8IO, String> {
+        let mut io = Chip8IO::new();
+        let lines: Vec<&str> = text.lines().collect();
+        io.set_hires(lines.len() == HIRES_ROWS);
+        if lines.len() != io.display.height() {
+            return Err(format!(
+                "Expected {} or {} rows, got {}",
+                DISPLAY_ROWS,
+                HIRES_ROWS,
+                lines.len()
+            ));
+        }
+        let width = io.display.width();
+        for (row, line) in lines.into_iter().enumerate() {
+            if line.chars().count() != width {
+                return Err(format!("Expected {} columns in {:?}", width, line));
+            }
+            for (col, c) in line.chars().enumerate() {
+                let pixel = match c {
+                    '#' => true,
+                    '.' => false,
+                    _ => return Err(format!("Unexpected {:?} in display", c)),
+                };
+                io.display.set(row, col, pixel);
+            }
+        }
+        Ok(io)
+    }
 }
 
 #[derive(Debug)]
 pub struct Chip8 {
     pub stack: Vec<u16>,
+    /// CALL fails once the stack holds this many return addresses
+    pub max_stack_depth: usize,
     pub pc: u16,
-    pub reg: [u8; 16],
+    /// v0 to vF, then any extra registers. Instructions only have a nibble for the register, so
+    /// they can only reach the first 16
+    pub reg: Vec<u8>,
     pub idx: u16,
+    /// Address of the sprite for digit 0. The other digits follow it. None if no font was loaded
+    font_base: Option<u16>,
     pub delay: u8,
+    pub sound: u8,
+    /// Where to play the beep while the sound timer is running
+    pub audio: Option<Box<dyn AudioSink>>,
+    /// Whether the sink is currently beeping
+    tone_on: bool,
+    beep: Beep,
+    /// Where the timers get the time from
+    clock: Box<dyn Clock>,
+    /// When set, `step` leaves the timers alone, and whoever runs the CPU calls `end_frame`
+    /// at 60Hz instead
+    pub external_timers: bool,
+    /// When the timers are due to count down next, minus `TIMER_PERIOD`
     tick: time::Instant,
-    init_mem: Box<[u8; 4096]>,
-    pub mem: Box<[u8; 4096]>,
+    /// Whether a sprite was drawn since the last frame ended, for `Quirks::display_wait`
+    drew_this_frame: bool,
+    /// The XO-CHIP planes that `DRAW`, `CLR` and the scrolls work on, as a bitmask for
+    /// `Chip8IO::planes_mut`. Only the first plane until a ROM says otherwise with `PLANE`
+    pub selected_planes: u8,
+    init_mem: Box<[u8; MEMORY_SIZE]>,
+    pub mem: Box<[u8; MEMORY_SIZE]>,
     pub io: Arc<Mutex<Chip8IO>>,
 
+    /// Stop executing instructions
     pub paused: bool,
+    /// Stop the delay and sound timers. Independent of `paused`, so either can run alone
+    pub timers_paused: bool,
+    /// Leave the display as it is on `reset`, so the next run draws over the last one
+    pub persist_display: bool,
+    /// A key `KEYD` returned has to be released before `KEYD` can return it again, so holding a
+    /// key down doesn't count as pressing it over and over
+    pub keyd_needs_release: bool,
+    /// Bitset of keys `KEYD` returned that haven't been released since
+    keyd_held: u16,
+    pub quirks: Quirks,
+    /// Told about every instruction whose result depends on a quirk
+    pub diagnostics: Option<Box<dyn DiagnosticSink>>,
+    /// Draw sprites a row at a time into the packed display, stopping at the first clipped row.
+    /// Turning this off draws them pixel by pixel, which is slower but obviously correct
+    pub draw_fast_path: bool,
+    pub decode_error_policy: DecodeErrorPolicy,
+    pub sys_policy: SysPolicy,
+    /// Stop with an error when fetching from an odd address, instead of decoding the two bytes
+    /// there. Real interpreters only ever fetch from even addresses
+    pub strict_alignment: bool,
+    /// Stop with an error when `ADD`, `ADDR`, `SUB` or `SUBN` overflow, instead of wrapping
+    /// around (and setting VF), to catch arithmetic that wasn't meant to wrap
+    pub trap_overflow: bool,
+    /// Names for addresses and registers, for the debugger to show
+    pub symbols: Symbols,
+    /// Pause before executing any instruction of these kinds
+    pub opcode_breakpoints: HashMap<Discriminant<Instruction>, BreakAction>,
+    /// Where `BreakAction::Log` breakpoints append the CPU state
+    pub breakpoint_log: Option<fs::File>,
+
+    /// Gets the state after every executed instruction that matches one of `trace_filters`, or
+    /// after every instruction if there are no filters
+    pub trace: Option<Box<dyn TraceSink>>,
+    pub trace_filters: Vec<TraceFilter>,
+
+    /// Why the last step stopped the program, if it did
+    stopped: Option<RunStatus>,
+    /// Set when stopped at a breakpoint, so that resuming executes the instruction
+    at_breakpoint: bool,
+
+    /// Scripted key presses, applied as instructions execute
+    pub key_replay: Option<KeyReplay>,
+
+    /// When set, time spent waiting for locks is recorded here
+    pub lock_profiler: Option<Arc<LockProfiler>>,
+
+    /// When set, registers read before ever being written hold garbage from this RNG instead of 0
+    uninit_rng: Option<StdRng>,
+    /// Where `RAND` gets its numbers. Cloning it saves the exact sequence to come, so restoring
+    /// the clone later repeats it
+    pub rand_rng: Pcg32,
+    /// Set by `seed_rand`, so `reset` can start the sequence over
+    rand_seed: Option<u64>,
+    /// Bitset of registers that have been written (or filled with garbage) since reset
+    reg_written: u16,
+
+    /// Bitset of registers that `SKPR`/`SKUP` have checked for a key since reset
+    pub checked_registers: u16,
+
+    /// Whether an instruction at each address has run since reset
+    pub executed: Box<[bool; MEMORY_SIZE]>,
+
+    /// The last `history_len` executed instructions and their addresses, oldest first
+    pub history: VecDeque<(u16, Instruction)>,
+    history_len: usize,
+
+    /// Both display planes before each of the last `DISPLAY_HISTORY_LEN` `DRAW`s and `CLR`s,
+    /// oldest first
+    display_history: VecDeque<(Framebuffer, Framebuffer)>,
+}
+
+/// What `Chip8` is serialized as: the machine state, with the IO by value. Settings other than
+/// the quirks, the debugger's state and the sinks are left out, and come back as `Chip8::builder`
+/// sets them
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SerializedChip8 {
+    pc: u16,
+    idx: u16,
+    reg: Vec<u8>,
+    stack: Vec<u16>,
+    delay: u8,
+    sound: u8,
+    selected_planes: u8,
+    quirks: Quirks,
+    mem: Vec<u8>,
+    io: Chip8IO,
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Chip8 {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let io = self.io.lock().unwrap();
+        SerializedChip8 {
+            pc: self.pc,
+            idx: self.idx,
+            reg: self.reg.clone(),
+            stack: self.stack.clone(),
+            delay: self.delay,
+            sound: self.sound,
+            selected_planes: self.selected_planes,
+            quirks: self.quirks,
+            mem: self.mem.to_vec(),
+            io: Chip8IO {
+                keystate: io.keystate,
+                remote_keys: io.remote_keys,
+                replay_keys: io.replay_keys,
+                display: io.display,
+                second_plane: io.second_plane,
+                frames: io.frames,
+            },
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Deserializes to a paused CPU with its own IO, which starts over from the deserialized memory
+/// on `reset`
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Chip8 {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let state = SerializedChip8::deserialize(deserializer)?;
+        if state.mem.len() != MEMORY_SIZE {
+            return Err(D::Error::invalid_length(state.mem.len(), &"64K of memory"));
+        }
+        if !(DEFAULT_REGISTERS..=MAX_REGISTERS).contains(&state.reg.len()) {
+            return Err(D::Error::invalid_length(
+                state.reg.len(),
+                &"16 to 64 registers",
+            ));
+        }
+        if state.stack.len() > DEFAULT_STACK_DEPTH {
+            return Err(D::Error::invalid_length(
+                state.stack.len(),
+                &"at most 16 return addresses",
+            ));
+        }
+        if state.selected_planes > 3 {
+            return Err(D::Error::invalid_value(
+                serde::de::Unexpected::Unsigned(state.selected_planes as u64),
+                &"planes 0 to 3",
+            ));
+        }
+        let mut cpu = Chip8::builder(&[], Arc::new(Mutex::new(Chip8IO::new())))
+            .paused(true)
+            .build();
+        // Building resets the IO, so it goes in afterwards
+        *cpu.io.lock().unwrap() = state.io;
+        cpu.pc = state.pc;
+        cpu.idx = state.idx;
+        cpu.reg = state.reg;
+        cpu.stack = state.stack;
+        cpu.delay = state.delay;
+        cpu.sound = state.sound;
+        cpu.selected_planes = state.selected_planes;
+        cpu.quirks = state.quirks;
+        cpu.mem.copy_from_slice(&state.mem);
+        cpu.init_mem.copy_from_slice(&state.mem);
+        Ok(cpu)
+    }
+}
+
+/// Reads the fields of a saved state in order
+struct StateReader<'a>(&'a [u8]);
+
+impl<'a> StateReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.0.len() < len {
+            return Err("Saved state ends early".to_string());
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn word(&mut self) -> Result<u16, String> {
+        Ok(u16::from_be_bytes([self.byte()?, self.byte()?]))
+    }
+}
+
+/// What to do when the word at the pc is not a valid instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeErrorPolicy {
+    /// Stop with an error
+    Halt,
+    /// Skip the whole word and carry on with the next one
+    SkipWord,
+    /// Treat the first byte as data and try decoding again from the next byte
+    SkipByte,
+}
+
+impl FromStr for DecodeErrorPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "halt" => Ok(DecodeErrorPolicy::Halt),
+            "skip-word" => Ok(DecodeErrorPolicy::SkipWord),
+            "skip-byte" => Ok(DecodeErrorPolicy::SkipByte),
+            _ => Err(format!(
+                "Unknown policy {} (expected halt, skip-word or skip-byte)",
+                s
+            )),
+        }
+    }
+}
+
+/// What to do on a `SYS nnn` call to machine code, other than `SYS 0`, which always ends the
+/// program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SysPolicy {
+    /// Stop with an error
+    Error,
+    /// Carry on with the next instruction, like most modern interpreters
+    Ignore,
+    /// End the program, like `SYS 0`
+    End,
+}
+
+impl FromStr for SysPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(SysPolicy::Error),
+            "ignore" => Ok(SysPolicy::Ignore),
+            "end" => Ok(SysPolicy::End),
+            _ => Err(format!(
+                "Unknown policy {} (expected error, ignore or end)",
+                s
+            )),
+        }
+    }
+}
+
+/// What an opcode breakpoint does when hit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakAction {
+    /// Pause before executing the instruction
+    Pause,
+    /// Append the CPU state to the breakpoint log and carry on
+    Log,
+}
+
+/// Whether the CPU is running, and if not, why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunStatus {
+    Running,
+    Paused,
+    /// Stuck jumping to itself, which is how many ROMs finish
+    Looping,
+    /// Stopped by an error
+    Halted(String),
+    /// Reached `SYS 0`
+    Ended,
+}
+
+impl Display for RunStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunStatus::Running => write!(f, "Running"),
+            RunStatus::Paused => write!(f, "Paused"),
+            RunStatus::Looping => write!(f, "Looping"),
+            RunStatus::Halted(reason) => write!(f, "Halted: {}", reason),
+            RunStatus::Ended => write!(f, "Ended"),
+        }
+    }
 }
 
 /// Outcome of one step of execution
@@ -85,6 +583,9 @@ pub enum StepResult {
     /// Program continues. Bool specifies whether the display was updated
     Continue(bool),
 
+    /// Hit a breakpoint. The CPU is now paused, before executing the instruction at the pc
+    Breakpoint,
+
     /// Endlessly looping
     Loop,
 
@@ -92,6 +593,19 @@ pub enum StepResult {
     End,
 }
 
+/// What happened during a `step_batch`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BatchResult {
+    /// Instructions executed (or skipped while paused)
+    pub steps: usize,
+    /// Whether any of them updated the display
+    pub drew: bool,
+    /// The batch stopped at a breakpoint. The CPU is now paused, before the instruction at the pc
+    pub breakpoint: bool,
+    /// Looping, Ended or Halted, if the program stopped during the batch
+    pub stopped: Option<RunStatus>,
+}
+
 fn wkey(f: &mut fmt::Formatter<'_>, keystate: [bool; 16], key: u8) -> fmt::Result {
     if keystate[key as usize] {
         write!(f, "{:X}", key)
@@ -123,11 +637,9 @@ impl Display for Chip8IO {
         wkey(f, self.keystate, 0xF)?;
         writeln!(f)?;
 
-        writeln!(
-            f,
-            "\n┌────────────────────────────────────────────────────────────────┐"
-        )?;
-        for row in self.display {
+        let border = "─".repeat(self.display.width());
+        writeln!(f, "\n┌{}┐", border)?;
+        for row in self.display.rows() {
             write!(f, "│")?;
             for pixel in row {
                 if pixel {
@@ -136,12 +648,9 @@ impl Display for Chip8IO {
                     write!(f, "·")?;
                 }
             }
-            write!(f, "│\n")?;
+            writeln!(f, "│")?;
         }
-        writeln!(
-            f,
-            "└────────────────────────────────────────────────────────────────┘"
-        )?;
+        writeln!(f, "└{}┘", border)?;
         Ok(())
     }
 }
@@ -150,213 +659,802 @@ impl Display for Chip8 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let instr = match self.current_instruction() {
             Ok(i) => format!("{}", i),
-            Err(e) => e,
+            Err(e) => e.to_string(),
         };
 
         write!(
             f,
-            "CHIP8 | pc: {:#X} | {:<20} | idx: {:>3X} | reg: {:?} | stack: {}",
+            "CHIP8 | pc: {:#X} | {:<20} | idx: {:>3X} | reg: {:?} | stack: {} | delay: {} | sound: {}",
             self.pc,
             instr,
             self.idx,
             self.reg,
-            self.stack.len()
+            self.stack.len(),
+            self.delay,
+            self.sound
         )?;
         Ok(())
     }
 }
 
-impl Chip8 {
-    pub fn new(instruction_section: &[u8], io: Arc<Mutex<Chip8IO>>, paused: bool) -> Chip8 {
-        let mut mem = Box::new([0; 4096]);
-        mem[0] = 0b11110000;
-        mem[1] = 0b10010000;
-        mem[2] = 0b10010000;
-        mem[3] = 0b10010000;
-        mem[4] = 0b11110000;
-
-        mem[5] = 0b00100000;
-        mem[6] = 0b01100000;
-        mem[7] = 0b00100000;
-        mem[8] = 0b00100000;
-        mem[9] = 0b01110000;
-
-        mem[10] = 0b11110000;
-        mem[11] = 0b00010000;
-        mem[12] = 0b11110000;
-        mem[13] = 0b10000000;
-        mem[14] = 0b11110000;
-
-        mem[15] = 0b11110000;
-        mem[16] = 0b00010000;
-        mem[17] = 0b11110000;
-        mem[18] = 0b00010000;
-        mem[19] = 0b11110000;
-
-        mem[20] = 0b10010000;
-        mem[21] = 0b10010000;
-        mem[22] = 0b11110000;
-        mem[23] = 0b00010000;
-        mem[24] = 0b00010000;
-
-        mem[25] = 0b11110000;
-        mem[26] = 0b10000000;
-        mem[27] = 0b11110000;
-        mem[28] = 0b00010000;
-        mem[29] = 0b11110000;
-
-        mem[30] = 0b11110000;
-        mem[31] = 0b10000000;
-        mem[32] = 0b11110000;
-        mem[33] = 0b10010000;
-        mem[34] = 0b11110000;
-
-        mem[35] = 0b11110000;
-        mem[36] = 0b00010000;
-        mem[37] = 0b00100000;
-        mem[38] = 0b01000000;
-        mem[39] = 0b10000000;
-
-        mem[40] = 0b11110000;
-        mem[41] = 0b10010000;
-        mem[42] = 0b11110000;
-        mem[43] = 0b10010000;
-        mem[44] = 0b11110000;
-
-        mem[45] = 0b11110000;
-        mem[46] = 0b10010000;
-        mem[47] = 0b11110000;
-        mem[48] = 0b00010000;
-        mem[49] = 0b11110000;
-
-        mem[50] = 0b11110000;
-        mem[51] = 0b10010000;
-        mem[52] = 0b11110000;
-        mem[53] = 0b10010000;
-        mem[54] = 0b10010000;
-
-        mem[55] = 0b11100000;
-        mem[56] = 0b10010000;
-        mem[57] = 0b11100000;
-        mem[58] = 0b10010000;
-        mem[59] = 0b11100000;
-
-        mem[60] = 0b11110000;
-        mem[61] = 0b10000000;
-        mem[62] = 0b10000000;
-        mem[63] = 0b10000000;
-        mem[64] = 0b11110000;
-
-        mem[65] = 0b11100000;
-        mem[66] = 0b10010000;
-        mem[67] = 0b10010000;
-        mem[68] = 0b10010000;
-        mem[69] = 0b11100000;
-
-        mem[70] = 0b11110000;
-        mem[71] = 0b10000000;
-        mem[72] = 0b11110000;
-        mem[73] = 0b10000000;
-        mem[74] = 0b11110000;
-
-        mem[75] = 0b11110000;
-        mem[76] = 0b10000000;
-        mem[77] = 0b11110000;
-        mem[78] = 0b10000000;
-        mem[79] = 0b10000000;
+/// Configures a `Chip8` before it is created
+pub struct Chip8Builder<'a> {
+    rom: &'a [u8],
+    io: Arc<Mutex<Chip8IO>>,
+    paused: bool,
+    font_base: Option<u16>,
+    clock: Box<dyn Clock>,
+    history_len: usize,
+    registers: usize,
+    config: RomConfig,
+}
 
-        mem[0x200..0x200 + instruction_section.len()].copy_from_slice(instruction_section);
+impl<'a> Chip8Builder<'a> {
+    /// Use the settings from the ROM's sidecar file
+    pub fn rom_config(mut self, config: &RomConfig) -> Self {
+        self.config = config.clone();
+        self
+    }
+
+    pub fn paused(mut self, paused: bool) -> Self {
+        self.paused = paused;
+        self
+    }
+
+    /// Load the built-in font at this address, or leave low memory alone with `None`. Panics if
+    /// the font doesn't fit below 0x200, where the ROM goes
+    pub fn with_font(mut self, base: Option<u16>) -> Self {
+        if let Some(base) = base {
+            assert!(
+                base as usize + FONT.len() <= 0x200,
+                "Font at {:#x} doesn't fit below 0x200",
+                base
+            );
+        }
+        self.font_base = base;
+        self
+    }
+
+    /// Keep this many executed instructions in `Chip8::history`, up to `MAX_HISTORY_LEN`
+    pub fn history_len(mut self, len: usize) -> Self {
+        self.history_len = len.min(MAX_HISTORY_LEN);
+        self
+    }
+
+    /// Give the CPU this many registers, between 16 and `MAX_REGISTERS`
+    pub fn registers(mut self, count: usize) -> Self {
+        self.registers = count.clamp(DEFAULT_REGISTERS, MAX_REGISTERS);
+        self
+    }
 
-        Chip8 {
-            reg: [0; 16],
+    #[cfg(test)]
+    pub fn clock(mut self, clock: Box<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Panics if the ROM is longer than `MAX_ROM_SIZE`
+    pub fn build(self) -> Chip8 {
+        let mut cpu = Chip8 {
+            reg: vec![0; self.registers],
             idx: 0,
+            font_base: self.font_base,
             pc: 0x200,
             stack: Vec::new(),
+            max_stack_depth: DEFAULT_STACK_DEPTH,
             delay: 0,
-            tick: time::Instant::now(),
-            init_mem: mem.clone(),
-            mem,
+            sound: 0,
+            audio: None,
+            tone_on: false,
+            beep: Beep::default(),
+            tick: self.clock.now(),
+            clock: self.clock,
+            external_timers: false,
+            drew_this_frame: false,
+            selected_planes: 1,
+            init_mem: Box::new([0; MEMORY_SIZE]),
+            mem: Box::new([0; MEMORY_SIZE]),
+            io: self.io,
+            paused: self.paused,
+            timers_paused: self.paused,
+            persist_display: false,
+            keyd_needs_release: false,
+            keyd_held: 0,
+            quirks: self.config.quirks(
+                rom::known_profile(self.rom)
+                    .map(Profile::quirks)
+                    .unwrap_or_default(),
+            ),
+            diagnostics: None,
+            draw_fast_path: true,
+            decode_error_policy: DecodeErrorPolicy::Halt,
+            strict_alignment: false,
+            trap_overflow: false,
+            symbols: Symbols::default(),
+            sys_policy: SysPolicy::Error,
+            opcode_breakpoints: HashMap::new(),
+            breakpoint_log: None,
+            trace: None,
+            trace_filters: Vec::new(),
+            stopped: None,
+            at_breakpoint: false,
+            key_replay: None,
+            lock_profiler: None,
+            uninit_rng: None,
+            rand_rng: Pcg32::from_entropy(),
+            rand_seed: None,
+            reg_written: 0,
+            checked_registers: 0,
+            executed: Box::new([false; MEMORY_SIZE]),
+            history: VecDeque::with_capacity(self.history_len),
+            history_len: self.history_len,
+            display_history: VecDeque::with_capacity(DISPLAY_HISTORY_LEN),
+        };
+        if let Err(e) = cpu.load_rom(self.rom) {
+            panic!("{}", e);
+        }
+        cpu
+    }
+}
+
+impl Chip8 {
+    /// Pause or resume both instructions and timers
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.timers_paused = paused;
+    }
+
+    pub fn history_len(&self) -> usize {
+        self.history_len
+    }
+
+    /// Change how many executed instructions `history` keeps, up to `MAX_HISTORY_LEN`. Shrinking
+    /// it drops the oldest ones
+    pub fn set_history_len(&mut self, len: usize) {
+        self.history_len = len.min(MAX_HISTORY_LEN);
+        while self.history.len() > self.history_len {
+            self.history.pop_front();
+        }
+    }
+
+    /// Start configuring a CPU that will run `instruction_section`, loaded at 0x200
+    pub fn builder(instruction_section: &[u8], io: Arc<Mutex<Chip8IO>>) -> Chip8Builder<'_> {
+        Chip8Builder {
+            rom: instruction_section,
             io,
-            paused,
+            paused: false,
+            font_base: Some(DEFAULT_FONT_BASE),
+            clock: Box::new(SystemClock),
+            history_len: DEFAULT_HISTORY_LEN,
+            registers: DEFAULT_REGISTERS,
+            config: RomConfig::default(),
         }
     }
 
+    /// A CPU with the default settings. Use `builder` to change them
+    pub fn new(instruction_section: &[u8], io: Arc<Mutex<Chip8IO>>, paused: bool) -> Chip8 {
+        Self::builder(instruction_section, io)
+            .paused(paused)
+            .build()
+    }
+
+    /// Make reads of registers that were never written return random (non-zero) values, to
+    /// surface ROMs that rely on registers being zero-initialised
+    pub fn randomize_uninit_regs(&mut self, seed: u64) {
+        self.uninit_rng = Some(StdRng::seed_from_u64(seed));
+    }
+
+    /// Make `RAND` produce the same numbers on every run, and again after every reset
+    pub fn seed_rand(&mut self, seed: u64) {
+        self.rand_seed = Some(seed);
+        self.rand_rng = Pcg32::seed_from_u64(seed);
+    }
+
+    /// Memory image at power-on: the font at `font_base` (if any) and the ROM at 0x200
+    fn initial_memory(
+        font_base: Option<u16>,
+        instruction_section: &[u8],
+    ) -> Box<[u8; MEMORY_SIZE]> {
+        let mut mem = Box::new([0; MEMORY_SIZE]);
+        if let Some(base) = font_base {
+            mem[base as usize..base as usize + FONT.len()].copy_from_slice(&FONT);
+        }
+        mem[0x200..0x200 + instruction_section.len()].copy_from_slice(instruction_section);
+        mem
+    }
+
+    /// Replace the loaded ROM and reset. Settings (pause state, options) are kept. A ROM that
+    /// doesn't fit in memory is rejected, and the old one stays loaded.
+    pub fn load_rom(&mut self, instruction_section: &[u8]) -> Result<(), String> {
+        if instruction_section.len() > MAX_ROM_SIZE {
+            return Err(format!(
+                "ROM is {} bytes, but only {} fit in memory",
+                instruction_section.len(),
+                MAX_ROM_SIZE
+            ));
+        }
+        self.init_mem = Self::initial_memory(self.font_base, instruction_section);
+        self.reset();
+        Ok(())
+    }
+
     fn advance(&mut self, amount: u16) -> Result<StepResult, String> {
-        self.pc += amount;
+        self.pc = self.pc.wrapping_add(amount);
         Ok(StepResult::Continue(false))
     }
 
+    /// With `trap_overflow`, the error for `instr` overflowing
+    fn check_overflow(&self, instr: Instruction, overflowed: bool) -> Result<(), String> {
+        if overflowed && self.trap_overflow {
+            return Err(format!(
+                "Arithmetic overflow in {} at {:#x}",
+                instr, self.pc
+            ));
+        }
+        Ok(())
+    }
+
+    /// XOR `sprite`, `row_bytes` wide, onto `display` with its top left corner at
+    /// (`row_start`, `col_start`). Returns whether that turned off any pixels
+    fn draw_sprite(
+        &self,
+        display: &mut Framebuffer,
+        sprite: &[u8],
+        row_bytes: usize,
+        row_start: usize,
+        col_start: usize,
+    ) -> bool {
+        let (width, height) = (display.width(), display.height());
+        let clip = self.quirks.clip_sprites;
+        let ignore_wrapped = self.quirks.ignore_wrapped_collisions && !clip;
+
+        let mut collided = false;
+        for (i, bytes) in sprite.chunks(row_bytes).enumerate() {
+            let row = row_start + i;
+            if self.draw_fast_path && clip && row >= height {
+                // Clipped, and so are all the rows below
+                break;
+            }
+            for (j, &byte) in bytes.iter().enumerate() {
+                let col = col_start + 8 * j;
+                // Only the pixels that would still be drawn if the sprite were clipped
+                let unwrapped_collision = ignore_wrapped
+                    && row < height
+                    && col < width
+                    && display.collides(row, col, byte, true);
+                let any_collision = if !self.draw_fast_path {
+                    display.xor_byte_scalar(row, col, byte, clip)
+                } else if clip && col >= width {
+                    false
+                } else {
+                    display.xor_byte(row % height, col % width, byte, clip)
+                };
+                collided |= if ignore_wrapped {
+                    unwrapped_collision
+                } else {
+                    any_collision
+                };
+            }
+        }
+        collided
+    }
+
+    /// Skip over the next instruction, which is two words long if it's a `LOADILONG`
+    fn skip(&mut self) -> Result<StepResult, String> {
+        let next = self.pc as usize + 2;
+        let long = self.mem.get(next..next + 2) == Some(&[0xF0, 0x00][..]);
+        self.advance(if long { 6 } else { 4 })
+    }
+
+    /// The `len` bytes of memory from I, or an error saying `what` would go past the end
+    fn idx_range(&self, len: usize, what: &str) -> Result<Range<usize>, String> {
+        let start = self.idx as usize;
+        if start + len > MEMORY_SIZE {
+            return Err(format!("{} past end of memory at {:#x}", what, self.idx));
+        }
+        Ok(start..start + len)
+    }
+
     pub fn reset(&mut self) {
-        self.reg = [0; 16];
+        self.reg.fill(0);
         self.idx = 0;
         self.pc = 0x200;
         self.stack = Vec::new();
         self.delay = 0;
-        self.tick = time::Instant::now();
+        self.sound = 0;
+        self.update_tone();
+        self.reg_written = 0;
+        self.checked_registers = 0;
+        self.at_breakpoint = false;
+        self.history.clear();
+        self.executed.fill(false);
+        self.display_history.clear();
+        self.stopped = None;
+        self.keyd_held = 0;
+        self.selected_planes = 1;
+        if let Some(replay) = &mut self.key_replay {
+            replay.rewind();
+        }
+        if let Some(seed) = self.rand_seed {
+            self.rand_rng = Pcg32::seed_from_u64(seed);
+        }
+        self.tick = self.clock.now();
         self.mem = self.init_mem.clone();
-        self.io.lock().unwrap().reset();
+        let mut io = self.io.lock().unwrap();
+        if self.persist_display {
+            io.keystate = [false; 16];
+            io.replay_keys = [false; 16];
+        } else {
+            io.reset();
+        }
+    }
+
+    /// The registers, I, pc, timers, stack, memory and display, for `load_state` to restore
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = STATE_MAGIC.to_vec();
+        state.push(STATE_VERSION);
+        state.extend(self.pc.to_be_bytes());
+        state.extend(self.idx.to_be_bytes());
+        state.extend([self.delay, self.sound, self.selected_planes]);
+        state.push(self.reg.len() as u8);
+        state.extend(&self.reg);
+        state.extend((self.stack.len() as u16).to_be_bytes());
+        state.extend(self.stack.iter().flat_map(|addr| addr.to_be_bytes()));
+        state.extend(self.mem.iter());
+        let io = self.io.lock().unwrap();
+        state.push(io.display.hires() as u8);
+        for plane in [&io.display, &io.second_plane] {
+            let row_bytes = plane.width() / 8;
+            for row in 0..plane.height() {
+                state.extend(&plane.row(row).to_be_bytes()[..row_bytes]);
+            }
+        }
+        state
+    }
+
+    /// Restore what `save_state` saved. The saved memory becomes the program, so `rom` and
+    /// `reset` go back to it rather than to the ROM that was loaded before. Nothing changes if
+    /// `state` isn't a whole state from this version, or was saved with a different number of
+    /// registers
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let mut input = StateReader(state);
+        if input.take(4)? != STATE_MAGIC {
+            return Err("Not a saved state".to_string());
+        }
+        let version = input.byte()?;
+        if version != STATE_VERSION {
+            return Err(format!(
+                "Saved state is version {}, expected {}",
+                version, STATE_VERSION
+            ));
+        }
+        let pc = input.word()?;
+        let idx = input.word()?;
+        let [delay, sound, selected_planes] = <[u8; 3]>::try_from(input.take(3)?).unwrap();
+        if selected_planes > 3 {
+            return Err(format!(
+                "Saved state selects planes {}, past the second",
+                selected_planes
+            ));
+        }
+        let reg_count = input.byte()? as usize;
+        if reg_count != self.reg.len() {
+            return Err(format!(
+                "Saved state has {} registers, expected {}",
+                reg_count,
+                self.reg.len()
+            ));
+        }
+        let reg = input.take(reg_count)?.to_vec();
+        let stack_len = input.word()?;
+        if stack_len as usize > self.max_stack_depth {
+            return Err(format!(
+                "Saved state has {} return addresses, but the stack only holds {}",
+                stack_len, self.max_stack_depth
+            ));
+        }
+        let stack = (0..stack_len)
+            .map(|_| input.word())
+            .collect::<Result<Vec<_>, _>>()?;
+        let mem = input.take(MEMORY_SIZE)?;
+        let mut display = Framebuffer::new();
+        display.set_hires(input.byte()? != 0);
+        let mut second_plane = display;
+        for plane in [&mut display, &mut second_plane] {
+            let row_bytes = plane.width() / 8;
+            for row in 0..plane.height() {
+                let mut bits = [0; 16];
+                bits[..row_bytes].copy_from_slice(input.take(row_bytes)?);
+                plane.set_row(row, u128::from_be_bytes(bits));
+            }
+        }
+        if !input.0.is_empty() {
+            return Err(format!(
+                "{} unexpected bytes after saved state",
+                input.0.len()
+            ));
+        }
+
+        self.pc = pc;
+        self.idx = idx;
+        self.delay = delay;
+        self.sound = sound;
+        self.update_tone();
+        self.selected_planes = selected_planes;
+        self.reg = reg;
+        self.stack = stack;
+        self.mem.copy_from_slice(mem);
+        self.init_mem.copy_from_slice(mem);
+        self.stopped = None;
+        self.at_breakpoint = false;
+        self.history.clear();
+        self.executed.fill(false);
+        self.display_history.clear();
+        let mut io = self.io.lock().unwrap();
+        io.display = display;
+        io.second_plane = second_plane;
+        Ok(())
+    }
+
+    /// Put both display planes back how they were before the last `DRAW` or `CLR`, leaving the
+    /// rest of the CPU alone. Returns false if there is no earlier display to go back to
+    pub fn previous_frame(&mut self) -> bool {
+        match self.display_history.pop_back() {
+            Some((display, second_plane)) => {
+                let mut io = self.io.lock().unwrap();
+                io.display = display;
+                io.second_plane = second_plane;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remember the display before an instruction changes it, for `previous_frame`
+    fn save_display(&mut self, io: &Chip8IO) {
+        if self.display_history.len() == DISPLAY_HISTORY_LEN {
+            self.display_history.pop_front();
+        }
+        self.display_history
+            .push_back((io.display, io.second_plane));
+    }
+
+    /// The ROM as loaded at 0x200, with the rest of memory after it
+    pub fn rom(&self) -> &[u8] {
+        &self.init_mem[0x200..]
+    }
+
+    pub fn current_instruction(&self) -> Result<Instruction, Chip8Error> {
+        Instruction::decode(&self.mem[self.pc as usize..])
+    }
+
+    /// Every address in memory where `pattern` starts
+    pub fn find_bytes(&self, pattern: &[u8]) -> Vec<u16> {
+        if pattern.is_empty() {
+            return Vec::new();
+        }
+        self.mem
+            .windows(pattern.len())
+            .enumerate()
+            .filter(|(_, window)| *window == pattern)
+            .map(|(addr, _)| addr as u16)
+            .collect()
+    }
+
+    /// Decode the memory between `start` (inclusive) and `end` (exclusive) as instructions
+    pub fn disasm_range(
+        &self,
+        start: u16,
+        end: u16,
+    ) -> impl Iterator<Item = (u16, Result<Instruction, Chip8Error>)> + '_ {
+        let end = (end as usize).min(self.mem.len());
+        let start = (start as usize).min(end);
+        instruction::disassemble(&self.mem[start..end], start as u16)
+    }
+
+    /// Tell the audio sink to start or stop beeping when the sound timer starts or stops
+    fn update_tone(&mut self) {
+        // Once the program is over nothing would stop the beep, so it stops with the program
+        let ended = matches!(self.stopped, Some(RunStatus::Ended | RunStatus::Halted(_)));
+        let on = self.sound > 0 && !ended;
+        if on != self.tone_on {
+            self.tone_on = on;
+            if let Some(audio) = &mut self.audio {
+                audio.set_tone(on, self.beep);
+            }
+        }
+    }
+
+    pub fn beep(&self) -> Beep {
+        self.beep
+    }
+
+    /// Change what the sound timer plays. Takes effect straight away if it's already beeping
+    pub fn set_beep(&mut self, beep: Beep) {
+        self.beep = beep;
+        if let (true, Some(audio)) = (self.tone_on, &mut self.audio) {
+            audio.set_tone(true, beep);
+        }
+    }
+
+    /// Append a line with the current state to the breakpoint log, if there is one
+    fn log_state(&mut self) -> Result<(), String> {
+        let state = self.to_string();
+        if let Some(log) = &mut self.breakpoint_log {
+            writeln!(log, "{}", state).map_err(|e| format!("Writing breakpoint log: {}", e))?;
+        }
+        Ok(())
+    }
+
+    /// End one of the 60 frames a second: count the delay and sound timers down by one, unless
+    /// they're paused, and let `Quirks::display_wait` draw again
+    pub fn end_frame(&mut self) {
+        self.drew_this_frame = false;
+        self.io.lock().unwrap().frames += 1;
+        if self.timers_paused {
+            return;
+        }
+        self.delay = self.delay.saturating_sub(1);
+        self.sound = self.sound.saturating_sub(1);
+        self.update_tone();
+    }
+
+    /// Run until an instruction changes the display, for at most `max_steps` instructions, even
+    /// if paused. Returns whether the display changed. Stops early at breakpoints and when the
+    /// program ends
+    pub fn step_to_display_update(&mut self, max_steps: u64) -> Result<bool, String> {
+        let paused = mem::replace(&mut self.paused, false);
+        let mut result = Ok(false);
+        for _ in 0..max_steps {
+            match self.step() {
+                Ok(StepResult::Continue(false)) => {}
+                Ok(StepResult::Continue(true)) => {
+                    result = Ok(true);
+                    break;
+                }
+                Ok(_) => break,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        self.paused = paused;
+        result
+    }
+
+    /// Run up to `n` instructions, stopping early at a breakpoint or when the program stops.
+    /// For front-ends where calling `step` for every instruction is too slow
+    pub fn step_batch(&mut self, n: usize) -> BatchResult {
+        let mut result = BatchResult::default();
+        while result.steps < n {
+            let step = self.step();
+            result.steps += 1;
+            match step {
+                Ok(StepResult::Continue(drew)) => result.drew |= drew,
+                Ok(StepResult::Breakpoint) => {
+                    result.breakpoint = true;
+                    break;
+                }
+                Ok(StepResult::Loop | StepResult::End) | Err(_) => {
+                    result.stopped = self.stopped.clone();
+                    break;
+                }
+            }
+        }
+        result
     }
 
-    pub fn current_instruction(&self) -> Result<Instruction, String> {
-        Instruction::try_from(u16::from_be_bytes([
-            self.mem[self.pc as usize],
-            self.mem[self.pc as usize + 1],
-        ]))
+    /// Run until the first frame is drawn, then pause, so a freshly loaded program shows
+    /// something instead of a blank screen. Returns whether anything was drawn within
+    /// `max_steps` instructions
+    pub fn run_to_first_draw(&mut self, max_steps: u64) -> Result<bool, String> {
+        let drew = self.step_to_display_update(max_steps);
+        self.set_paused(true);
+        drew
     }
 
     pub fn step(&mut self) -> Result<StepResult, String> {
+        let result = self.step_unrecorded();
+        self.stopped = match &result {
+            Ok(StepResult::Loop) => Some(RunStatus::Looping),
+            Ok(StepResult::End) => Some(RunStatus::Ended),
+            Err(e) => Some(RunStatus::Halted(e.clone())),
+            Ok(StepResult::Continue(_) | StepResult::Breakpoint) => None,
+        };
+        self.update_tone();
+        result
+    }
+
+    /// What the front-end should say the CPU is doing
+    pub fn status(&self) -> RunStatus {
+        match &self.stopped {
+            Some(status) => status.clone(),
+            None if self.paused => RunStatus::Paused,
+            None => RunStatus::Running,
+        }
+    }
+
+    /// `step`, without remembering whether it stopped the program
+    fn step_unrecorded(&mut self) -> Result<StepResult, String> {
+        if !self.external_timers {
+            // Tick once for every period that passed since the last tick, keeping the remainder,
+            // so the timers run at 60Hz however often this is called
+            let now = self.clock.now();
+            while now.duration_since(self.tick) >= TIMER_PERIOD {
+                self.tick += TIMER_PERIOD;
+                self.end_frame();
+            }
+        }
+
         if self.paused {
             return Ok(StepResult::Continue(false));
         }
 
-        if time::Instant::now() - self.tick > time::Duration::from_millis(016) {
-            self.delay = self.delay.saturating_sub(1);
-            self.tick = time::Instant::now();
+        if self.strict_alignment && !self.pc.is_multiple_of(2) {
+            return Err(format!("Instruction fetch from odd address {:#x}", self.pc));
+        }
+
+        let instr = match self.current_instruction() {
+            Ok(instr) => instr,
+            Err(e) => {
+                return match self.decode_error_policy {
+                    DecodeErrorPolicy::Halt => Err(e.into()),
+                    DecodeErrorPolicy::SkipWord => self.advance(2),
+                    DecodeErrorPolicy::SkipByte => self.advance(1),
+                }
+            }
+        };
+
+        let resuming = mem::take(&mut self.at_breakpoint);
+        match self.opcode_breakpoints.get(&mem::discriminant(&instr)) {
+            Some(BreakAction::Pause) if !resuming => {
+                self.paused = true;
+                self.timers_paused = true;
+                self.at_breakpoint = true;
+                return Ok(StepResult::Breakpoint);
+            }
+            Some(BreakAction::Log) => self.log_state()?,
+            _ => {}
+        }
+
+        if let Some(rng) = &mut self.uninit_rng {
+            for r in instr.reads() {
+                if self.reg_written & (1 << r) == 0 {
+                    self.reg[r as usize] = rng.gen_range(1..=255);
+                    self.reg_written |= 1 << r;
+                }
+            }
+            for r in instr.writes() {
+                self.reg_written |= 1 << r;
+            }
+        }
+
+        let pc = self.pc;
+        let traced = self.trace.is_some()
+            && (self.trace_filters.is_empty()
+                || self.trace_filters.iter().any(|f| f.matches(pc, instr)));
+
+        self.executed[pc as usize] = true;
+        if self.history_len > 0 {
+            if self.history.len() == self.history_len {
+                self.history.pop_front();
+            }
+            self.history.push_back((self.pc, instr));
+        }
+
+        // The only place step() locks the IO. Instructions get the guard passed down, so they
+        // can't deadlock by locking it again.
+        let io = Arc::clone(&self.io);
+        let mut io = lock_timed(&io, self.lock_profiler.as_ref().map(|p| &p.cpu_thread));
+        if let Some(replay) = &mut self.key_replay {
+            let held = io.replay_keys;
+            replay.advance(&mut io.replay_keys);
+            // Events take effect straight away. The GUI keeps the held keys pressed when it
+            // refreshes the keyboard.
+            for (key, was_held) in held.into_iter().enumerate() {
+                if io.replay_keys[key] != was_held {
+                    io.keystate[key] = io.replay_keys[key];
+                }
+            }
+        }
+        for (key, &pressed) in io.keystate.iter().enumerate() {
+            if !pressed {
+                self.keyd_held &= !(1 << key);
+            }
+        }
+        let result = self.execute(instr, &mut io);
+        drop(io);
+
+        if traced && result.is_ok() {
+            let state = self.to_string();
+            if let Some(trace) = &mut self.trace {
+                trace.traced(pc, instr, state);
+            }
+        }
+        result
+    }
+
+    /// Report that `instr` is about to use a quirk, and return whether it is `enabled`
+    fn quirk(&mut self, instr: Instruction, quirk: &'static str, enabled: bool) -> bool {
+        if let Some(sink) = &mut self.diagnostics {
+            sink.quirk_used(QuirkUse {
+                pc: self.pc,
+                instruction: instr,
+                quirk,
+                enabled,
+            });
         }
+        enabled
+    }
 
-        match self.current_instruction()? {
+    fn execute(&mut self, instr: Instruction, io: &mut Chip8IO) -> Result<StepResult, String> {
+        match instr {
             MOVE(x, y) => {
                 self.reg[x as usize] = self.reg[y as usize];
                 self.advance(2)
             }
             OR(x, y) => {
                 self.reg[x as usize] |= self.reg[y as usize];
+                if self.quirk(instr, "vf_reset", self.quirks.vf_reset) {
+                    self.reg[0xF] = 0;
+                }
                 self.advance(2)
             }
             AND(x, y) => {
                 self.reg[x as usize] &= self.reg[y as usize];
+                if self.quirk(instr, "vf_reset", self.quirks.vf_reset) {
+                    self.reg[0xF] = 0;
+                }
                 self.advance(2)
             }
             XOR(x, y) => {
                 self.reg[x as usize] ^= self.reg[y as usize];
+                if self.quirk(instr, "vf_reset", self.quirks.vf_reset) {
+                    self.reg[0xF] = 0;
+                }
                 self.advance(2)
             }
             ADDR(x, y) => {
-                match self.reg[x as usize].checked_add(self.reg[y as usize]) {
-                    Some(val) => {
-                        self.reg[x as usize] = val;
-                        self.reg[0xf] = 0;
-                    }
-                    None => {
-                        self.reg[x as usize] =
-                            self.reg[x as usize].wrapping_add(self.reg[y as usize]);
-                        self.reg[0xf] = 1;
-                    }
-                }
+                let (val, carry) = self.reg[x as usize].overflowing_add(self.reg[y as usize]);
+                self.check_overflow(instr, carry)?;
+                self.reg[x as usize] = val;
+                // The flag is written last, so it wins when x is VF
+                self.reg[0xf] = carry as u8;
                 self.advance(2)
             }
             SUB(x, y) => {
-                self.reg[x as usize] = self.reg[x as usize].wrapping_sub(self.reg[y as usize]);
+                let (val, borrow) = self.reg[x as usize].overflowing_sub(self.reg[y as usize]);
+                self.check_overflow(instr, borrow)?;
+                self.reg[x as usize] = val;
+                self.reg[0xf] = !borrow as u8;
+                self.advance(2)
+            }
+            SUBN(x, y) => {
+                let (val, borrow) = self.reg[y as usize].overflowing_sub(self.reg[x as usize]);
+                self.check_overflow(instr, borrow)?;
+                self.reg[x as usize] = val;
+                self.reg[0xf] = !borrow as u8;
                 self.advance(2)
             }
             SHR(x, y) => {
-                self.reg[0x0F] = self.reg[y as usize] & 1;
-                self.reg[y as usize] = self.reg[x as usize] >> 1;
+                let src = if self.quirk(instr, "shift_uses_vy", self.quirks.shift_uses_vy) {
+                    self.reg[y as usize]
+                } else {
+                    self.reg[x as usize]
+                };
+                self.reg[x as usize] = src >> 1;
+                self.reg[0x0F] = src & 1;
                 self.advance(2)
             }
             SHL(x, y) => {
-                self.reg[0x0F] = self.reg[y as usize] & 0xE0;
-                self.reg[y as usize] = self.reg[x as usize] << 1;
+                let src = if self.quirk(instr, "shift_uses_vy", self.quirks.shift_uses_vy) {
+                    self.reg[y as usize]
+                } else {
+                    self.reg[x as usize]
+                };
+                self.reg[x as usize] = src << 1;
+                self.reg[0x0F] = src >> 7;
                 self.advance(2)
             }
             LOAD(x, n) => {
@@ -364,13 +1462,17 @@ impl Chip8 {
                 self.advance(2)
             }
             ADD(x, n) => {
-                self.reg[x as usize] = self.reg[x as usize].wrapping_add(n);
+                let (val, carry) = self.reg[x as usize].overflowing_add(n);
+                self.check_overflow(instr, carry)?;
+                self.reg[x as usize] = val;
                 self.advance(2)
             }
             // Subroutines
             CALL(addr) => {
                 if addr == self.pc {
                     Ok(StepResult::Loop)
+                } else if self.stack.len() >= self.max_stack_depth {
+                    Err(format!("Stack overflow calling {:#x}", addr))
                 } else {
                     self.stack.push(self.pc);
                     self.pc = addr;
@@ -396,7 +1498,12 @@ impl Chip8 {
                 }
             }
             JUMPI(addr) => {
-                let next_pc = addr + self.reg[0] as u16;
+                let offset_reg = if self.quirk(instr, "jump_with_vx", self.quirks.jump_with_vx) {
+                    (addr >> 8) & 0xF
+                } else {
+                    0
+                };
+                let next_pc = addr + self.reg[offset_reg as usize] as u16;
                 if next_pc == self.pc {
                     Ok(StepResult::Loop)
                 } else {
@@ -407,85 +1514,79 @@ impl Chip8 {
             // Skip
             SKE(x, n) => {
                 if self.reg[x as usize] == n {
-                    self.advance(4)
+                    self.skip()
                 } else {
                     self.advance(2)
                 }
             }
             SKNE(x, n) => {
                 if self.reg[x as usize] != n {
-                    self.advance(4)
+                    self.skip()
                 } else {
                     self.advance(2)
                 }
             }
             SKRE(x, y) => {
-                if self.reg[x as usize] != self.reg[y as usize] {
-                    self.advance(4)
+                if self.reg[x as usize] == self.reg[y as usize] {
+                    self.skip()
                 } else {
                     self.advance(2)
                 }
             }
             SKRNE(x, y) => {
                 if self.reg[x as usize] != self.reg[y as usize] {
-                    self.advance(4)
+                    self.skip()
                 } else {
                     self.advance(2)
                 }
             }
             // Memory
             STOR(x) => {
-                for r in 0..=x {
-                    self.mem[self.idx as usize] = self.reg[r as usize];
-                    self.idx += 1;
+                let range = self.idx_range(x as usize + 1, "STOR write")?;
+                self.mem[range].copy_from_slice(&self.reg[..=x as usize]);
+                let quirk = self.quirks.load_store_increments_i;
+                if self.quirk(instr, "load_store_increments_i", quirk) {
+                    self.idx = self.idx.wrapping_add(x as u16 + 1);
                 }
-
                 self.advance(2)
             }
             READ(x) => {
-                for r in 0..=x {
-                    self.reg[r as usize] = self.mem[self.idx as usize];
-                    self.idx += 1;
+                let range = self.idx_range(x as usize + 1, "READ")?;
+                self.reg[..=x as usize].copy_from_slice(&self.mem[range]);
+                let quirk = self.quirks.load_store_increments_i;
+                if self.quirk(instr, "load_store_increments_i", quirk) {
+                    self.idx = self.idx.wrapping_add(x as u16 + 1);
                 }
-
                 self.advance(2)
             }
             // Input
             SKPR(x) => {
+                self.checked_registers |= 1 << x;
                 let keyidx: usize = self.reg[x as usize] as usize;
-                let pressed = *self
-                    .io
-                    .lock()
-                    .unwrap()
-                    .keystate
-                    .get(keyidx)
-                    .unwrap_or(&false);
+                let pressed = *io.keystate.get(keyidx).unwrap_or(&false);
                 if pressed {
-                    self.advance(4)
+                    self.skip()
                 } else {
                     self.advance(2)
                 }
             }
             SKUP(x) => {
+                self.checked_registers |= 1 << x;
                 let keyidx: usize = self.reg[x as usize] as usize;
-                let pressed = *self
-                    .io
-                    .lock()
-                    .unwrap()
-                    .keystate
-                    .get(keyidx)
-                    .unwrap_or(&false);
+                let pressed = *io.keystate.get(keyidx).unwrap_or(&false);
                 if !pressed {
-                    self.advance(4)
+                    self.skip()
                 } else {
                     self.advance(2)
                 }
             }
             KEYD(x) => {
-                let keystate = self.io.lock().unwrap().keystate;
-                for (key, &pressed) in keystate.iter().enumerate() {
-                    if pressed {
+                for (key, &pressed) in io.keystate.iter().enumerate() {
+                    if pressed && self.keyd_held & (1 << key) == 0 {
                         self.reg[x as usize] = key as u8;
+                        if self.keyd_needs_release {
+                            self.keyd_held |= 1 << key;
+                        }
                         let _ = self.advance(2);
                         break;
                     }
@@ -494,8 +1595,11 @@ impl Chip8 {
             }
 
             // Sound
-            // TODO: Implement sound
-            LOADS(_) => self.advance(2),
+            LOADS(x) => {
+                self.sound = self.reg[x as usize];
+                self.update_tone();
+                self.advance(2)
+            }
 
             // Delays
             MOVED(x) => {
@@ -509,43 +1613,94 @@ impl Chip8 {
 
             // Index register
             ADDI(x) => {
-                self.idx += self.reg[x as usize] as u16;
+                // I wraps around within the 4K address space, or within 64K if LOADILONG
+                // already took it past 4K
+                let limit: u32 = if self.idx > 0x0FFF { 0xFFFF } else { 0x0FFF };
+                let sum = self.idx as u32 + self.reg[x as usize] as u32;
+                self.idx = (sum & limit) as u16;
+                if sum > limit && self.quirk(instr, "addi_sets_vf", self.quirks.addi_sets_vf) {
+                    self.reg[0xF] = 1;
+                }
                 self.advance(2)
             }
             LOADI(addr) => {
                 self.idx = addr;
                 self.advance(2)
             }
+            LOADILONG(addr) => {
+                self.idx = addr;
+                self.advance(4)
+            }
             // Screen
             DRAW(x, y, n) => {
-                let mut row = self.reg[y as usize] as usize;
-                let memidx = self.idx as usize;
-
-                {
-                    // Lock IO here
-                    let display = &mut self.io.lock().unwrap().display;
-                    self.reg[0x0F] = 0;
-                    for byte in &self.mem[memidx..memidx + n as usize] {
-                        let mut col = self.reg[x as usize] as usize;
-                        for bitidx in 0..8 {
-                            let bit = (byte & (1 << (7 - bitidx))) != 0;
-                            if display[row % DISPLAY_ROWS][col % DISPLAY_COLS] & bit {
-                                self.reg[0x0F] = 1;
-                            }
-
-                            display[row % DISPLAY_ROWS][col % DISPLAY_COLS] ^= bit;
-                            col += 1;
-                        }
-
-                        row += 1;
-                    }
+                if self.quirks.display_wait && self.drew_this_frame {
+                    // Try again next step, once the frame is over
+                    return Ok(StepResult::Continue(false));
+                }
+                // In high resolution, n = 0 draws a 16x16 sprite, two bytes per row
+                let row_bytes = if n == 0 && io.display.hires() { 2 } else { 1 };
+                let rows = if row_bytes == 2 { 16 } else { n as usize };
+                // Each selected plane gets its own sprite, one after the other
+                let sprite_len = rows * row_bytes;
+                let planes = self.selected_planes.count_ones() as usize;
+                let sprites = self.idx_range(sprite_len * planes, "DRAW sprite read")?;
+                self.drew_this_frame = true;
+                self.save_display(io);
+
+                let (width, height) = (io.display.width(), io.display.height());
+                let row_start = self.reg[y as usize] as usize % height;
+                let col_start = self.reg[x as usize] as usize % width;
+                let mut collided = false;
+                let sprites = self.mem[sprites].chunks(sprite_len.max(1));
+                for (plane, sprite) in io.planes_mut(self.selected_planes).zip(sprites) {
+                    collided |= self.draw_sprite(plane, sprite, row_bytes, row_start, col_start);
                 }
+                self.reg[0x0F] = collided as u8;
 
                 let _ = self.advance(2);
                 Ok(StepResult::Continue(true))
             }
             CLR => {
-                self.io.lock().unwrap().display = [[false; 64]; 32];
+                self.save_display(io);
+                for plane in io.planes_mut(self.selected_planes) {
+                    plane.clear();
+                }
+                self.advance(2)
+            }
+            SCD(_) | SCR | SCL => {
+                self.save_display(io);
+                let halve = !io.display.hires()
+                    && self.quirk(
+                        instr,
+                        "half_scroll_in_lores",
+                        self.quirks.half_scroll_in_lores,
+                    );
+                let amount = |n: usize| if halve { n / 2 } else { n };
+                for plane in io.planes_mut(self.selected_planes) {
+                    match instr {
+                        SCD(n) => plane.scroll_down(amount(n as usize)),
+                        SCR => plane.scroll_right(amount(4)),
+                        _ => plane.scroll_left(amount(4)),
+                    }
+                }
+                let _ = self.advance(2);
+                Ok(StepResult::Continue(true))
+            }
+            LORES | HIRES => {
+                let quirk = self.quirks.resolution_switch_clears;
+                if self.quirk(instr, "resolution_switch_clears", quirk) {
+                    self.save_display(io);
+                    io.display.clear();
+                    io.second_plane.clear();
+                }
+                io.set_hires(instr == HIRES);
+                self.advance(2)
+            }
+            PLANE(n) => {
+                if n > 3 {
+                    return Err(format!("PLANE {} selects planes past the second", n));
+                }
+                self.selected_planes = n;
                 self.advance(2)
             }
             // Other
@@ -554,7 +1709,8 @@ impl Chip8 {
                 if val > 15 {
                     Err(format!("LDSPR for {} > 15", val))
                 } else {
-                    self.idx = val as u16 * 5;
+                    let base = self.font_base.unwrap_or(DEFAULT_FONT_BASE);
+                    self.idx = base + val as u16 * 5;
                     self.advance(2)
                 }
             }
@@ -563,44 +1719,118 @@ impl Chip8 {
                 let tens = (self.reg[x as usize] % 100) / 10;
                 let ones = self.reg[x as usize] % 10;
 
-                self.mem[self.idx as usize] = hundreds;
-                self.mem[self.idx as usize + 1] = tens;
-                self.mem[self.idx as usize + 2] = ones;
+                let range = self.idx_range(3, "BCD write")?;
+                self.mem[range].copy_from_slice(&[hundreds, tens, ones]);
 
                 self.advance(2)
             }
             RAND(x, n) => {
-                let mut rng = rand::thread_rng();
-                self.reg[x as usize] = rng.gen_range(0..n);
+                self.reg[x as usize] = self.rand_rng.gen::<u8>() & n;
                 self.advance(2)
             }
             SYS(0) => Ok(StepResult::End),
-            SYS(_) => Err("SYS".to_string()),
+            SYS(addr) => match self.sys_policy {
+                SysPolicy::Error => Err(format!("SYS {:#x}", addr)),
+                SysPolicy::Ignore => self.advance(2),
+                SysPolicy::End => Ok(StepResult::End),
+            },
+        }
+    }
+
+    /// Step until `condition` holds, calling `on_step` after each step. Returns how many steps
+    /// that took, or None if the program stopped or `max_steps` ran out first.
+    pub fn run_until(
+        &mut self,
+        condition: impl Fn(&Chip8) -> bool,
+        max_steps: u64,
+        mut on_step: impl FnMut(&Chip8),
+    ) -> Result<Option<u64>, String> {
+        for steps in 0..=max_steps {
+            if condition(self) {
+                return Ok(Some(steps));
+            }
+            if steps == max_steps {
+                break;
+            }
+            match self.step()? {
+                StepResult::Continue(_) | StepResult::Breakpoint => on_step(self),
+                StepResult::Loop | StepResult::End => break,
+            }
         }
+        Ok(None)
     }
 
+    /// A CPU running `code`, on a clock that stands still
     #[cfg(test)]
     fn new_test(code: &[Instruction]) -> Chip8 {
-        let mut instr_ram: Vec<u8> = Vec::new();
-        for instr in code {
-            let [high, low] = u16::from(*instr).to_be_bytes();
-            instr_ram.push(high);
-            instr_ram.push(low);
-        }
-        Self::new(&instr_ram, Arc::new(Mutex::new(Chip8IO::new())), false)
+        Self::builder(&encode(code), Arc::new(Mutex::new(Chip8IO::new())))
+            .clock(Box::new(ManualClock::new()))
+            .build()
+    }
+
+    /// Like `new_test`, with a handle on the CPU's clock
+    #[cfg(test)]
+    fn new_test_clocked(code: &[Instruction]) -> (Chip8, ManualClock) {
+        let clock = ManualClock::new();
+        let mut cpu = Self::new_test(code);
+        cpu.clock = Box::new(clock.clone());
+        cpu.tick = clock.now();
+        (cpu, clock)
     }
 
     #[cfg(test)]
     fn run_to_end(&mut self) {
-        loop {
-            match self.step() {
-                Ok(StepResult::Continue(_)) => {}
-                _ => break,
-            }
-        }
+        while let Ok(StepResult::Continue(_)) = self.step() {}
     }
 }
 
+#[test]
+fn step_batch_stops_mid_batch() {
+    let mut cpu = Chip8::new_test(&[LOAD(0, 1), LOADI(0x300), DRAW(0, 0, 1), LOAD(1, 1)]);
+    let result = cpu.step_batch(3);
+    assert_eq!(
+        result,
+        BatchResult {
+            steps: 3,
+            drew: true,
+            ..BatchResult::default()
+        }
+    );
+
+    // LOAD, then the SYS 0 after the program
+    let result = cpu.step_batch(100);
+    assert_eq!(result.steps, 2);
+    assert_eq!(result.stopped, Some(RunStatus::Ended));
+    assert!(!result.drew);
+
+    let mut cpu = Chip8::new_test(&[LOAD(0, 1), CLR]);
+    cpu.opcode_breakpoints
+        .insert(mem::discriminant(&CLR), BreakAction::Pause);
+    let result = cpu.step_batch(100);
+    assert!(result.breakpoint);
+    assert_eq!((result.steps, cpu.pc), (2, 0x202));
+
+    let mut cpu = Chip8::new_test(&[LOAD(0, 1), RTS]);
+    let result = cpu.step_batch(100);
+    assert_eq!(
+        result.stopped,
+        Some(RunStatus::Halted("Return from empty stack".to_string()))
+    );
+}
+
+#[test]
+fn pause_at_first_draw() {
+    let mut cpu = Chip8::new_test(&[LOAD(0, 1), LOADI(0x300), DRAW(0, 0, 1), LOAD(1, 1), CLR]);
+    assert_eq!(cpu.run_to_first_draw(100), Ok(true));
+    assert!(cpu.paused);
+    assert_eq!(cpu.pc, 0x206);
+    assert_eq!(cpu.reg[1], 0);
+
+    let mut cpu = Chip8::new_test(&[JUMP(0x200)]);
+    assert_eq!(cpu.run_to_first_draw(100), Ok(false));
+    assert!(cpu.paused);
+}
+
 #[test]
 fn load() {
     let mut cpu = Chip8::new_test(&[LOAD(0, 10)]);
@@ -631,6 +1861,29 @@ fn skne_yes() {
     assert_eq!(cpu.pc, 0x204);
 }
 
+#[test]
+fn skre_equal() {
+    let mut cpu = Chip8::new_test(&[SKRE(0, 2), LOAD(1, 42)]);
+    cpu.reg[0] = 10;
+    cpu.reg[2] = 10;
+    cpu.reg[1] = 142;
+    cpu.run_to_end();
+
+    assert_eq!(cpu.reg[1], 142);
+    assert_eq!(cpu.pc, 0x204);
+}
+
+#[test]
+fn skre_not_equal() {
+    let mut cpu = Chip8::new_test(&[SKRE(0, 2), LOAD(1, 42)]);
+    cpu.reg[0] = 10;
+    cpu.reg[2] = 11;
+    cpu.run_to_end();
+
+    assert_eq!(cpu.reg[1], 42);
+    assert_eq!(cpu.pc, 0x204);
+}
+
 #[test]
 fn call_rts() {
     let mut cpu = Chip8::new_test(&[
@@ -651,14 +1904,22 @@ fn call_rts() {
 }
 
 #[test]
-fn rand_limit() {
+fn rand_masks() {
     for _ in 0..100 {
-        let mut cpu = Chip8::new_test(&[RAND(0, 10)]);
+        let mut cpu = Chip8::new_test(&[RAND(0, 0b1010)]);
         cpu.run_to_end();
-        assert!(cpu.reg[0] < 10);
+        assert_eq!(cpu.reg[0] & !0b1010, 0);
     }
 }
 
+#[test]
+fn rand_zero_mask() {
+    let mut cpu = Chip8::new_test(&[RAND(0, 0)]);
+    cpu.reg[0] = 7;
+    cpu.run_to_end();
+    assert_eq!(cpu.reg[0], 0);
+}
+
 #[test]
 fn skup_pressed() {
     let mut cpu = Chip8::new_test(&[SKUP(0), LOAD(1, 42)]);
@@ -681,44 +1942,1597 @@ fn skup_up() {
     assert_eq!(cpu.reg[1], 0);
 }
 
+/// Run a single `DRAW` of `sprite` at (`x`, `y`), on a display where only the `lit` (row, col)
+/// pixels are on. Returns VF and the pixels that changed.
+#[cfg(test)]
+fn draw_once(
+    lit: &[(usize, usize)],
+    sprite: &[u8],
+    (x, y): (u8, u8),
+    quirks: Quirks,
+) -> (u8, Vec<(usize, usize)>) {
+    let mut cpu = Chip8::new_test(&[]);
+    cpu.quirks = quirks;
+    cpu.reg[0] = x;
+    cpu.reg[1] = y;
+    cpu.idx = 0x300;
+    cpu.mem[0x300..0x300 + sprite.len()].copy_from_slice(sprite);
+
+    let mut io = Chip8IO::new();
+    for &(row, col) in lit {
+        io.display.set(row, col, true);
+    }
+    let before = io.display;
+    cpu.execute(DRAW(0, 1, sprite.len() as u8), &mut io)
+        .unwrap();
+
+    let mut changed = Vec::new();
+    for row in 0..DISPLAY_ROWS {
+        for col in 0..DISPLAY_COLS {
+            if before.get(row, col) != io.display.get(row, col) {
+                changed.push((row, col));
+            }
+        }
+    }
+    (cpu.reg[0xF], changed)
+}
+
 #[test]
 fn draw_xor_true_begin() {
-    let mut cpu = Chip8::new_test(&[DRAW(0, 1, 2)]);
-    cpu.reg[0] = 0;
-    cpu.reg[1] = 0;
-    cpu.idx = 0x300;
+    let (vf, _) = draw_once(&[(0, 0)], &[0xFF, 0xFF], (0, 0), Quirks::default());
+    assert_eq!(vf, 1);
+}
+
+#[test]
+fn draw_xor_true_end() {
+    let (vf, _) = draw_once(&[(1, 7)], &[0xFF, 0xFF], (0, 0), Quirks::default());
+    assert_eq!(vf, 1);
+}
+
+#[test]
+fn draw_xor_false() {
+    let (vf, changed) = draw_once(&[], &[0xFF, 0xFF], (0, 0), Quirks::default());
+    assert_eq!(vf, 0);
+    assert_eq!(changed.len(), 16);
+}
+
+#[test]
+fn draw_collisions() {
+    let clip = Quirks {
+        clip_sprites: true,
+        ..Quirks::default()
+    };
+    let ignore_wrapped = Quirks {
+        ignore_wrapped_collisions: true,
+        ..Quirks::default()
+    };
+    // Lit pixels, sprite, position, quirks, then the expected VF and changed pixels
+    type Case = (
+        &'static [(usize, usize)],
+        &'static [u8],
+        (u8, u8),
+        Quirks,
+        u8,
+        &'static [(usize, usize)],
+    );
+    #[rustfmt::skip]
+    let cases: [Case; 7] = [
+        // Lit pixel next to the sprite, not under it
+        (&[(0, 1)], &[0x80], (0, 0), Quirks::default(), 0, &[(0, 0)]),
+        // Under a blank part of the sprite
+        (&[(0, 1)], &[0xA0], (0, 0), Quirks::default(), 0, &[(0, 0), (0, 2)]),
+        // Under a set bit: turned off
+        (&[(5, 10)], &[0x20], (8, 5), Quirks::default(), 1, &[(5, 10)]),
+        // Start position wraps on both axes
+        (&[(2, 3)], &[0x80], (67, 34), Quirks::default(), 1, &[(2, 3)]),
+        // Wrapped off the right edge onto a lit pixel
+        (&[(0, 0)], &[0x01], (57, 0), Quirks::default(), 1, &[(0, 0)]),
+        (&[(0, 0)], &[0x01], (57, 0), ignore_wrapped, 0, &[(0, 0)]),
+        // Clipped instead, so the lit pixel is left alone
+        (&[(0, 0)], &[0x01], (57, 0), clip, 0, &[]),
+    ];
+    for (i, (lit, sprite, pos, quirks, vf, changed)) in cases.into_iter().enumerate() {
+        assert_eq!(
+            draw_once(lit, sprite, pos, quirks),
+            (vf, changed.to_vec()),
+            "case {}",
+            i
+        );
+    }
+}
+
+#[test]
+fn display_shows_timers() {
+    let mut cpu = Chip8::new_test(&[LOADD(0), LOADS(1)]);
+    cpu.reg[0] = 42;
+    cpu.reg[1] = 17;
+    cpu.run_to_end();
+
+    let out = format!("{}", cpu);
+    assert!(out.contains("delay: 42"), "{}", out);
+    assert!(out.contains("sound: 17"), "{}", out);
+}
+
+#[test]
+fn uninit_regs_randomized() {
+    let mut cpu = Chip8::new_test(&[LOAD(1, 42), ADD(1, 1), ADD(2, 0)]);
+    cpu.randomize_uninit_regs(1234);
+    cpu.run_to_end();
+
+    assert_eq!(cpu.reg[1], 43);
+    assert_ne!(cpu.reg[2], 0);
+}
+
+#[test]
+fn load_rom_reinitializes_memory() {
+    let mut cpu = Chip8::new_test(&[LOAD(0, 1), LOAD(1, 2)]);
+    cpu.run_to_end();
+    cpu.mem[0x300] = 0xAB;
+
+    cpu.load_rom(&u16::from(LOAD(2, 3)).to_be_bytes()).unwrap();
+    assert_eq!(cpu.pc, 0x200);
+    assert_eq!(cpu.reg, [0; 16]);
+    assert_eq!(cpu.mem[0x300], 0);
+    assert_eq!(cpu.mem[0x202], 0);
+    assert_eq!(cpu.mem[0..5], [0xF0, 0x90, 0x90, 0x90, 0xF0]);
+
+    cpu.run_to_end();
+    assert_eq!(cpu.reg[2], 3);
+}
+
+#[test]
+fn load_rom_rejects_oversized() {
+    let mut cpu = Chip8::new_test(&[LOAD(0, 1)]);
+    cpu.run_to_end();
+
+    assert!(cpu.load_rom(&vec![0; MAX_ROM_SIZE + 1]).is_err());
+    assert_eq!(cpu.mem[0x200..0x202], u16::from(LOAD(0, 1)).to_be_bytes());
+    assert_eq!(cpu.reg[0], 1);
+
+    cpu.load_rom(&vec![0xAB; MAX_ROM_SIZE]).unwrap();
+    assert_eq!(cpu.mem[MEMORY_SIZE - 1], 0xAB);
+}
+
+#[test]
+fn break_on_draw() {
+    let mut cpu = Chip8::new_test(&[LOAD(0, 1), LOADI(0), DRAW(0, 0, 5), LOAD(1, 2)]);
+    cpu.opcode_breakpoints
+        .insert(mem::discriminant(&DRAW(0, 0, 0)), BreakAction::Pause);
+    cpu.run_to_end();
+
+    assert!(cpu.paused);
+    assert_eq!(cpu.pc, 0x204);
+    assert_eq!(cpu.reg[0], 1);
+    assert!(!cpu.io.lock().unwrap().display.get(1, 1));
+
+    cpu.paused = false;
+    cpu.run_to_end();
+    assert!(cpu.io.lock().unwrap().display.get(1, 1));
+    assert_eq!(cpu.reg[1], 2);
+}
+
+#[cfg(test)]
+fn new_test_bytes(rom: &[u8], policy: DecodeErrorPolicy) -> Chip8 {
+    let mut cpu = Chip8::new(rom, Arc::new(Mutex::new(Chip8IO::new())), false);
+    cpu.decode_error_policy = policy;
+    cpu
+}
+
+#[test]
+fn invalid_instruction_halt() {
+    // LOAD v0, 1; <invalid>; LOAD v1, 2
+    let mut cpu = new_test_bytes(
+        &[0x60, 0x01, 0x50, 0x01, 0x61, 0x02],
+        DecodeErrorPolicy::Halt,
+    );
+    cpu.step().unwrap();
+    assert!(cpu.step().is_err());
+    assert_eq!(cpu.pc, 0x202);
+    assert_eq!(cpu.reg[1], 0);
+}
+
+#[test]
+fn invalid_instruction_skip_word() {
+    let mut cpu = new_test_bytes(
+        &[0x60, 0x01, 0x50, 0x01, 0x61, 0x02],
+        DecodeErrorPolicy::SkipWord,
+    );
+    cpu.run_to_end();
+    assert_eq!(cpu.reg[0], 1);
+    assert_eq!(cpu.reg[1], 2);
+}
+
+#[test]
+fn invalid_instruction_skip_byte() {
+    // LOAD v0, 1; <data byte>; LOAD v1, 2 at an odd address
+    let mut cpu = new_test_bytes(&[0x60, 0x01, 0xFF, 0x61, 0x02], DecodeErrorPolicy::SkipByte);
+    cpu.run_to_end();
+    assert_eq!(cpu.reg[0], 1);
+    assert_eq!(cpu.reg[1], 2);
+    assert_eq!(cpu.pc, 0x205);
+}
+
+#[test]
+fn strict_alignment_rejects_odd_pc() {
+    for strict in [false, true] {
+        // The jump lands in the middle of LOAD v1, 0x60, on 0x6000 (LOAD v0, 0)
+        let mut cpu = Chip8::new_test(&[JUMP(0x203), LOAD(1, 0x60), CLR]);
+        cpu.strict_alignment = strict;
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x203);
+        if strict {
+            assert!(cpu.step() == Err("Instruction fetch from odd address 0x203".to_string()));
+        } else {
+            assert!(cpu.step().is_ok());
+            assert_eq!(cpu.pc, 0x205);
+        }
+    }
+}
+
+#[test]
+fn trap_overflow_stops_wrapping() {
+    for instr in [ADD(0, 1), ADDR(0, 1), SUBN(1, 2), SUB(2, 0)] {
+        // v0 = 0xFF, v1 = 1, v2 = 0
+        let mut cpu = Chip8::new_test(&[LOAD(0, 0xFF), LOAD(1, 1), instr]);
+        cpu.trap_overflow = true;
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert!(cpu.step().is_err(), "{} didn't trap", instr);
+        // Nothing was written
+        assert_eq!(cpu.reg[..3], [0xFF, 1, 0]);
+        assert_eq!(cpu.pc, 0x204);
+    }
+
+    let mut cpu = Chip8::new_test(&[LOAD(0, 0xFF), ADD(0, 1)]);
+    cpu.trap_overflow = true;
+    cpu.step().unwrap();
+    assert!(cpu.step() == Err("Arithmetic overflow in ADD   v0, 0x1 at 0x202".to_string()));
+
+    // Off by default
+    let mut cpu = Chip8::new_test(&[LOAD(0, 0xFF), ADD(0, 1)]);
+    cpu.run_to_end();
+    assert_eq!(cpu.status(), RunStatus::Ended);
+    assert_eq!(cpu.reg[0], 0);
+
+    // In range is fine
+    let mut cpu = Chip8::new_test(&[LOAD(0, 0xFE), ADD(0, 1), LOAD(1, 0xFF), SUB(0, 1)]);
+    cpu.trap_overflow = true;
+    cpu.run_to_end();
+    assert_eq!(cpu.status(), RunStatus::Ended);
+    assert_eq!(cpu.reg[0], 0);
+}
+
+#[test]
+fn call_stack_overflow() {
+    let mut cpu = Chip8::new_test(&[CALL(0x202), CALL(0x200)]);
+    cpu.max_stack_depth = 4;
+    for _ in 0..4 {
+        assert!(cpu.step().is_ok());
+    }
+    assert_eq!(cpu.stack.len(), 4);
+    assert!(cpu.step().is_err());
+}
+
+#[test]
+fn ldspr_vip_font_base() {
+    let rom = [0x60, 0x02, 0xF0, 0x29]; // LOAD v0, 2; LDSPR v0
+    let mut cpu = Chip8::builder(&rom, Arc::new(Mutex::new(Chip8IO::new())))
+        .with_font(Some(VIP_FONT_BASE))
+        .build();
+    cpu.run_to_end();
+
+    assert_eq!(cpu.idx, 0x050 + 10);
+    assert_eq!(cpu.mem[0x050 + 10..0x050 + 15], FONT[10..15]);
+    assert_eq!(cpu.mem[0..5], [0; 5]);
+}
+
+#[test]
+#[should_panic(expected = "Font at 0x1c0 doesn't fit below 0x200")]
+fn font_overlapping_rom_rejected() {
+    Chip8::builder(&[], Arc::new(Mutex::new(Chip8IO::new()))).with_font(Some(0x1c0));
+}
+
+#[test]
+fn disasm_range_decodes() {
+    let mut cpu = Chip8::new_test(&[LOAD(0, 1), CLR, JUMP(0x200)]);
+    cpu.mem[0x208..0x20A].copy_from_slice(&[0x5A, 0xB1]);
+    let decoded: Vec<_> = cpu.disasm_range(0x202, 0x20A).collect();
+    assert_eq!(
+        decoded,
+        vec![
+            (0x202, Ok(CLR)),
+            (0x204, Ok(JUMP(0x200))),
+            (0x206, Ok(SYS(0))),
+            (0x208, Err(Chip8Error::InvalidOpcode(0x5AB1))),
+        ]
+    );
+
+    // LOADILONG's address would be past the end of the range
+    cpu.mem[0x300..0x302].copy_from_slice(&[0xF0, 0x00]);
+    assert_eq!(
+        cpu.disasm_range(0x300, 0x302).next(),
+        Some((0x300, Err(Chip8Error::MissingAddress)))
+    );
+}
+
+#[test]
+fn known_rom_gets_profile() {
+    let blinky = include_bytes!("../GAMES/BLINKY.ch8");
+    let cpu = Chip8::new(blinky, Arc::new(Mutex::new(Chip8IO::new())), true);
+    assert_eq!(cpu.quirks, Profile::SuperChip.quirks());
+
+    let cpu = Chip8::new_test(&[CLR]);
+    assert_eq!(cpu.quirks, Quirks::default());
+}
+
+#[test]
+fn draw_clip_sprites() {
+    // Full-width row drawn 4 pixels from the right edge
+    let mut cpu = Chip8::new_test(&[LOAD(0, 60), LOADI(0x300), DRAW(0, 1, 1)]);
     cpu.mem[0x300] = 0xFF;
-    cpu.mem[0x301] = 0xFF;
-    cpu.io.lock().unwrap().display[0][0] = true;
+    cpu.quirks.clip_sprites = true;
+    cpu.run_to_end();
+
+    let io = cpu.io.lock().unwrap();
+    assert!((60..64).all(|col| io.display.get(0, col)));
+    assert!(!(0..4).any(|col| io.display.get(0, col)));
+}
+
+#[test]
+fn jumpi_with_vx() {
+    let mut cpu = Chip8::new_test(&[LOAD(2, 4), JUMPI(0x202)]);
+    cpu.quirks.jump_with_vx = true;
+    cpu.step().unwrap();
+    cpu.step().unwrap();
+    assert_eq!(cpu.pc, 0x206);
+}
+
+#[cfg(test)]
+fn clipped_draw_cpu(fast: bool, x: u8, y: u8) -> Chip8 {
+    let mut cpu = Chip8::new_test(&[LOAD(0, x), LOAD(1, y), LOADI(0x300), DRAW(0, 1, 15)]);
+    for (i, byte) in cpu.mem[0x300..0x30F].iter_mut().enumerate() {
+        *byte = 0xA5 ^ (i as u8 * 17);
+    }
+    cpu.quirks.clip_sprites = true;
+    cpu.draw_fast_path = fast;
+    cpu
+}
+
+#[test]
+fn draw_fast_path_matches_naive() {
+    for (x, y) in [
+        (0, 0),
+        (30, 10),
+        (60, 5),
+        (10, 25),
+        (63, 31),
+        (100, 200),
+        (56, 17),
+    ] {
+        let mut fast = clipped_draw_cpu(true, x, y);
+        let mut naive = clipped_draw_cpu(false, x, y);
+        // Draw twice so that VF reports the collision with the first draw
+        for _ in 0..2 {
+            fast.pc = 0x200;
+            naive.pc = 0x200;
+            fast.run_to_end();
+            naive.run_to_end();
+            assert_eq!(fast.reg[0xF], naive.reg[0xF], "VF at ({}, {})", x, y);
+        }
+        assert_eq!(
+            fast.io.lock().unwrap().display,
+            naive.io.lock().unwrap().display,
+            "display at ({}, {})",
+            x,
+            y
+        );
+    }
+}
+
+/// Not a correctness test. Run with `cargo test --release -- --ignored --nocapture bench_`
+#[test]
+#[ignore]
+fn bench_draw() {
+    for (x, y) in [(20, 10), (62, 30)] {
+        for fast in [false, true] {
+            let mut cpu = clipped_draw_cpu(fast, x, y);
+            let start = time::Instant::now();
+            for _ in 0..1_000_000 {
+                cpu.pc = 0x206;
+                cpu.step().unwrap();
+            }
+            println!("({}, {}) fast path {}: {:?}", x, y, fast, start.elapsed());
+        }
+    }
+}
+
+/// Copying the display out of the lock against rendering it through the guard. Run like
+/// `bench_draw`
+#[test]
+#[ignore]
+fn bench_display_copy() {
+    let row = format!("{}\n", "#.".repeat(DISPLAY_COLS / 2));
+    let io = Mutex::new(Chip8IO::from_text(&row.repeat(DISPLAY_ROWS)).unwrap());
+    let render = |display: &Framebuffer| display.rows().flatten().filter(|&p| p).count();
+
+    let start = time::Instant::now();
+    let mut lit = 0;
+    for _ in 0..100_000 {
+        let display = io.lock().unwrap().display;
+        lit += render(&display);
+    }
+    println!("copy: {:?} ({})", start.elapsed(), lit);
+
+    let start = time::Instant::now();
+    let mut lit = 0;
+    for _ in 0..100_000 {
+        lit += render(io.lock().unwrap().planes().0);
+    }
+    println!("borrow: {:?} ({})", start.elapsed(), lit);
+}
+
+#[cfg(test)]
+#[derive(Debug)]
+struct MockSink(Arc<Mutex<Vec<bool>>>);
+
+#[cfg(test)]
+impl AudioSink for MockSink {
+    fn set_tone(&mut self, active: bool, _beep: Beep) {
+        self.0.lock().unwrap().push(active);
+    }
+}
+
+#[test]
+fn sound_timer_drives_sink() {
+    let (mut cpu, clock) = Chip8::new_test_clocked(&[LOAD(0, 2), LOADS(0), JUMP(0x204)]);
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    cpu.audio = Some(Box::new(MockSink(calls.clone())));
+
+    cpu.step().unwrap();
+    cpu.step().unwrap();
+    assert_eq!(*calls.lock().unwrap(), vec![true]);
+
+    // Two timer ticks bring the sound timer to zero
+    for _ in 0..2 {
+        clock.advance(time::Duration::from_millis(17));
+        cpu.step().unwrap();
+    }
+    assert_eq!(cpu.sound, 0);
+    assert_eq!(*calls.lock().unwrap(), vec![true, false]);
+}
+
+#[test]
+fn beep_stops_with_program() {
+    for end in [SYS(0), SYS(0x123)] {
+        let mut cpu = Chip8::new_test(&[LOAD(0, 60), LOADS(0), end]);
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        cpu.audio = Some(Box::new(MockSink(calls.clone())));
+        cpu.run_to_end();
+        assert_eq!(cpu.sound, 60);
+        assert_eq!(*calls.lock().unwrap(), vec![true, false]);
+
+        cpu.reset();
+        assert_eq!(*calls.lock().unwrap(), vec![true, false]);
+    }
+}
+
+#[test]
+fn display_text_round_trip() {
+    let mut io = Chip8IO::new();
+    io.display.set(0, 0, true);
+    io.display.set(5, 63, true);
+    io.display.set(31, 17, true);
+    let text = io.to_text();
+    assert_eq!(text.lines().count(), DISPLAY_ROWS);
+    assert_eq!(Chip8IO::from_text(&text).unwrap().display, io.display);
+
+    assert!(Chip8IO::from_text("#.").is_err());
+    assert!(Chip8IO::from_text(&text.replace('#', "x")).is_err());
+}
+
+#[test]
+fn display_golden_digit() {
+    // Draw the font's 8 at (2, 1)
+    let mut cpu = Chip8::new_test(&[LOAD(0, 8), LDSPR(0), LOAD(1, 2), LOAD(2, 1), DRAW(1, 2, 5)]);
+    cpu.run_to_end();
+
+    let sprite = ["", "..####", "..#..#", "..####", "..#..#", "..####"];
+    let mut expected = String::new();
+    for row in 0..DISPLAY_ROWS {
+        let start = sprite.get(row).unwrap_or(&"");
+        expected.push_str(&format!("{:.<64}\n", start));
+    }
+    assert_eq!(cpu.io.lock().unwrap().to_text(), expected);
+}
+
+#[test]
+fn concurrent_steps_and_reads() {
+    use std::thread;
+
+    // Draws, clears and polls keys in a loop, so every step touches the IO
+    let cpu = Chip8::new_test(&[LOADI(0x300), DRAW(0, 0, 4), SKPR(0), CLR, JUMP(0x202)]);
+    let io = cpu.io.clone();
+    let cpu = Arc::new(Mutex::new(cpu));
+
+    let runner = {
+        let cpu = cpu.clone();
+        thread::spawn(move || {
+            for _ in 0..20_000 {
+                cpu.lock().unwrap().step().unwrap();
+            }
+        })
+    };
+    // What the GUI does each frame: lock the IO alone, and the CPU then the IO
+    for _ in 0..20_000 {
+        io.lock().unwrap().keystate[0] ^= true;
+        let cpu = cpu.lock().unwrap();
+        let _ = cpu.io.lock().unwrap().display.get(0, 0);
+    }
+    runner.join().unwrap();
+}
+
+#[test]
+fn log_breakpoint_in_loop() {
+    let path = std::env::temp_dir().join(format!("chip8-breakpoint-log-{}", std::process::id()));
+    // Count v0 up to 3, logging every ADD
+    let mut cpu = Chip8::new_test(&[LOAD(0, 0), ADD(0, 1), SKE(0, 3), JUMP(0x202)]);
+    cpu.opcode_breakpoints
+        .insert(mem::discriminant(&ADD(0, 0)), BreakAction::Log);
+    cpu.breakpoint_log = Some(fs::File::create(&path).unwrap());
+    cpu.run_to_end();
+    cpu.breakpoint_log = None;
+
+    let log = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 3, "{}", log);
+    assert!(lines.iter().all(|l| l.contains("pc: 0x202")), "{}", log);
+    assert!(lines[2].contains("reg: [2, 0"), "{}", log);
+    assert!(!cpu.paused);
+}
+
+/// Run `rom` for up to `steps` instructions (stopping early if it ends or loops forever), then
+/// check the SHA-1 of the display's bitmap
+#[cfg(test)]
+fn run_and_assert_display_hash(rom: &[u8], steps: usize, expected_hash: &str) {
+    let mut cpu = Chip8::new(rom, Arc::new(Mutex::new(Chip8IO::new())), false);
+    for _ in 0..steps {
+        match cpu.step() {
+            Ok(StepResult::Continue(_)) => {}
+            Ok(_) => break,
+            Err(e) => panic!("CPU error at {:#x}: {}", cpu.pc, e),
+        }
+    }
+    let io = cpu.io.lock().unwrap();
+    assert_eq!(
+        rom::hash(&io.as_bitmap()),
+        expected_hash,
+        "display:\n{}",
+        io.to_text()
+    );
+}
+
+#[test]
+fn display_bitmap_packing() {
+    let mut io = Chip8IO::new();
+    io.display.set(0, 0, true);
+    io.display.set(0, 9, true);
+    io.display.set(31, 63, true);
+    let bitmap = io.as_bitmap();
+    assert_eq!(bitmap.len(), DISPLAY_ROWS * DISPLAY_COLS / 8);
+    assert_eq!(bitmap[0..2], [0b1000_0000, 0b0100_0000]);
+    assert_eq!(bitmap[255], 1);
+
+    let mut copy = Chip8IO::new();
+    copy.set_display_from_bitmap(&bitmap).unwrap();
+    assert_eq!(copy.display, io.display);
+    assert_eq!(copy.as_bitmap(), bitmap);
+
+    assert!(copy.set_display_from_bitmap(&bitmap[1..]).is_err());
+
+    // The length has to match the resolution we're already in
+    let mut hires = [0; HIRES_ROWS * HIRES_COLS / 8];
+    hires[15] = 1;
+    assert!(copy.set_display_from_bitmap(&hires).is_err());
+    assert!(!copy.display.hires());
+    copy.set_hires(true);
+    copy.set_display_from_bitmap(&hires).unwrap();
+    assert!(copy.display.get(0, 127));
+    assert_eq!(copy.as_bitmap(), hires);
+}
+
+#[test]
+fn ibm_logo_display_hash() {
+    run_and_assert_display_hash(
+        include_bytes!("../GAMES/TEST/IBM.ch8"),
+        1000,
+        "075988f15b129f140e8fa743c10fbf6608a9ecc5",
+    );
+}
+
+#[test]
+fn builder_without_font() {
+    let cpu = Chip8::builder(&[0x00, 0xE0], Arc::new(Mutex::new(Chip8IO::new())))
+        .with_font(None)
+        .build();
+    assert!(cpu.mem[..0x200].iter().all(|&b| b == 0));
+    assert_eq!(cpu.mem[0x200..0x202], [0x00, 0xE0]);
+    assert!(!cpu.paused);
+}
+
+#[test]
+fn draw_clip_bottom_rows() {
+    for (vy, clip) in [(30, true), (62, true), (30, false)] {
+        let mut cpu = Chip8::new_test(&[LOAD(1, vy), LOADI(0x300), DRAW(0, 1, 4)]);
+        cpu.mem[0x300..0x304].fill(0xFF);
+        cpu.quirks.clip_sprites = clip;
+        cpu.run_to_end();
+
+        let io = cpu.io.lock().unwrap();
+        // The start row always wraps, so 62 starts at 30 too
+        assert!(io.display.get(30, 0) && io.display.get(31, 0), "Vy={}", vy);
+        assert_eq!(io.display.get(0, 0), !clip, "Vy={} clip={}", vy, clip);
+        assert_eq!(io.display.get(1, 0), !clip, "Vy={} clip={}", vy, clip);
+    }
+}
+
+#[test]
+fn run_until_register_value() {
+    // Count v0 up forever
+    let mut cpu = Chip8::new_test(&[ADD(0, 1), JUMP(0x200)]);
+    let mut traced = 0;
+    let steps = cpu
+        .run_until(|cpu| cpu.reg[0] == 5, 1000, |_| traced += 1)
+        .unwrap();
+    assert_eq!(steps, Some(9));
+    assert_eq!(traced, 9);
+    assert_eq!(cpu.pc, 0x202);
+
+    let mut cpu = Chip8::new_test(&[ADD(0, 1)]);
+    assert_eq!(cpu.run_until(|cpu| cpu.reg[0] == 5, 1000, |_| ()), Ok(None));
+}
+
+#[test]
+fn sys_policies() {
+    let run = |policy| {
+        let mut cpu = Chip8::new_test(&[SYS(0x123), LOAD(0, 1)]);
+        cpu.sys_policy = policy;
+        (cpu.step(), cpu)
+    };
+
+    let (result, cpu) = run(SysPolicy::Error);
+    assert!(result.is_err());
+    assert_eq!(cpu.pc, 0x200);
+
+    let (result, mut cpu) = run(SysPolicy::Ignore);
+    assert!(result == Ok(StepResult::Continue(false)));
+    cpu.run_to_end();
+    assert_eq!(cpu.reg[0], 1);
+
+    let (result, cpu) = run(SysPolicy::End);
+    assert!(result == Ok(StepResult::End));
+    assert_eq!(cpu.reg[0], 0);
+}
+
+#[test]
+fn addi_overflow_quirk() {
+    for quirk in [false, true] {
+        for (start, overflows) in [(0xFFA, false), (0xFFB, true)] {
+            let mut cpu = Chip8::new_test(&[LOAD(0xF, 7), LOAD(1, 5), LOADI(start), ADDI(1)]);
+            cpu.quirks.addi_sets_vf = quirk;
+            cpu.run_to_end();
+            assert_eq!(cpu.idx, (start + 5) & 0x0FFF);
+            assert_eq!(cpu.reg[0xF], if quirk && overflows { 1 } else { 7 });
+        }
+    }
+}
+
+#[test]
+fn addi_wraps_at_end_of_memory() {
+    let mut cpu = Chip8::new_test(&[LOAD(1, 0xFF), LOADI(0xFFF), ADDI(1), STOR(0)]);
+    cpu.run_to_end();
+    assert_eq!(cpu.status(), RunStatus::Ended);
+    assert_eq!(cpu.idx, 0x0FE);
+    assert_eq!(cpu.reg[0xF], 0);
+
+    let mut cpu = Chip8::new_test(&[LOAD(1, 1), LOADI(0xFFE), ADDI(1)]);
     cpu.run_to_end();
+    assert_eq!(cpu.idx, 0xFFF);
+}
+
+#[test]
+fn shr_flag_from_low_bit() {
+    for (value, flag) in [(0b101, 1), (0b100, 0)] {
+        let mut cpu = Chip8::new_test(&[LOAD(2, value), SHR(2, 2)]);
+        cpu.run_to_end();
+        assert_eq!(cpu.reg[2], value >> 1);
+        assert_eq!(cpu.reg[0xF], flag);
+    }
+}
 
+#[test]
+fn shifts_write_vx() {
+    for shift_uses_vy in [false, true] {
+        let mut cpu = Chip8::new_test(&[LOAD(1, 0x81), LOAD(2, 0x81), SHL(1, 2), SHR(2, 1)]);
+        cpu.quirks.shift_uses_vy = shift_uses_vy;
+        cpu.run_to_end();
+        assert_eq!(cpu.reg[1], 0x02);
+        // Vy only changes when it is also Vx
+        assert_eq!(cpu.reg[2], if shift_uses_vy { 0x01 } else { 0x40 });
+        assert_eq!(cpu.reg[0xF], if shift_uses_vy { 0 } else { 1 });
+    }
+}
+
+#[test]
+fn shift_flag_wins_over_vf_result() {
+    let mut cpu = Chip8::new_test(&[LOAD(0xF, 0x80), SHL(0xF, 0xF)]);
+    cpu.run_to_end();
     assert_eq!(cpu.reg[0xF], 1);
+
+    let mut cpu = Chip8::new_test(&[LOAD(0xF, 0x02), SHR(0xF, 0xF)]);
+    cpu.run_to_end();
+    assert_eq!(cpu.reg[0xF], 0);
 }
 
 #[test]
-fn draw_xor_true_end() {
-    let mut cpu = Chip8::new_test(&[DRAW(0, 1, 2)]);
-    cpu.reg[0] = 0;
-    cpu.reg[1] = 0;
-    cpu.idx = 0x300;
+fn load_store_increment_quirk() {
+    for increments in [false, true] {
+        let mut cpu = Chip8::new_test(&[
+            LOAD(0, 1),
+            LOAD(1, 2),
+            LOAD(2, 3),
+            LOAD(3, 4),
+            LOADI(0x300),
+            STOR(3),
+        ]);
+        cpu.quirks.load_store_increments_i = increments;
+        cpu.run_to_end();
+        assert_eq!(cpu.mem[0x300..0x305], [1, 2, 3, 4, 0]);
+        assert_eq!(cpu.idx, if increments { 0x304 } else { 0x300 });
+    }
+}
+
+#[test]
+fn stor_read_keep_idx_by_default() {
+    let mut cpu = Chip8::new_test(&[
+        LOAD(0, 1),
+        LOAD(1, 2),
+        LOAD(2, 3),
+        LOAD(3, 4),
+        LOADI(0x300),
+        STOR(3),
+        LOAD(0, 0),
+        READ(2),
+    ]);
+    cpu.run_to_end();
+    assert_eq!(cpu.mem[0x300..0x304], [1, 2, 3, 4]);
+    assert_eq!(cpu.reg[0..4], [1, 2, 3, 4]);
+    assert_eq!(cpu.idx, 0x300);
+}
+
+#[test]
+fn memory_access_past_end_is_an_error() {
+    let mut cpu = Chip8::new_test(&[LOADILONG(0xFFFE), STOR(2)]);
+    cpu.step().unwrap();
+    assert!(cpu.step() == Err("STOR write past end of memory at 0xfffe".to_string()));
+
+    // Up to the last byte is fine
+    let mut cpu = Chip8::new_test(&[LOADILONG(0xFFFE), STOR(1), READ(1)]);
+    cpu.run_to_end();
+    assert_eq!(cpu.status(), RunStatus::Ended);
+
+    for instr in [READ(2), BCD(0), DRAW(0, 0, 15)] {
+        let mut cpu = Chip8::new_test(&[LOADILONG(0xFFFE), instr]);
+        cpu.step().unwrap();
+        assert!(cpu.step().is_err(), "{}", instr);
+    }
+}
+
+#[test]
+fn logic_vf_reset() {
+    for vf_reset in [false, true] {
+        let mut cpu = Chip8::new_test(&[LOAD(0xF, 7), OR(0, 1)]);
+        cpu.quirks.vf_reset = vf_reset;
+        cpu.run_to_end();
+        assert_eq!(cpu.reg[0xF], if vf_reset { 0 } else { 7 });
+    }
+}
+
+#[test]
+fn display_wait_one_draw_per_frame() {
+    let (mut cpu, clock) = Chip8::new_test_clocked(&[DRAW(0, 0, 1), DRAW(0, 0, 1)]);
+    cpu.quirks.display_wait = true;
+    cpu.step().unwrap();
+    cpu.step().unwrap();
+    assert_eq!(cpu.pc, 0x202);
+
+    clock.advance(time::Duration::from_millis(17));
+    cpu.step().unwrap();
+    assert_eq!(cpu.pc, 0x204);
+}
+
+#[test]
+fn display_wait_with_timers_paused() {
+    let (mut cpu, clock) =
+        Chip8::new_test_clocked(&[LOAD(0, 5), LOADD(0), DRAW(0, 0, 1), DRAW(0, 0, 1)]);
+    cpu.quirks.display_wait = true;
+    for _ in 0..4 {
+        cpu.step().unwrap();
+    }
+    cpu.timers_paused = true;
+    assert_eq!(cpu.pc, 0x206);
+
+    // Frames still end, so the next sprite gets drawn, but the timers stay put
+    clock.advance(time::Duration::from_millis(17));
+    cpu.step().unwrap();
+    assert_eq!(cpu.pc, 0x208);
+    assert_eq!(cpu.delay, 5);
+    assert_eq!(cpu.io.lock().unwrap().frames, 1);
+}
+
+#[test]
+fn history_keeps_latest() {
+    let code: Vec<Instruction> = (0..DEFAULT_HISTORY_LEN as u8 + 10)
+        .map(|n| LOAD(0, n))
+        .collect();
+    let mut cpu = Chip8::new_test(&code);
+    for _ in &code {
+        cpu.step().unwrap();
+    }
+
+    assert_eq!(cpu.history.len(), DEFAULT_HISTORY_LEN);
+    for (i, &(addr, instr)) in cpu.history.iter().enumerate() {
+        let n = i + 10;
+        assert_eq!(addr, 0x200 + 2 * n as u16);
+        assert_eq!(instr, LOAD(0, n as u8));
+    }
+}
+
+#[test]
+fn history_capacity_evicts_oldest() {
+    let code: Vec<Instruction> = (0..20).map(|n| LOAD(0, n)).collect();
+    let rom = encode(&code);
+    let mut cpu = Chip8::builder(&rom, Arc::new(Mutex::new(Chip8IO::new())))
+        .history_len(5)
+        .build();
+    for n in 0..code.len() {
+        cpu.step().unwrap();
+        assert!(cpu.history.len() <= 5);
+        assert_eq!(
+            cpu.history.back(),
+            Some(&(0x200 + 2 * n as u16, LOAD(0, n as u8)))
+        );
+    }
+    let kept: Vec<_> = cpu.history.iter().map(|&(_, instr)| instr).collect();
+    assert_eq!(
+        kept,
+        [
+            LOAD(0, 15),
+            LOAD(0, 16),
+            LOAD(0, 17),
+            LOAD(0, 18),
+            LOAD(0, 19)
+        ]
+    );
+
+    cpu.set_history_len(2);
+    let kept: Vec<_> = cpu.history.iter().map(|&(_, instr)| instr).collect();
+    assert_eq!(kept, [LOAD(0, 18), LOAD(0, 19)]);
+
+    cpu.set_history_len(usize::MAX);
+    assert_eq!(cpu.history_len(), MAX_HISTORY_LEN);
+}
+
+#[test]
+fn previous_frame_restores_display_only() {
+    let mut cpu = Chip8::new_test(&[
+        LOADI(0x300),
+        DRAW(0, 0, 1),
+        LOAD(0, 8),
+        DRAW(0, 0, 1),
+        LOAD(1, 5),
+        PLANE(2),
+        DRAW(0, 0, 1),
+    ]);
     cpu.mem[0x300] = 0xFF;
-    cpu.mem[0x301] = 0xFF;
-    cpu.io.lock().unwrap().display[1][7] = true;
     cpu.run_to_end();
+    let mut first = Framebuffer::new();
+    first.xor_byte(0, 0, 0xFF, false);
+    let mut both = first;
+    both.xor_byte(8, 8, 0xFF, false);
+
+    // The last DRAW was only on the second plane
+    assert!(cpu.previous_frame());
+    assert_eq!(cpu.io.lock().unwrap().display, both);
+    assert_eq!(cpu.io.lock().unwrap().second_plane, Framebuffer::new());
+    assert_eq!((cpu.reg[0], cpu.reg[1], cpu.pc), (8, 5, 0x20E));
+
+    assert!(cpu.previous_frame());
+    assert_eq!(cpu.io.lock().unwrap().display, first);
+
+    assert!(cpu.previous_frame());
+    assert_eq!(cpu.io.lock().unwrap().display, Framebuffer::new());
+    assert!(!cpu.previous_frame());
+}
 
-    assert_eq!(cpu.reg[0xF], 1);
+#[test]
+fn extra_registers() {
+    let rom = encode(&[LOAD(0xF, 1), LOAD(0, 2), ADDR(0, 0xF)]);
+    let mut cpu = Chip8::builder(&rom, Arc::new(Mutex::new(Chip8IO::new())))
+        .registers(24)
+        .build();
+    assert_eq!(cpu.reg, [0; 24]);
+
+    cpu.run_to_end();
+    assert_eq!(
+        cpu.reg[..16],
+        [3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+    );
+    assert_eq!(cpu.reg[16..], [0; 8]);
+
+    cpu.reg[20] = 7;
+    cpu.reset();
+    assert_eq!(cpu.reg, [0; 24]);
+
+    let cpu = Chip8::builder(&rom, Arc::new(Mutex::new(Chip8IO::new())))
+        .registers(4)
+        .build();
+    assert_eq!(cpu.reg.len(), DEFAULT_REGISTERS);
 }
 
 #[test]
-fn draw_xor_false() {
-    let mut cpu = Chip8::new_test(&[DRAW(0, 1, 2)]);
-    cpu.reg[0] = 0;
+fn keyd_waits_for_release() {
+    for needs_release in [false, true] {
+        let mut cpu = Chip8::new_test(&[KEYD(0), KEYD(1)]);
+        cpu.keyd_needs_release = needs_release;
+        cpu.io.lock().unwrap().keystate[5] = true;
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        if !needs_release {
+            // Still held, so the second KEYD takes it straight away
+            assert_eq!((cpu.pc, cpu.reg[1]), (0x204, 5));
+            continue;
+        }
+        assert_eq!(cpu.pc, 0x202);
+
+        cpu.io.lock().unwrap().keystate[5] = false;
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x202);
+        cpu.io.lock().unwrap().keystate[5] = true;
+        cpu.step().unwrap();
+        assert_eq!((cpu.pc, cpu.reg[1]), (0x204, 5));
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug)]
+struct MockTrace(Arc<Mutex<Vec<(u16, Instruction)>>>);
+
+#[cfg(test)]
+impl TraceSink for MockTrace {
+    fn traced(&mut self, pc: u16, instruction: Instruction, _state: String) {
+        self.0.lock().unwrap().push((pc, instruction));
+    }
+}
+
+#[test]
+fn trace_filter_selects_draws() {
+    let mut cpu = Chip8::new_test(&[LOADI(0x300), DRAW(0, 0, 1), LOAD(0, 8), CLR, DRAW(0, 1, 1)]);
+    let traced = Arc::new(Mutex::new(Vec::new()));
+    cpu.trace = Some(Box::new(MockTrace(traced.clone())));
+    cpu.trace_filters = vec!["DRAW".parse().unwrap()];
+    cpu.run_to_end();
+    assert_eq!(
+        *traced.lock().unwrap(),
+        [(0x202, DRAW(0, 0, 1)), (0x208, DRAW(0, 1, 1))]
+    );
+
+    // No filters traces everything
+    traced.lock().unwrap().clear();
+    cpu.trace_filters.clear();
+    cpu.reset();
+    cpu.run_to_end();
+    assert_eq!(traced.lock().unwrap().len(), 6);
+}
+
+#[test]
+fn status_through_self_loop() {
+    let mut cpu = Chip8::new(
+        &[0x60, 0x01, 0x12, 0x02],
+        Arc::new(Mutex::new(Chip8IO::new())),
+        true,
+    );
+    assert_eq!(cpu.status(), RunStatus::Paused);
+
+    cpu.set_paused(false);
+    assert_eq!(cpu.status(), RunStatus::Running);
+    cpu.step().unwrap();
+    assert_eq!(cpu.status(), RunStatus::Running);
+    assert!(cpu.step() == Ok(StepResult::Loop));
+    assert_eq!(cpu.status(), RunStatus::Looping);
+
+    cpu.reset();
+    assert_eq!(cpu.status(), RunStatus::Running);
+
+    let mut cpu = Chip8::new_test(&[LOAD(0, 1)]);
+    cpu.run_to_end();
+    assert_eq!(cpu.status(), RunStatus::Ended);
+
+    let mut cpu = Chip8::new_test(&[RTS]);
+    assert!(cpu.step().is_err());
+    assert_eq!(
+        cpu.status(),
+        RunStatus::Halted("Return from empty stack".to_string())
+    );
+}
+
+#[test]
+fn hires_draws_16x16_sprites() {
+    let mut cpu = Chip8::new_test(&[
+        HIRES,
+        LOAD(0, 120),
+        LOAD(1, 60),
+        LOADI(0x300),
+        DRAW(0, 1, 0),
+        LORES,
+        DRAW(0, 1, 0),
+    ]);
+    // A 16x16 box outline
+    cpu.mem[0x300..0x302].copy_from_slice(&[0xFF, 0xFF]);
+    for row in 1..15 {
+        cpu.mem[0x300 + 2 * row..0x302 + 2 * row].copy_from_slice(&[0x80, 0x01]);
+    }
+    cpu.mem[0x31E..0x320].copy_from_slice(&[0xFF, 0xFF]);
+    cpu.run_to_end();
+
+    let io = cpu.io.lock().unwrap();
+    assert!(!io.display.hires());
+    let mut hires = io.display;
+    hires.set_hires(true);
+    // Wrapped around both edges, since it starts 8 pixels from the right and 4 from the bottom
+    assert!((120..128).chain(0..8).all(|col| hires.get(60, col)));
+    assert!(hires.get(61, 120) && hires.get(61, 7) && !hires.get(61, 121));
+    assert!((120..128).chain(0..8).all(|col| hires.get(11, col)));
+    assert!(!hires.get(12, 120));
+    // In low resolution, n = 0 draws nothing
+    assert_eq!(cpu.reg[0xF], 0);
+}
+
+#[test]
+fn planes_draw_and_clear_separately() {
+    let mut cpu = Chip8::new_test(&[
+        PLANE(2),
+        LOADI(0x300),
+        DRAW(0, 0, 1),
+        PLANE(3),
+        LOAD(0, 8),
+        DRAW(0, 1, 1),
+        PLANE(1),
+        CLR,
+    ]);
+    cpu.mem[0x300..0x302].copy_from_slice(&[0xFF, 0x0F]);
+    for _ in 0..3 {
+        cpu.step().unwrap();
+    }
+    {
+        let io = cpu.io.lock().unwrap();
+        assert!((0..8).all(|col| io.second_plane.get(0, col)));
+        assert_eq!(io.display, Framebuffer::new(), "plane 0 was drawn to");
+    }
+
+    // With both selected, the second plane's sprite follows the first's
+    for _ in 0..3 {
+        cpu.step().unwrap();
+    }
+    {
+        let io = cpu.io.lock().unwrap();
+        assert!((8..16).all(|col| io.display.get(0, col)));
+        assert!((12..16).all(|col| io.second_plane.get(0, col)));
+        assert!(!io.second_plane.get(0, 8));
+    }
+    assert_eq!(cpu.reg[0xF], 0);
+
+    cpu.run_to_end();
+    let io = cpu.io.lock().unwrap();
+    assert_eq!(io.display, Framebuffer::new());
+    assert!(io.second_plane.get(0, 0));
+    drop(io);
+    assert!(Chip8::new_test(&[PLANE(4)]).step().is_err());
+}
+
+#[test]
+fn scroll_instructions() {
+    for half in [false, true] {
+        let mut cpu = Chip8::new_test(&[SCD(2), SCR, SCL, SCL]);
+        cpu.quirks.half_scroll_in_lores = half;
+        cpu.io.lock().unwrap().display.set(0, 10, true);
+        let at = |cpu: &Chip8, row, col| cpu.io.lock().unwrap().display.get(row, col);
+
+        let shift = if half { 1 } else { 2 };
+        assert!(cpu.step() == Ok(StepResult::Continue(true)));
+        assert!(at(&cpu, shift, 10));
+        cpu.step().unwrap();
+        assert!(at(&cpu, shift, 10 + 2 * shift));
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert!(at(&cpu, shift, 10 - 2 * shift));
+    }
+
+    // Full amounts in high resolution, whatever the quirk
+    let mut cpu = Chip8::new_test(&[HIRES, SCD(3), SCR]);
+    cpu.quirks.half_scroll_in_lores = true;
+    cpu.step().unwrap();
+    cpu.io.lock().unwrap().display.set(0, 100, true);
+    cpu.run_to_end();
+    assert!(cpu.io.lock().unwrap().display.get(3, 104));
+}
+
+#[test]
+fn resolution_switch_clear_quirk() {
+    for clears in [false, true] {
+        let mut cpu = Chip8::new_test(&[LOADI(0x300), DRAW(0, 0, 1), HIRES, LORES]);
+        cpu.mem[0x300] = 0xFF;
+        cpu.quirks.resolution_switch_clears = clears;
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+
+        cpu.step().unwrap();
+        let io = cpu.io.lock().unwrap();
+        assert!(io.display.hires());
+        assert_eq!(io.display.get(0, 0), !clears, "clears: {}", clears);
+        drop(io);
+
+        cpu.io.lock().unwrap().display.set(5, 5, true);
+        cpu.step().unwrap();
+        let io = cpu.io.lock().unwrap();
+        assert!(!io.display.hires());
+        assert_eq!(io.display.get(5, 5), !clears, "clears: {}", clears);
+    }
+}
+
+#[test]
+fn delay_timer_follows_clock() {
+    let (mut cpu, clock) = Chip8::new_test_clocked(&[LOAD(0, 3), LOADD(0), JUMP(0x204)]);
+    cpu.step().unwrap();
+    cpu.step().unwrap();
+
+    // However many steps run, the timer only moves when the clock does
+    for _ in 0..100 {
+        cpu.step().unwrap();
+    }
+    assert_eq!(cpu.delay, 3);
+
+    for expected in [2, 1, 0, 0] {
+        clock.advance(time::Duration::from_millis(17));
+        cpu.step().unwrap();
+        assert_eq!(cpu.delay, expected);
+    }
+}
+
+#[test]
+fn delay_timer_runs_at_60hz() {
+    // Stepping faster than the timer: ticks aren't lost to rounding
+    let (mut cpu, clock) = Chip8::new_test_clocked(&[LOAD(0, 200), LOADD(0), JUMP(0x204)]);
+    cpu.step().unwrap();
+    cpu.step().unwrap();
+    for _ in 0..100 {
+        clock.advance(time::Duration::from_millis(10));
+        cpu.step().unwrap();
+    }
+    assert_eq!(cpu.delay, 200 - 60);
+
+    // Stepping slower than the timer: it catches up
+    let (mut cpu, clock) = Chip8::new_test_clocked(&[LOAD(0, 200), LOADD(0), MOVED(1)]);
+    cpu.step().unwrap();
+    cpu.step().unwrap();
+    clock.advance(time::Duration::from_millis(500));
+    cpu.step().unwrap();
+    assert_eq!(cpu.reg[1], 200 - 30);
+}
+
+#[test]
+fn restored_rng_repeats_rand() {
+    let mut cpu = Chip8::new_test(&[RAND(0, 0xFF), RAND(1, 0xFF), RAND(2, 0xFF), JUMP(0x202)]);
+    cpu.step().unwrap();
+    let saved = (cpu.pc, cpu.rand_rng.clone());
+    cpu.step().unwrap();
+    cpu.step().unwrap();
+    let first = (cpu.reg[1], cpu.reg[2]);
+
+    (cpu.pc, cpu.rand_rng) = saved;
     cpu.reg[1] = 0;
-    cpu.idx = 0x300;
-    cpu.mem[0x300] = 0xFF;
-    cpu.mem[0x301] = 0xFF;
-    // cpu.io.lock().unwrap().display[0][0] = false;
+    cpu.reg[2] = 0;
+    cpu.step().unwrap();
+    cpu.step().unwrap();
+    assert_eq!((cpu.reg[1], cpu.reg[2]), first);
+}
+
+#[test]
+fn seeded_rand_repeats_after_reset() {
+    let mut cpu = Chip8::new_test(&[RAND(0, 0xFF), RAND(1, 0xFF), RAND(2, 0xFF)]);
+    cpu.seed_rand(42);
+    cpu.run_to_end();
+    let first = cpu.reg[..3].to_vec();
+
+    cpu.reset();
+    cpu.run_to_end();
+    assert_eq!(cpu.reg[..3], first);
+}
+
+#[test]
+fn key_checks_recorded() {
+    let mut cpu = Chip8::new_test(&[SKPR(3), SKUP(0xA)]);
     cpu.run_to_end();
+    assert_eq!(cpu.checked_registers, 1 << 3 | 1 << 0xA);
+
+    cpu.reset();
+    assert_eq!(cpu.checked_registers, 0);
+}
+
+#[test]
+fn wrapped_collisions() {
+    for ignore in [false, true] {
+        // Draw a pixel at (0, 0), then a sprite at column 63 whose second pixel wraps onto it
+        let mut cpu = Chip8::new_test(&[
+            LOADI(0x300),
+            DRAW(0, 0, 1),
+            LOAD(0, 63),
+            LOADI(0x301),
+            DRAW(0, 1, 1),
+        ]);
+        cpu.mem[0x300] = 0b1000_0000;
+        cpu.mem[0x301] = 0b0100_0000;
+        cpu.quirks.ignore_wrapped_collisions = ignore;
+        cpu.run_to_end();
 
+        let io = cpu.io.lock().unwrap();
+        assert!(!io.display.get(0, 0), "wrapped pixel is still drawn");
+        assert_eq!(cpu.reg[0xF], if ignore { 0 } else { 1 });
+    }
+}
+
+#[test]
+fn find_bytes_in_memory() {
+    let mut cpu = Chip8::new_test(&[]);
+    cpu.mem[0x300..0x303].copy_from_slice(&[0xDE, 0xAD, 0xBE]);
+    cpu.mem[0xFFFE..].copy_from_slice(&[0xDE, 0xAD]);
+
+    assert_eq!(cpu.find_bytes(&[0xDE, 0xAD, 0xBE]), vec![0x300]);
+    assert_eq!(cpu.find_bytes(&[0xDE, 0xAD]), vec![0x300, 0xFFFE]);
+    assert_eq!(cpu.find_bytes(&[0xDE, 0xAD, 0xBE, 0xEF]), Vec::<u16>::new());
+    assert_eq!(cpu.find_bytes(&[]), Vec::<u16>::new());
+}
+
+#[test]
+fn sub_borrow_flag() {
+    // VF is 1 when there is no borrow, including when the registers are equal
+    for (a, b, result, flag) in [(5, 3, 2, 1), (3, 3, 0, 1), (3, 5, 0xFE, 0)] {
+        let mut cpu = Chip8::new_test(&[LOAD(0, a), LOAD(1, b), SUB(0, 1)]);
+        cpu.run_to_end();
+        assert_eq!((cpu.reg[0], cpu.reg[0xF]), (result, flag));
+
+        let mut cpu = Chip8::new_test(&[LOAD(0, b), LOAD(1, a), SUBN(0, 1)]);
+        cpu.run_to_end();
+        assert_eq!((cpu.reg[0], cpu.reg[0xF]), (result, flag));
+    }
+
+    // The flag wins when x is VF
+    let mut cpu = Chip8::new_test(&[LOAD(0xF, 5), LOAD(1, 3), SUB(0xF, 1)]);
+    cpu.run_to_end();
+    assert_eq!(cpu.reg[0xF], 1);
+    let mut cpu = Chip8::new_test(&[LOAD(0xF, 5), LOAD(1, 3), SUBN(0xF, 1)]);
+    cpu.run_to_end();
     assert_eq!(cpu.reg[0xF], 0);
 }
+
+#[test]
+fn addr_carry_boundary() {
+    // LOAD v0, 0xFE; LOAD v1, 1; ADDR v0, v1; ADDR v0, v1
+    let mut cpu = new_test_bytes(
+        &[0x60, 0xFE, 0x61, 0x01, 0x80, 0x14, 0x80, 0x14],
+        DecodeErrorPolicy::Halt,
+    );
+    cpu.step().unwrap();
+    cpu.step().unwrap();
+    cpu.step().unwrap();
+    assert_eq!((cpu.reg[0], cpu.reg[0xF]), (0xFF, 0));
+    cpu.step().unwrap();
+    assert_eq!((cpu.reg[0], cpu.reg[0xF]), (0x00, 1));
+
+    // LOAD vF, 0xFF; ADDR vF, v1
+    let mut cpu = new_test_bytes(
+        &[0x6F, 0xFF, 0x61, 0x01, 0x8F, 0x14],
+        DecodeErrorPolicy::Halt,
+    );
+    cpu.run_to_end();
+    assert_eq!(cpu.reg[0xF], 1);
+}
+
+#[test]
+fn persist_display_on_reset() {
+    for persist in [false, true] {
+        let mut cpu = Chip8::new_test(&[DRAW(0, 0, 1)]);
+        cpu.mem[0] = 0xFF;
+        cpu.persist_display = persist;
+        cpu.run_to_end();
+        cpu.io.lock().unwrap().keystate[3] = true;
+
+        cpu.reset();
+        let io = cpu.io.lock().unwrap();
+        assert_eq!(io.display.get(0, 0), persist);
+        assert!(!io.keystate[3]);
+    }
+}
+
+#[test]
+fn key_replay_drives_keyd() {
+    use crate::replay::parse_script;
+
+    let mut cpu = Chip8::new_test(&[KEYD(0)]);
+    cpu.key_replay = Some(KeyReplay::new(parse_script("10 press 7").unwrap()));
+    for _ in 0..10 {
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x200);
+    }
+    cpu.step().unwrap();
+    assert_eq!((cpu.pc, cpu.reg[0]), (0x202, 7));
+
+    // Resetting starts the script over
+    cpu.reset();
+    cpu.step().unwrap();
+    assert_eq!(cpu.pc, 0x200);
+}
+
+#[test]
+fn replayed_keys_survive_key_refresh() {
+    use crate::replay::parse_script;
+
+    let mut cpu = Chip8::new_test(&[JUMP(0x200)]);
+    cpu.key_replay = Some(KeyReplay::new(parse_script("0 press 7").unwrap()));
+    cpu.step().unwrap();
+    assert!(cpu.io.lock().unwrap().keystate[7]);
+
+    // The GUI refreshing the keyboard with nothing pressed keeps the replayed key held
+    cpu.io.lock().unwrap().set_local_keys([false; 16]);
+    assert!(cpu.io.lock().unwrap().keystate[7]);
+    assert_eq!(
+        cpu.io
+            .lock()
+            .unwrap()
+            .keystate
+            .iter()
+            .filter(|&&k| k)
+            .count(),
+        1
+    );
+}
+
+#[cfg(test)]
+#[derive(Debug)]
+struct MockDiagnostics(Arc<Mutex<Vec<QuirkUse>>>);
+
+#[cfg(test)]
+impl DiagnosticSink for MockDiagnostics {
+    fn quirk_used(&mut self, diagnostic: QuirkUse) {
+        self.0.lock().unwrap().push(diagnostic);
+    }
+}
+
+#[test]
+fn or_reports_quirk() {
+    let mut cpu = Chip8::new_test(&[LOAD(0, 3), OR(0, 0)]);
+    let log = Arc::new(Mutex::new(Vec::new()));
+    cpu.diagnostics = Some(Box::new(MockDiagnostics(log.clone())));
+    cpu.run_to_end();
+
+    assert_eq!(
+        *log.lock().unwrap(),
+        vec![QuirkUse {
+            pc: 0x202,
+            instruction: OR(0, 0),
+            quirk: "vf_reset",
+            enabled: false,
+        }]
+    );
+    assert_eq!(
+        log.lock().unwrap()[0].to_string(),
+        "0x202: OR    v0, v0 ran with vf_reset = false"
+    );
+}
+
+#[test]
+fn shr_reports_quirk() {
+    let mut cpu = Chip8::new_test(&[LOAD(0, 3), SHR(0, 0)]);
+    let log = Arc::new(Mutex::new(Vec::new()));
+    cpu.diagnostics = Some(Box::new(MockDiagnostics(log.clone())));
+    cpu.run_to_end();
+
+    assert_eq!(
+        *log.lock().unwrap(),
+        vec![QuirkUse {
+            pc: 0x202,
+            instruction: SHR(0, 0),
+            quirk: "shift_uses_vy",
+            enabled: false,
+        }]
+    );
+    assert_eq!(
+        log.lock().unwrap()[0].to_string(),
+        "0x202: SHR   v0, v0 ran with shift_uses_vy = false"
+    );
+}
+
+#[test]
+fn pause_cpu_and_timers_separately() {
+    for paused in [false, true] {
+        for timers_paused in [false, true] {
+            let (mut cpu, clock) =
+                Chip8::new_test_clocked(&[LOAD(0, 5), LOADD(0), ADD(1, 1), JUMP(0x204)]);
+            cpu.step().unwrap();
+            cpu.step().unwrap();
+            cpu.paused = paused;
+            cpu.timers_paused = timers_paused;
+
+            for _ in 0..3 {
+                clock.advance(time::Duration::from_millis(17));
+                cpu.step().unwrap();
+            }
+            let what = format!("paused {} timers_paused {}", paused, timers_paused);
+            assert_eq!(cpu.delay, if timers_paused { 5 } else { 2 }, "{}", what);
+            assert_eq!(cpu.reg[1], if paused { 0 } else { 2 }, "{}", what);
+        }
+    }
+}
+
+#[test]
+fn loadilong_reaches_past_4k() {
+    // LOADILONG is skipped as a whole
+    let mut cpu = Chip8::new_test(&[
+        LOAD(0, 1),
+        SKE(0, 1),
+        LOADILONG(0x1234),
+        LOADILONG(0xF000),
+        STOR(0),
+    ]);
+    cpu.step().unwrap();
+    cpu.step().unwrap();
+    assert_eq!(cpu.pc, 0x208);
+    cpu.step().unwrap();
+    assert_eq!(cpu.idx, 0xF000);
+    assert_eq!(cpu.pc, 0x20C);
+    cpu.step().unwrap();
+    assert_eq!(cpu.mem[0xF000], 1);
+
+    // ADDI wraps at 64K once I is past 4K
+    let mut cpu = Chip8::new_test(&[LOAD(1, 0x10), LOADILONG(0xFFF8), ADDI(1)]);
+    cpu.run_to_end();
+    assert_eq!(cpu.idx, 0x0008);
+}
+
+#[test]
+fn save_and_load_state() {
+    let mut cpu = Chip8::new_test(&[
+        LOAD(0, 0x12),
+        LOAD(0xF, 0x34),
+        LOADD(0),
+        LOADS(0xF),
+        PLANE(3),
+        HIRES,
+        LOADI(0x400),
+        STOR(0xF),
+        LDSPR(1),
+        DRAW(1, 1, 5),
+        CALL(0x218),
+        SYS(0),
+        LOADILONG(0x8000),
+    ]);
+    cpu.run_to_end();
+    let pc = cpu.pc;
+    let (display, second_plane) = {
+        let io = cpu.io.lock().unwrap();
+        (io.display, io.second_plane)
+    };
+    let state = cpu.save_state();
+
+    cpu.reset();
+    assert_eq!(cpu.io.lock().unwrap().display, Framebuffer::new());
+    cpu.load_state(&state).unwrap();
+    assert_eq!(cpu.pc, pc);
+    assert_eq!(cpu.idx, 0x8000);
+    assert_eq!(cpu.stack, vec![0x214]);
+    assert_eq!((cpu.delay, cpu.sound), (0x12, 0x34));
+    assert_eq!(cpu.selected_planes, 3);
+    assert_eq!(cpu.reg[0], 0x12);
+    assert_eq!(cpu.mem[0x40F], 0x34);
+    let io = cpu.io.lock().unwrap();
+    assert!(io.display.hires());
+    assert_eq!(io.display, display);
+    assert_eq!(io.second_plane, second_plane);
+    assert_ne!(io.second_plane, Framebuffer::new());
+    drop(io);
+    assert_eq!(cpu.save_state(), state);
+
+    // Other versions and truncated states are refused, leaving the CPU alone
+    let mut newer = state.clone();
+    newer[4] = STATE_VERSION + 1;
+    assert_eq!(
+        cpu.load_state(&newer),
+        Err(format!(
+            "Saved state is version {}, expected {}",
+            STATE_VERSION + 1,
+            STATE_VERSION
+        ))
+    );
+    assert!(cpu.load_state(b"PK\x03\x04").is_err());
+    cpu.pc = 0x200;
+    assert_eq!(
+        cpu.load_state(&state[..state.len() - 1]),
+        Err("Saved state ends early".to_string())
+    );
+    let mut planes = state.clone();
+    planes[11] = 4;
+    assert_eq!(
+        cpu.load_state(&planes),
+        Err("Saved state selects planes 4, past the second".to_string())
+    );
+    cpu.max_stack_depth = 0;
+    assert_eq!(
+        cpu.load_state(&state),
+        Err("Saved state has 1 return addresses, but the stack only holds 0".to_string())
+    );
+    assert_eq!(cpu.pc, 0x200);
+}
+
+#[test]
+fn load_state_from_another_rom() {
+    let mut other = Chip8::new_test(&[LOAD(0, 7), LOADI(0x300), STOR(0)]);
+    other.run_to_end();
+    let state = other.save_state();
+
+    let mut cpu = Chip8::new_test(&[LOAD(1, 2), SYS(0)]);
+    cpu.run_to_end();
+    cpu.load_state(&state).unwrap();
+    assert_eq!(cpu.rom(), other.mem[0x200..].to_vec());
+    assert!(!cpu.executed[0x200]);
+
+    // Resetting starts over from the loaded memory, not the first ROM
+    cpu.reset();
+    assert_eq!(cpu.current_instruction(), Ok(LOAD(0, 7)));
+    assert_eq!(cpu.mem[0x300], 7);
+    cpu.run_to_end();
+    assert_eq!((cpu.reg[0], cpu.reg[1]), (7, 0));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_json_round_trip() {
+    let cpu = Chip8::new_test(&[]);
+    let json = serde_json::to_string(&cpu).unwrap();
+    let copy: Chip8 = serde_json::from_str(&json).unwrap();
+    assert_eq!(copy.pc, cpu.pc);
+    assert_eq!(copy.reg, cpu.reg);
+    assert_eq!(copy.mem, cpu.mem);
+    assert_eq!(copy.quirks, cpu.quirks);
+    assert_eq!(
+        copy.io.lock().unwrap().display,
+        cpu.io.lock().unwrap().display
+    );
+    assert_eq!(serde_json::to_string(&copy).unwrap(), json);
+
+    // After running, the state round trips too
+    let mut cpu = Chip8::new_test(&[LOAD(0, 7), CALL(0x206), SYS(0), LDSPR(0), DRAW(1, 1, 5)]);
+    cpu.run_to_end();
+    let json = serde_json::to_string(&cpu).unwrap();
+    assert!(json.contains("\"stack\":[514]"), "{}", json);
+    assert!(json.contains("\"####...."), "{}", json);
+    let copy: Chip8 = serde_json::from_str(&json).unwrap();
+    assert_eq!(copy.save_state(), cpu.save_state());
+
+    let short = json.replacen("\"mem\":[", "\"mem\":[1,", 1);
+    assert!(serde_json::from_str::<Chip8>(&short).is_err());
+    let deep = json.replacen("\"stack\":[514]", &format!("\"stack\":{:?}", [514; 17]), 1);
+    assert!(serde_json::from_str::<Chip8>(&deep).is_err());
+    let planes = json.replacen("\"selected_planes\":1", "\"selected_planes\":4", 1);
+    assert_ne!(planes, json);
+    assert!(serde_json::from_str::<Chip8>(&planes).is_err());
+}
@@ -0,0 +1,427 @@
+pub const DISPLAY_ROWS: usize = 32;
+pub const DISPLAY_COLS: usize = 64;
+/// Size of the SUPER-CHIP high resolution display
+pub const HIRES_ROWS: usize = 64;
+pub const HIRES_COLS: usize = 128;
+
+/// The monochrome display, 64x32 or (in SUPER-CHIP high resolution mode) 128x64, packed one bit
+/// per pixel. Each row is a u128 with column 0 in the most significant bit, so a sprite byte can
+/// be drawn with a shift and a single XOR. Switching resolution keeps the bits, so the low
+/// resolution picture is the top left quarter of the high resolution one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Framebuffer {
+    rows: [u128; HIRES_ROWS],
+    hires: bool,
+}
+
+impl Default for Framebuffer {
+    fn default() -> Self {
+        Framebuffer {
+            rows: [0; HIRES_ROWS],
+            hires: false,
+        }
+    }
+}
+
+/// Bit for `col` within a row
+fn col_mask(col: usize) -> u128 {
+    1 << (HIRES_COLS - 1 - col)
+}
+
+/// Bits of a row covered by a sprite byte drawn at `col` on a display `width` pixels wide, see
+/// `Framebuffer::xor_byte`
+fn sprite_mask(col: usize, byte: u8, clip: bool, width: usize) -> u128 {
+    let sprite = (byte as u128) << (HIRES_COLS - 8);
+    let on_screen = u128::MAX << (HIRES_COLS - width);
+    let mask = if clip {
+        sprite >> col
+    } else if width == HIRES_COLS {
+        sprite.rotate_right(col as u32)
+    } else {
+        // Move the pixels past the right edge back to the left
+        (sprite >> col) | (sprite >> col) << width
+    };
+    mask & on_screen
+}
+
+impl Framebuffer {
+    pub fn new() -> Framebuffer {
+        Framebuffer::default()
+    }
+
+    /// Whether this is the 128x64 SUPER-CHIP display
+    pub fn hires(&self) -> bool {
+        self.hires
+    }
+
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+    }
+
+    pub fn width(&self) -> usize {
+        if self.hires {
+            HIRES_COLS
+        } else {
+            DISPLAY_COLS
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        if self.hires {
+            HIRES_ROWS
+        } else {
+            DISPLAY_ROWS
+        }
+    }
+
+    pub fn get(&self, row: usize, col: usize) -> bool {
+        self.rows[row] & col_mask(col) != 0
+    }
+
+    pub fn set(&mut self, row: usize, col: usize, on: bool) {
+        let mask = col_mask(col);
+        if on {
+            self.rows[row] |= mask;
+        } else {
+            self.rows[row] &= !mask;
+        }
+    }
+
+    /// Turn every pixel off, keeping the resolution
+    pub fn clear(&mut self) {
+        self.rows = [0; HIRES_ROWS];
+    }
+
+    /// Bits of one row, column 0 in the most significant bit
+    pub fn row(&self, row: usize) -> u128 {
+        self.rows[row]
+    }
+
+    pub fn set_row(&mut self, row: usize, bits: u128) {
+        self.rows[row] = bits;
+    }
+
+    /// Every row as pixels from left to right
+    pub fn rows(&self) -> impl Iterator<Item = impl Iterator<Item = bool>> + '_ {
+        let width = self.width();
+        self.rows[..self.height()]
+            .iter()
+            .map(move |&bits| (0..width).map(move |col| bits & col_mask(col) != 0))
+    }
+
+    /// The bits of a row that are on screen at this resolution
+    fn on_screen(&self) -> u128 {
+        u128::MAX << (HIRES_COLS - self.width())
+    }
+
+    /// Move every pixel `n` rows down. Rows scrolled in at the top are off
+    pub fn scroll_down(&mut self, n: usize) {
+        let height = self.height();
+        let n = n.min(height);
+        self.rows.copy_within(0..height - n, n);
+        self.rows[..n].fill(0);
+    }
+
+    /// Move every pixel `n` columns right. Columns scrolled in at the left are off
+    pub fn scroll_right(&mut self, n: usize) {
+        let (height, on_screen) = (self.height(), self.on_screen());
+        for row in &mut self.rows[..height] {
+            *row = (*row & on_screen).checked_shr(n as u32).unwrap_or(0) & on_screen;
+        }
+    }
+
+    /// Move every pixel `n` columns left. Columns scrolled in at the right are off
+    pub fn scroll_left(&mut self, n: usize) {
+        let (height, on_screen) = (self.height(), self.on_screen());
+        for row in &mut self.rows[..height] {
+            *row = (*row & on_screen).checked_shl(n as u32).unwrap_or(0);
+        }
+    }
+
+    /// XOR the 8 pixels of a sprite byte (leftmost in the most significant bit) into `row`,
+    /// starting at `col`. Pixels past the right edge wrap around, or are dropped if `clip`.
+    /// Returns whether any pixel was turned off.
+    pub fn xor_byte(&mut self, row: usize, col: usize, byte: u8, clip: bool) -> bool {
+        let mask = sprite_mask(col, byte, clip, self.width());
+        let collided = self.rows[row] & mask != 0;
+        self.rows[row] ^= mask;
+        collided
+    }
+
+    /// Whether `xor_byte` would turn any pixel off, without drawing anything
+    pub fn collides(&self, row: usize, col: usize, byte: u8, clip: bool) -> bool {
+        self.rows[row] & sprite_mask(col, byte, clip, self.width()) != 0
+    }
+
+    /// Same as `xor_byte`, one pixel at a time. `row` and `col` may be past the edges, in which
+    /// case they wrap around, or are dropped if `clip`.
+    pub fn xor_byte_scalar(&mut self, row: usize, col: usize, byte: u8, clip: bool) -> bool {
+        let (width, height) = (self.width(), self.height());
+        let mut collided = false;
+        for bitidx in 0..8 {
+            let (row, col) = (row, col + bitidx);
+            if clip && (row >= height || col >= width) {
+                continue;
+            }
+            let bit = (byte & (1 << (7 - bitidx))) != 0;
+            let (row, col) = (row % height, col % width);
+            if self.get(row, col) & bit {
+                collided = true;
+            }
+            self.set(row, col, self.get(row, col) ^ bit);
+        }
+        collided
+    }
+}
+
+/// Serialized as the resolution and one line of `#` (on) and `.` (off) per row, so dumps of the
+/// display can be read and diffed
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct FramebufferRows {
+    hires: bool,
+    rows: Vec<String>,
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Framebuffer {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        FramebufferRows {
+            hires: self.hires,
+            rows: self
+                .rows()
+                .map(|row| row.map(|on| if on { '#' } else { '.' }).collect())
+                .collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Framebuffer {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let FramebufferRows { hires, rows } = FramebufferRows::deserialize(deserializer)?;
+        let mut display = Framebuffer::new();
+        display.set_hires(hires);
+        if rows.len() != display.height() {
+            return Err(D::Error::invalid_length(
+                rows.len(),
+                &"a row per display row",
+            ));
+        }
+        for (row, line) in rows.iter().enumerate() {
+            if line.chars().count() != display.width() {
+                return Err(D::Error::custom(format!(
+                    "Expected {} columns in {:?}",
+                    display.width(),
+                    line
+                )));
+            }
+            for (col, c) in line.chars().enumerate() {
+                match c {
+                    '#' => display.set(row, col, true),
+                    '.' => {}
+                    _ => return Err(D::Error::custom(format!("Unexpected {:?} in display", c))),
+                }
+            }
+        }
+        Ok(display)
+    }
+}
+
+/// Fraction of its brightness a pixel keeps each frame after being turned off, by default
+pub const DEFAULT_AFTERIMAGE_DECAY: f32 = 0.7;
+
+/// How bright each pixel looks on screen. Pixels that turn off fade out over a few frames, like
+/// on a slow LCD, so ROMs that erase a sprite and redraw it every frame don't flicker.
+#[derive(Debug, Clone)]
+pub struct Afterimage {
+    levels: [[f32; HIRES_COLS]; HIRES_ROWS],
+    hires: bool,
+    /// Fraction of its brightness an off pixel keeps each frame. At 0 pixels turn off straight away
+    pub decay: f32,
+}
+
+impl Afterimage {
+    pub fn new(decay: f32) -> Afterimage {
+        Afterimage {
+            levels: [[0.; HIRES_COLS]; HIRES_ROWS],
+            hires: false,
+            decay,
+        }
+    }
+
+    /// Move on `frames` frames, showing `display`. Off pixels fade once per frame, however often
+    /// we're shown
+    pub fn update(&mut self, display: &Framebuffer, frames: u64) {
+        if display.hires() != self.hires {
+            // The old levels are for different pixels
+            self.hires = display.hires();
+            self.levels = [[0.; HIRES_COLS]; HIRES_ROWS];
+        }
+        let decay = self.decay.powi(frames.min(i32::MAX as u64) as i32);
+        for (levels, pixels) in self.levels.iter_mut().zip(display.rows()) {
+            for (level, on) in levels.iter_mut().zip(pixels) {
+                *level = if on { 1. } else { *level * decay };
+            }
+        }
+    }
+
+    /// Brightness of a pixel, from 0 (off) to 1 (on)
+    pub fn level(&self, row: usize, col: usize) -> f32 {
+        self.levels[row][col]
+    }
+}
+
+#[test]
+fn packed_matches_scalar() {
+    for hires in [false, true] {
+        for clip in [false, true] {
+            let mut fb = Framebuffer::new();
+            fb.set_hires(hires);
+            for col in 0..fb.width() {
+                let mut packed = fb;
+                let mut scalar = fb;
+                for fb in [&mut packed, &mut scalar] {
+                    fb.set(3, 0, true);
+                    fb.set(3, fb.width() - 1, true);
+                    fb.set(3, col, true);
+                }
+                let packed_vf = packed.xor_byte(3, col, 0b1011_0011, clip);
+                let scalar_vf = scalar.xor_byte_scalar(3, col, 0b1011_0011, clip);
+                assert_eq!(packed, scalar, "col {} clip {} hires {}", col, clip, hires);
+                assert_eq!(packed_vf, scalar_vf, "col {} clip {}", col, clip);
+            }
+        }
+    }
+}
+
+#[test]
+fn xor_byte_wraps_and_clips() {
+    let mut fb = Framebuffer::new();
+    assert!(!fb.xor_byte(0, 60, 0xFF, false));
+    assert_eq!(fb.row(0), 0xF000_0000_0000_000F << 64);
+    assert!(fb.xor_byte(0, 60, 0x80, true));
+    assert_eq!(fb.row(0), 0xF000_0000_0000_0007 << 64);
+
+    fb.clear();
+    assert_eq!(fb.row(0), 0);
+}
+
+#[test]
+fn hires_rows_are_128_wide() {
+    let mut fb = Framebuffer::new();
+    fb.set_hires(true);
+    assert_eq!((fb.width(), fb.height()), (HIRES_COLS, HIRES_ROWS));
+    assert!(!fb.xor_byte(63, 124, 0xFF, false));
+    assert_eq!(fb.row(63), 0xF << 124 | 0xF);
+    assert!(fb.get(63, 127) && fb.get(63, 0) && !fb.get(63, 64));
+    assert_eq!(fb.rows().count(), HIRES_ROWS);
+
+    // Back in low resolution, only the top left quarter is shown
+    fb.set(0, 3, true);
+    fb.set(0, 64, true);
+    fb.set_hires(false);
+    assert_eq!(fb.rows().count(), DISPLAY_ROWS);
+    let lit: Vec<(usize, usize)> = fb
+        .rows()
+        .enumerate()
+        .flat_map(|(r, row)| {
+            row.enumerate()
+                .filter(|&(_, on)| on)
+                .map(move |(c, _)| (r, c))
+        })
+        .collect();
+    assert_eq!(lit, [(0, 3)]);
+}
+
+#[test]
+fn collides_does_not_draw() {
+    let mut fb = Framebuffer::new();
+    fb.set(0, 0, true);
+    assert!(fb.collides(0, 63, 0b0100_0000, false));
+    assert!(!fb.collides(0, 63, 0b0100_0000, true));
+    assert_eq!(fb.row(0), col_mask(0));
+}
+
+#[test]
+fn afterimage_hides_flicker() {
+    let mut on = Framebuffer::new();
+    on.set(1, 2, true);
+    let off = Framebuffer::new();
+
+    let mut afterimage = Afterimage::new(0.5);
+    for _ in 0..5 {
+        afterimage.update(&on, 1);
+        assert_eq!(afterimage.level(1, 2), 1.);
+        afterimage.update(&off, 1);
+        assert_eq!(afterimage.level(1, 2), 0.5);
+    }
+    afterimage.update(&off, 1);
+    afterimage.update(&off, 1);
+    assert_eq!(afterimage.level(1, 2), 0.125);
+    assert_eq!(afterimage.level(0, 0), 0.);
+
+    let mut no_afterimage = Afterimage::new(0.);
+    no_afterimage.update(&on, 1);
+    no_afterimage.update(&off, 1);
+    assert_eq!(no_afterimage.level(1, 2), 0.);
+}
+
+#[test]
+fn afterimage_fades_per_frame() {
+    let mut on = Framebuffer::new();
+    on.set(1, 2, true);
+    let off = Framebuffer::new();
+
+    let mut afterimage = Afterimage::new(0.5);
+    afterimage.update(&on, 1);
+    // Repainting without a new frame doesn't fade anything
+    afterimage.update(&off, 0);
+    afterimage.update(&off, 0);
+    assert_eq!(afterimage.level(1, 2), 1.);
+    // Missing repaints doesn't slow the fade down
+    afterimage.update(&off, 2);
+    assert_eq!(afterimage.level(1, 2), 0.25);
+}
+
+#[test]
+fn scroll_each_direction() {
+    let text = |fb: &Framebuffer| -> Vec<String> {
+        fb.rows()
+            .take(3)
+            .map(|row| row.take(6).map(|on| if on { '#' } else { '.' }).collect())
+            .collect()
+    };
+    let mut pattern = Framebuffer::new();
+    pattern.set(0, 0, true);
+    pattern.set(0, 5, true);
+    pattern.set(1, 1, true);
+    // Off screen in low resolution, so it must not scroll into view
+    pattern.set(0, 64, true);
+
+    let mut fb = pattern;
+    fb.scroll_down(1);
+    assert_eq!(text(&fb), ["......", "#....#", ".#...."]);
+
+    let mut fb = pattern;
+    fb.scroll_right(4);
+    assert_eq!(text(&fb), ["....#.", ".....#", "......"]);
+    assert!(!fb.get(0, 63) && fb.get(0, 9));
+
+    let mut fb = pattern;
+    fb.scroll_left(1);
+    assert_eq!(text(&fb), ["....#.", "#.....", "......"]);
+    assert!(!fb.get(0, 63));
+
+    let mut fb = pattern;
+    fb.scroll_down(40);
+    assert_eq!(fb, Framebuffer::new());
+
+    let mut fb = pattern;
+    fb.set_hires(true);
+    fb.scroll_left(4);
+    assert!(fb.get(0, 60) && fb.get(0, 1));
+}
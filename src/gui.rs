@@ -1,21 +1,38 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::mem;
+use std::path::PathBuf;
 use std::sync::atomic::{self, AtomicU64};
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use eframe::egui::Slider;
 use eframe::epaint::{Color32, Rect, Vec2};
 use eframe::{egui, epi};
 
-use crate::cpu::{Chip8, Chip8IO, StepResult, KEYPAD_TO_QWERTY};
-use crate::cpu::{DISPLAY_COLS, DISPLAY_ROWS};
+use crate::analyze::{control_targets, reachable_addresses};
+use crate::audio::Waveform;
+use crate::cpu::{default_keymap, BreakAction, Chip8, Chip8IO, Keymap, KEYPAD_TO_QWERTY};
+use crate::cpu::{FIRST_DRAW_MAX_STEPS, MAX_HISTORY_LEN, MEMORY_SIZE};
+use crate::display::{Afterimage, DEFAULT_AFTERIMAGE_DECAY};
+use crate::instruction::Instruction;
+use crate::lock_stats::{lock_timed, LockProfiler, WaitStats};
+use crate::monitor::Command;
+use crate::opcode_docs;
 
 const WINDOW_NAME: &str = "CHIP8";
 const DISPLAY_WIDTH: f32 = 960.;
 const DISPLAY_HEIGHT: f32 = 540.;
-const PIXEL_WIDTH: f32 = DISPLAY_WIDTH / DISPLAY_COLS as f32;
-const PIXEL_HEIGHT: f32 = DISPLAY_HEIGHT / DISPLAY_ROWS as f32;
+
+/// Instructions shown before the pc in the disassembly, when following it
+const DISASSEMBLY_CONTEXT: u16 = 4;
+const DISASSEMBLY_LINES: u16 = 2 * DISASSEMBLY_CONTEXT + 1;
+const MEMORY_ROWS: usize = 9;
+const MEMORY_ROW_BYTES: usize = 8;
 
 const WINDOW_WIDTH: f32 = DISPLAY_WIDTH + 300.;
-const WINDOW_HEIGHT: f32 = DISPLAY_HEIGHT + 200.;
+const WINDOW_HEIGHT: f32 = DISPLAY_HEIGHT + 450.;
 
 pub struct Chip8Gui {
     cpu: Arc<Mutex<Chip8>>,
@@ -23,6 +40,43 @@ pub struct Chip8Gui {
 
     target_ips: Arc<AtomicU64>,
     dark_mode: bool,
+    /// Swap the on and off pixel colors
+    invert: bool,
+    /// Fades out pixels that were just turned off, to hide flicker
+    afterimage: Afterimage,
+    /// `Chip8IO::frames` when the afterimage was last updated
+    afterimage_frame: u64,
+    /// Set to cap the frame rate while running
+    frame_limiter: Option<FrameLimiter>,
+    /// Requests the frames the limiter holds back, once they're due. Started with the window.
+    repaint_timer: Option<RepaintTimer>,
+    lock_profiler: Option<Arc<LockProfiler>>,
+
+    /// Keep the disassembly centered on the pc. When off, it starts at `disasm_offset`
+    follow_pc: bool,
+    disasm_offset: u16,
+    mem_offset: u16,
+
+    goto_input: String,
+    goto_error: String,
+
+    monitor_input: String,
+    monitor_output: String,
+
+    /// Highlight the registers that changed the last time any of them did
+    highlight_changes: bool,
+    prev_reg: Vec<u8>,
+    reg_changes: u64,
+
+    /// Statically reachable addresses and jump targets of `analyzed_rom`, redone when another
+    /// ROM is loaded
+    reachable: BTreeSet<u16>,
+    targets: BTreeSet<u16>,
+    analyzed_rom: Vec<u8>,
+
+    keymap: Keymap,
+    /// Where "Save state" and "Load state" keep the snapshot
+    state_path: Option<PathBuf>,
 }
 
 impl Chip8Gui {
@@ -31,15 +85,49 @@ impl Chip8Gui {
         io: Arc<Mutex<Chip8IO>>,
         target_ips: Arc<AtomicU64>,
         dark_mode: bool,
+        invert: bool,
+        max_fps: Option<u32>,
     ) -> Self {
+        let lock_profiler = cpu.lock().unwrap().lock_profiler.clone();
         Self {
             cpu,
             io,
             target_ips,
             dark_mode,
+            invert,
+            afterimage: Afterimage::new(DEFAULT_AFTERIMAGE_DECAY),
+            afterimage_frame: 0,
+            frame_limiter: max_fps.map(|fps| FrameLimiter::new(fps.max(1))),
+            repaint_timer: None,
+            lock_profiler,
+            follow_pc: true,
+            disasm_offset: 0x200,
+            mem_offset: 0x200,
+            goto_input: String::new(),
+            goto_error: String::new(),
+            monitor_input: String::new(),
+            monitor_output: String::new(),
+            highlight_changes: false,
+            prev_reg: Vec::new(),
+            reg_changes: 0,
+            reachable: BTreeSet::new(),
+            targets: BTreeSet::new(),
+            analyzed_rom: Vec::new(),
+            keymap: default_keymap(),
+            state_path: None,
         }
     }
 
+    /// Use these keyboard keys for the CHIP-8 keys, instead of the usual 1234/QWER/ASDF/ZXCV
+    pub fn set_keymap(&mut self, keymap: Keymap) {
+        self.keymap = keymap;
+    }
+
+    /// Show "Save state" and "Load state" buttons, keeping the snapshot in `path`
+    pub fn set_state_path(&mut self, path: PathBuf) {
+        self.state_path = Some(path);
+    }
+
     pub fn run(self) {
         eframe::run_native(
             Box::new(self),
@@ -50,7 +138,29 @@ impl Chip8Gui {
         );
     }
 
-    fn chip8_display(&self, ui: &mut egui::Ui) -> egui::Response {
+    /// Where the GUI thread's lock waits go, if profiling
+    fn lock_waits(&self) -> Option<&Mutex<WaitStats>> {
+        self.lock_profiler.as_ref().map(|p| &p.gui_thread)
+    }
+
+    fn lock_cpu(&self) -> MutexGuard<'_, Chip8> {
+        lock_timed(&self.cpu, self.lock_waits())
+    }
+
+    fn lock_io(&self) -> MutexGuard<'_, Chip8IO> {
+        lock_timed(&self.io, self.lock_waits())
+    }
+
+    fn draw_lock_stats(&self, ui: &mut egui::Ui) {
+        if let Some(profiler) = &self.lock_profiler {
+            let cpu = *profiler.cpu_thread.lock().unwrap();
+            let gui = *profiler.gui_thread.lock().unwrap();
+            ui.label(egui::RichText::new(format!("CPU waits: {}", cpu)).small());
+            ui.label(egui::RichText::new(format!("GUI waits: {}", gui)).small());
+        }
+    }
+
+    fn chip8_display(&mut self, ui: &mut egui::Ui) -> egui::Response {
         let (rect, response) = ui.allocate_exact_size(
             Vec2::new(DISPLAY_WIDTH, DISPLAY_HEIGHT),
             egui::Sense {
@@ -60,25 +170,45 @@ impl Chip8Gui {
             },
         );
 
-        let (off_color, on_color) = if ui.style().visuals.dark_mode {
-            (Color32::BLACK, Color32::WHITE)
-        } else {
-            (Color32::WHITE, Color32::BLACK)
-        };
+        let (off_color, on_color) = pixel_colors(ui.style().visuals.dark_mode, self.invert);
 
+        // Work out the colors through the lock, borrowing the display, and paint once it's released
+        let (width, colors) = {
+            let io = lock_timed(&self.io, self.lock_profiler.as_ref().map(|p| &p.gui_thread));
+            let (display, second_plane) = io.planes();
+            // Resetting the IO starts the count again
+            let frames = io.frames.saturating_sub(self.afterimage_frame);
+            self.afterimage_frame = io.frames;
+            self.afterimage.update(display, frames);
+            let mut colors = Vec::with_capacity(display.width() * display.height());
+            for row in 0..display.height() {
+                for col in 0..display.width() {
+                    colors.push(plane_color(
+                        off_color,
+                        on_color,
+                        self.afterimage.level(row, col),
+                        second_plane.get(row, col),
+                    ));
+                }
+            }
+            (display.width(), colors)
+        };
+        // Either resolution fills the same space
+        let pixel_width = DISPLAY_WIDTH / width as f32;
+        let pixel_height = DISPLAY_HEIGHT / (colors.len() / width) as f32;
         let mut pos = rect.min;
-        for row in self.io.lock().unwrap().display {
+        for row in colors.chunks(width) {
             pos.x = 0.;
-            for pixel in row {
+            for &color in row {
                 ui.painter().rect(
-                    Rect::from_min_size(pos, Vec2::new(PIXEL_WIDTH + 1., PIXEL_HEIGHT + 1.)),
+                    Rect::from_min_size(pos, Vec2::new(pixel_width + 1., pixel_height + 1.)),
                     0.,
-                    if pixel { on_color } else { off_color },
+                    color,
                     (0., off_color),
                 );
-                pos.x += PIXEL_WIDTH;
+                pos.x += pixel_width;
             }
-            pos.y += PIXEL_HEIGHT as f32;
+            pos.y += pixel_height;
         }
 
         response
@@ -88,13 +218,13 @@ impl Chip8Gui {
         egui::Grid::new("chip8_keypad")
             .show(ui, |ui| {
                 for (idx, &keypad_key) in KEYPAD_TO_QWERTY.keys().enumerate() {
-                    let pressed = self.io.lock().unwrap().keystate[keypad_key as usize];
+                    let pressed = self.lock_io().keystate[keypad_key as usize];
                     if idx % 4 == 0 && (idx != 0) {
                         ui.end_row();
                     }
 
                     ui.label(
-                        egui::RichText::new(&format!("{:X}", keypad_key)).background_color(
+                        egui::RichText::new(format!("{:X}", keypad_key)).background_color(
                             if pressed {
                                 Color32::RED
                             } else {
@@ -107,23 +237,44 @@ impl Chip8Gui {
             .response
     }
 
-    fn draw_registers(&self, ui: &mut egui::Ui) -> egui::Response {
+    fn draw_registers(&mut self, ui: &mut egui::Ui) -> egui::Response {
+        let (reg, idx, delay, sound) = {
+            let cpu = self.lock_cpu();
+            (cpu.reg.clone(), cpu.idx, cpu.delay, cpu.sound)
+        };
+        if self.prev_reg.len() != reg.len() {
+            self.prev_reg = reg.clone();
+        }
+        let changed = changed_registers(&self.prev_reg, &reg);
+        if changed != 0 {
+            self.reg_changes = changed;
+            self.prev_reg = reg.clone();
+        }
+
         ui.vertical(|ui| {
-            egui::Grid::new("chip8_keypad")
-                .show(ui, |ui| {
-                    let cpu = self.cpu.lock().unwrap();
-                    for (reg, val) in cpu.reg.iter().enumerate() {
-                        ui.label(format!("v{:X}", reg));
-                        ui.label(format!("v{:#x}", val));
-                        ui.end_row();
+            egui::Grid::new("chip8_keypad").show(ui, |ui| {
+                for (r, val) in reg.iter().enumerate() {
+                    let mut name = egui::RichText::new(format!("v{:X}", r));
+                    if self.highlight_changes && self.reg_changes & (1 << r) != 0 {
+                        name = name.background_color(Color32::YELLOW);
                     }
-                    ui.label("Index");
-                    ui.label(format!("v{:#x}", cpu.idx));
+                    ui.label(name);
+                    ui.label(format!("v{:#x}", val));
                     ui.end_row();
-                })
-                .response;
+                }
+                ui.label("Index");
+                ui.label(format!("v{:#x}", idx));
+                ui.end_row();
+                ui.label("Delay");
+                ui.label(delay.to_string());
+                ui.end_row();
+                ui.label("Sound");
+                ui.label(sound.to_string());
+                ui.end_row();
+            });
+            ui.checkbox(&mut self.highlight_changes, "Highlight changes");
             let (pc, instr) = {
-                let cpu = self.cpu.lock().unwrap();
+                let cpu = self.lock_cpu();
                 (cpu.pc, cpu.current_instruction())
             };
             ui.label(format!(
@@ -138,25 +289,355 @@ impl Chip8Gui {
         .response
     }
 
+    /// First address shown in the disassembly
+    fn disasm_start(&self, pc: u16) -> u16 {
+        if self.follow_pc {
+            pc.saturating_sub(DISASSEMBLY_CONTEXT * 2)
+        } else {
+            self.disasm_offset
+        }
+    }
+
+    fn draw_disassembly(&mut self, ui: &mut egui::Ui) -> egui::Response {
+        ui.vertical(|ui| {
+            ui.checkbox(&mut self.follow_pc, "Follow PC");
+            let cpu = lock_timed(
+                &self.cpu,
+                self.lock_profiler.as_ref().map(|p| &p.gui_thread),
+            );
+            if cpu.rom() != self.analyzed_rom {
+                self.analyzed_rom = cpu.rom().to_vec();
+                self.reachable = reachable_addresses(&self.analyzed_rom);
+                self.targets = control_targets(&self.analyzed_rom);
+            }
+            let start = self.disasm_start(cpu.pc);
+            let end = start.saturating_add(DISASSEMBLY_LINES * 2);
+            for (addr, instr) in cpu.disasm_range(start, end) {
+                let color = match coverage(addr, &self.reachable, &cpu.executed[..]) {
+                    Coverage::Executed => Color32::from_rgb(0x20, 0xA0, 0x20),
+                    Coverage::Reachable => ui.visuals().text_color(),
+                    Coverage::Unreachable => Color32::GRAY,
+                };
+                // Jump and call targets are marked like labels
+                let text = format!(
+                    "{}{} {}: {}",
+                    if addr == cpu.pc { ">" } else { " " },
+                    if self.targets.contains(&addr) {
+                        "*"
+                    } else {
+                        " "
+                    },
+                    cpu.symbols.describe(addr),
+                    match instr {
+                        Ok(i) => i.format_with(&cpu.symbols),
+                        Err(_) => "???".to_string(),
+                    }
+                );
+                let label = ui.label(egui::RichText::new(text).monospace().color(color));
+                if let Some(doc) = instr.ok().as_ref().and_then(opcode_docs::lookup) {
+                    label.on_hover_text(doc.to_string());
+                }
+            }
+            // What the next instruction does, for following along while stepping
+            let next = cpu.disasm_range(cpu.pc, cpu.pc.saturating_add(4)).next();
+            if let Some(doc) = next
+                .and_then(|(_, i)| i.ok())
+                .as_ref()
+                .and_then(opcode_docs::lookup)
+            {
+                ui.label(egui::RichText::new(doc.to_string()).small());
+            }
+        })
+        .response
+    }
+
+    fn draw_memory(&self, ui: &mut egui::Ui) -> egui::Response {
+        ui.vertical(|ui| {
+            let cpu = self.lock_cpu();
+            let start = self.mem_offset as usize;
+            let end = (start + MEMORY_ROWS * MEMORY_ROW_BYTES).min(MEMORY_SIZE);
+            for (row, bytes) in cpu.mem[start..end].chunks(MEMORY_ROW_BYTES).enumerate() {
+                let mut text = format!("{:#05x}:", start + row * MEMORY_ROW_BYTES);
+                for byte in bytes {
+                    text.push_str(&format!(" {:02x}", byte));
+                }
+                ui.label(egui::RichText::new(text).monospace());
+            }
+        })
+        .response
+    }
+
+    /// Arrow keys and page up/down scroll the disassembly, or the memory view with shift held
+    fn handle_navigation(&mut self, ctx: &egui::Context) {
+        if ctx.wants_keyboard_input() {
+            return;
+        }
+        let input = ctx.input();
+        let lines: i32 = if input.key_pressed(egui::Key::ArrowUp) {
+            -1
+        } else if input.key_pressed(egui::Key::ArrowDown) {
+            1
+        } else if input.key_pressed(egui::Key::PageUp) {
+            -(DISASSEMBLY_LINES as i32)
+        } else if input.key_pressed(egui::Key::PageDown) {
+            DISASSEMBLY_LINES as i32
+        } else {
+            return;
+        };
+
+        if input.modifiers.shift {
+            self.mem_offset = scroll_offset(
+                self.mem_offset,
+                lines * MEMORY_ROW_BYTES as i32,
+                (MEMORY_SIZE - MEMORY_ROWS * MEMORY_ROW_BYTES) as u16,
+            );
+        } else {
+            let pc = self.lock_cpu().pc;
+            self.disasm_offset = scroll_offset(
+                self.disasm_start(pc),
+                lines * 2,
+                (MEMORY_SIZE - DISASSEMBLY_LINES as usize * 2) as u16,
+            );
+            self.follow_pc = false;
+        }
+    }
+
+    fn draw_stack_gauge(&self, ui: &mut egui::Ui) -> egui::Response {
+        let (depth, max) = {
+            let cpu = self.lock_cpu();
+            (cpu.stack.len(), cpu.max_stack_depth)
+        };
+        ui.add(
+            egui::ProgressBar::new(stack_fraction(depth, max))
+                .text(format!("Stack {}/{}", depth, max)),
+        )
+    }
+
+    fn draw_opcode_breakpoints(&self, ui: &mut egui::Ui) {
+        egui::CollapsingHeader::new("Break on").show(ui, |ui| {
+            egui::Grid::new("chip8_opcode_breakpoints").show(ui, |ui| {
+                let mut cpu = self.lock_cpu();
+                for (idx, kind) in Instruction::ALL_KINDS.iter().enumerate() {
+                    if idx % 4 == 0 && idx != 0 {
+                        ui.end_row();
+                    }
+
+                    let discriminant = mem::discriminant(kind);
+                    let mut enabled =
+                        cpu.opcode_breakpoints.get(&discriminant) == Some(&BreakAction::Pause);
+                    if ui.checkbox(&mut enabled, kind.mnemonic()).changed() {
+                        if enabled {
+                            cpu.opcode_breakpoints
+                                .insert(discriminant, BreakAction::Pause);
+                        } else {
+                            cpu.opcode_breakpoints.remove(&discriminant);
+                        }
+                    }
+                }
+            });
+        });
+    }
+
+    /// Jump the disassembly and memory views to an address, or move the pc there while paused
+    fn draw_goto(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            let response = ui.add(
+                egui::TextEdit::singleline(&mut self.goto_input)
+                    .hint_text("Address")
+                    .desired_width(80.)
+                    .font(egui::TextStyle::Monospace),
+            );
+            let enter = response.lost_focus() && ui.input().key_pressed(egui::Key::Enter);
+            let goto = ui.button("Go to").clicked() || enter;
+
+            // Only borrows self.cpu, so the other fields can be updated while it's held
+            let mut cpu = lock_timed(&self.cpu, self.lock_waits());
+            let set_pc = cpu.paused && ui.button("Set PC").clicked();
+            if goto || set_pc {
+                match parse_address(&self.goto_input) {
+                    Ok(addr) => {
+                        self.goto_error.clear();
+                        self.follow_pc = false;
+                        self.disasm_offset =
+                            addr.min((MEMORY_SIZE - DISASSEMBLY_LINES as usize * 2) as u16);
+                        self.mem_offset = (addr - addr % MEMORY_ROW_BYTES as u16)
+                            .min((MEMORY_SIZE - MEMORY_ROWS * MEMORY_ROW_BYTES) as u16);
+                        if set_pc {
+                            cpu.pc = addr;
+                        }
+                    }
+                    Err(e) => self.goto_error = e,
+                }
+            }
+        });
+        if !self.goto_error.is_empty() {
+            ui.colored_label(Color32::RED, &self.goto_error);
+        }
+    }
+
+    /// Prompt for monitor commands, e.g. `settimer delay 30`
+    fn draw_monitor(&mut self, ui: &mut egui::Ui) {
+        let response = ui.add(
+            egui::TextEdit::singleline(&mut self.monitor_input)
+                .hint_text("Monitor command")
+                .font(egui::TextStyle::Monospace),
+        );
+        if response.lost_focus() && ui.input().key_pressed(egui::Key::Enter) {
+            let result = self
+                .monitor_input
+                .parse::<Command>()
+                .and_then(|cmd| cmd.run(&mut self.lock_cpu()));
+            self.monitor_output = match result {
+                Ok(out) => out,
+                Err(e) => e,
+            };
+            self.monitor_input.clear();
+        }
+        ui.label(egui::RichText::new(&self.monitor_output).monospace());
+    }
+
+    /// The registers the ROM checks for key presses, and which keyboard key to press for each
+    fn draw_input_checking_state(&self, ui: &mut egui::Ui) {
+        egui::CollapsingHeader::new("Input").show(ui, |ui| {
+            let cpu = self.lock_cpu();
+            if cpu.checked_registers == 0 {
+                ui.label("No keys checked yet");
+            }
+            for reg in (0..16).filter(|r| cpu.checked_registers & (1 << r) != 0) {
+                ui.monospace(checked_register_label(
+                    &self.keymap,
+                    reg,
+                    cpu.reg[reg as usize],
+                ));
+            }
+        });
+    }
+
+    /// The most recently executed instructions, newest at the bottom
+    fn draw_history(&self, ui: &mut egui::Ui) {
+        egui::CollapsingHeader::new("History").show(ui, |ui| {
+            let mut len = self.lock_cpu().history_len();
+            let keep = ui.add(
+                Slider::new(&mut len, 0..=MAX_HISTORY_LEN)
+                    .logarithmic(true)
+                    .text("Instructions kept"),
+            );
+            if keep.changed() {
+                self.lock_cpu().set_history_len(len);
+            }
+            egui::ScrollArea::vertical()
+                .max_height(150.)
+                .stick_to_bottom()
+                .show(ui, |ui| {
+                    let cpu = self.lock_cpu();
+                    for (addr, instr) in &cpu.history {
+                        ui.monospace(format!("{:#05x}: {}", addr, instr));
+                    }
+                });
+        });
+    }
+
+    /// Waveform and pitch of the beep
+    fn draw_sound(&self, ui: &mut egui::Ui) {
+        egui::CollapsingHeader::new("Sound").show(ui, |ui| {
+            let mut cpu = self.lock_cpu();
+            let mut beep = cpu.beep();
+            let mut changed = false;
+            ui.horizontal(|ui| {
+                for option in Waveform::ALL {
+                    changed |= ui
+                        .radio_value(&mut beep.waveform, option, option.to_string())
+                        .changed();
+                }
+            });
+            changed |= ui
+                .add(
+                    Slider::new(&mut beep.freq, 50.0..=2000.0)
+                        .logarithmic(true)
+                        .text("Hz"),
+                )
+                .changed();
+            changed |= ui
+                .add(Slider::new(&mut beep.volume, 0.0..=1.0).text("Volume"))
+                .changed();
+            if changed {
+                cpu.set_beep(beep);
+            }
+        });
+    }
+
+    fn draw_display_settings(&mut self, ui: &mut egui::Ui) {
+        egui::CollapsingHeader::new("Display").show(ui, |ui| {
+            ui.add(Slider::new(&mut self.afterimage.decay, 0.0..=0.95).text("Afterimage"))
+                .on_hover_text("How slowly pixels fade out, to hide flicker. 0 turns it off");
+        });
+    }
+
+    /// Checkboxes for the interpreter quirks, applied to the running CPU straight away
+    fn draw_quirks(&self, ui: &mut egui::Ui) {
+        egui::CollapsingHeader::new("Quirks").show(ui, |ui| {
+            let mut cpu = self.lock_cpu();
+            for (name, enabled) in cpu.quirks.toggles() {
+                ui.checkbox(enabled, name);
+            }
+        });
+    }
+
     fn run_controls(&mut self, ui: &mut egui::Ui) {
-        if let Ok(mut cpu) = self.cpu.lock() {
-            if ui.button("Reset").clicked() {
-                cpu.reset();
+        // Not lock_cpu, which would borrow all of self while we hold the CPU
+        let mut cpu = lock_timed(
+            &self.cpu,
+            self.lock_profiler.as_ref().map(|p| &p.gui_thread),
+        );
+        ui.label(cpu.status().to_string());
+        if ui.button("Reset").clicked() {
+            cpu.reset();
+        }
+        if ui.button("Reset to first draw").clicked() {
+            cpu.reset();
+            if let Err(e) = cpu.run_to_first_draw(FIRST_DRAW_MAX_STEPS) {
+                println!("CPU error: {}", e);
             }
-            ui.checkbox(&mut cpu.paused, "Pause");
-            if cpu.paused {
-                if ui.button("Step").clicked() {
-                    cpu.paused = false;
-                    let _ = cpu.step();
-                    cpu.paused = true;
+        }
+        let mut paused = cpu.paused;
+        if ui.checkbox(&mut paused, "Pause").changed() {
+            cpu.set_paused(paused);
+        }
+        ui.checkbox(&mut cpu.timers_paused, "Pause timers");
+        if let Some(path) = &self.state_path {
+            if ui.button("Save state").clicked() {
+                if let Err(e) = fs::write(path, cpu.save_state()) {
+                    println!("Saving state to {}: {}", path.display(), e);
                 }
-                if ui.button("Step to display update").clicked() {
-                    cpu.paused = false;
-                    while cpu.step() != Ok(StepResult::Continue(true)) {}
-                    cpu.paused = true;
+            }
+            if ui.button("Load state").clicked() {
+                let loaded = fs::read(path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|state| cpu.load_state(&state));
+                match loaded {
+                    Ok(()) => {
+                        // The saved memory is the program now, so analyse it again
+                        self.analyzed_rom.clear();
+                        self.reachable.clear();
+                        self.targets.clear();
+                    }
+                    Err(e) => println!("Loading state from {}: {}", path.display(), e),
                 }
             }
         }
+        if cpu.paused {
+            if ui.button("Step").clicked() {
+                cpu.paused = false;
+                let _ = cpu.step();
+                cpu.paused = true;
+            }
+            if ui.button("Step to display update").clicked() {
+                let _ = cpu.step_to_display_update(FIRST_DRAW_MAX_STEPS);
+            }
+            if ui.button("Previous frame").clicked() {
+                cpu.previous_frame();
+            }
+        }
     }
 }
 
@@ -168,9 +649,12 @@ impl epi::App for Chip8Gui {
     fn setup(
         &mut self,
         ctx: &egui::Context,
-        _frame: &epi::Frame,
+        frame: &epi::Frame,
         _storage: Option<&dyn epi::Storage>,
     ) {
+        if self.frame_limiter.is_some() {
+            self.repaint_timer = Some(RepaintTimer::new(frame.clone()));
+        }
         ctx.set_style(egui::Style {
             visuals: if self.dark_mode {
                 egui::Visuals::dark()
@@ -184,14 +668,16 @@ impl epi::App for Chip8Gui {
 
     fn update(&mut self, ctx: &egui::Context, frame: &epi::Frame) {
         {
-            let chip8_keys = &mut self.io.lock().unwrap().keystate;
+            let keymap = self.keymap;
+            let io = &mut *self.lock_io();
             let pressed_keys = &ctx.input().keys_down;
-            for key in 0..chip8_keys.len() {
-                chip8_keys[key] =
-                    pressed_keys.contains(&key_for_char(KEYPAD_TO_QWERTY[&(key as u8)]).unwrap())
-            }
+            io.set_local_keys(
+                keymap.map(|physical| pressed_keys.contains(&key_for_char(physical).unwrap())),
+            );
         }
 
+        self.handle_navigation(ctx);
+
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.horizontal(|ui| {
                 self.run_controls(ui);
@@ -207,16 +693,212 @@ impl epi::App for Chip8Gui {
             });
             ui.separator();
             ui.horizontal(|ui| {
-                self.chip8_display(ui);
+                ui.vertical(|ui| {
+                    self.chip8_display(ui);
+                    ui.separator();
+                    ui.horizontal(|ui| {
+                        self.draw_disassembly(ui);
+                        ui.separator();
+                        self.draw_memory(ui);
+                    });
+                });
                 ui.vertical(|ui| {
                     self.draw_registers(ui);
+                    self.draw_stack_gauge(ui);
                     ui.separator();
                     self.draw_keypad(ui);
+                    self.draw_input_checking_state(ui);
+                    ui.separator();
+                    self.draw_opcode_breakpoints(ui);
+                    self.draw_quirks(ui);
+                    self.draw_display_settings(ui);
+                    self.draw_sound(ui);
+                    self.draw_history(ui);
+                    ui.separator();
+                    self.draw_goto(ui);
+                    self.draw_lock_stats(ui);
+                    ui.separator();
+                    self.draw_monitor(ui);
                 });
             });
         });
 
-        frame.request_repaint();
+        let paused = {
+            let cpu = self.lock_cpu();
+            cpu.paused && cpu.timers_paused
+        };
+        let input_pending = !ctx.input().keys_down.is_empty() || !ctx.input().events.is_empty();
+        if should_repaint(paused, input_pending) {
+            let now = Instant::now();
+            match (&mut self.frame_limiter, &self.repaint_timer) {
+                // However many DRAWs happen while we wait, they all show up in the next frame
+                (Some(limiter), Some(timer)) => timer.request_at(now + limiter.wait(now)),
+                _ => frame.request_repaint(),
+            }
+        }
+    }
+}
+
+/// Colors of pixels that are (off, on)
+fn pixel_colors(dark_mode: bool, invert: bool) -> (Color32, Color32) {
+    let (off, on) = if dark_mode {
+        (Color32::BLACK, Color32::WHITE)
+    } else {
+        (Color32::WHITE, Color32::BLACK)
+    };
+    if invert {
+        (on, off)
+    } else {
+        (off, on)
+    }
+}
+
+/// The color `level` of the way from `off` to `on`
+fn blend(off: Color32, on: Color32, level: f32) -> Color32 {
+    let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * level).round() as u8;
+    Color32::from_rgb(
+        mix(off.r(), on.r()),
+        mix(off.g(), on.g()),
+        mix(off.b(), on.b()),
+    )
+}
+
+/// Color of pixels that are on in XO-CHIP's second plane, but not the first
+const SECOND_PLANE_COLOR: Color32 = Color32::from_rgb(0xFF, 0x66, 0x00);
+/// Color of pixels that are on in both planes
+const BOTH_PLANES_COLOR: Color32 = Color32::from_rgb(0x99, 0x33, 0x00);
+
+/// Color of a pixel `level` of the way on in the first plane, and on or off in the second. Only
+/// the first plane fades out
+fn plane_color(off: Color32, on: Color32, level: f32, second: bool) -> Color32 {
+    match (level >= 1.0, second) {
+        (true, true) => BOTH_PLANES_COLOR,
+        (false, true) => SECOND_PLANE_COLOR,
+        (_, false) => blend(off, on, level),
+    }
+}
+
+/// Whether to keep repainting continuously. While paused nothing changes on its own, so we leave
+/// it to egui to repaint on input events.
+fn should_repaint(paused: bool, input_pending: bool) -> bool {
+    !paused || input_pending
+}
+
+/// Spaces frames at least `interval` apart
+struct FrameLimiter {
+    interval: Duration,
+    next_frame: Option<Instant>,
+}
+
+impl FrameLimiter {
+    fn new(fps: u32) -> FrameLimiter {
+        FrameLimiter {
+            interval: Duration::from_secs(1) / fps,
+            next_frame: None,
+        }
+    }
+
+    /// How long to wait before drawing a frame requested at `now`
+    fn wait(&mut self, now: Instant) -> Duration {
+        let wait = self
+            .next_frame
+            .map_or(Duration::ZERO, |next| next.saturating_duration_since(now));
+        self.next_frame = Some(now + wait + self.interval);
+        wait
+    }
+}
+
+/// Requests repaints from its own thread, so waiting for one doesn't block the event loop
+struct RepaintTimer(mpsc::Sender<Instant>);
+
+impl RepaintTimer {
+    fn new(frame: epi::Frame) -> RepaintTimer {
+        let (requests, due) = mpsc::channel::<Instant>();
+        // Ends when the GUI drops the sender
+        thread::spawn(move || {
+            for at in due {
+                thread::sleep(at.saturating_duration_since(Instant::now()));
+                frame.request_repaint();
+            }
+        });
+        RepaintTimer(requests)
+    }
+
+    /// Repaint once `at` comes around
+    fn request_at(&self, at: Instant) {
+        // The thread only stops once we're gone
+        let _ = self.0.send(at);
+    }
+}
+
+/// Parse a hex address (with or without 0x) that an instruction could be fetched from
+fn parse_address(s: &str) -> Result<u16, String> {
+    let s = s.trim();
+    let digits = s.strip_prefix("0x").unwrap_or(s);
+    match u16::from_str_radix(digits, 16) {
+        Ok(addr) if (addr as usize) < MEMORY_SIZE - 1 => Ok(addr),
+        Ok(_) => Err(format!("{} is outside memory", s)),
+        Err(_) => Err(format!("Invalid address: {}", s)),
+    }
+}
+
+/// Move a view's start address by `delta` bytes, staying within `0..=max`
+fn scroll_offset(offset: u16, delta: i32, max: u16) -> u16 {
+    (offset as i32 + delta).clamp(0, max as i32) as u16
+}
+
+/// How full the stack is, for the gauge
+fn stack_fraction(depth: usize, max: usize) -> f32 {
+    if max == 0 {
+        1.
+    } else {
+        (depth as f32 / max as f32).min(1.)
+    }
+}
+
+/// What we know about whether the instruction at an address runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Coverage {
+    /// Has run since reset
+    Executed,
+    /// Hasn't run yet, but can
+    Reachable,
+    /// Can't run, as far as static analysis can tell (probably data)
+    Unreachable,
+}
+
+/// Combine static reachability with the addresses that actually ran. Code reached through
+/// `JUMPI` can run without being statically reachable, so having run wins
+fn coverage(addr: u16, reachable: &BTreeSet<u16>, executed: &[bool]) -> Coverage {
+    if executed.get(addr as usize) == Some(&true) {
+        Coverage::Executed
+    } else if reachable.contains(&addr) {
+        Coverage::Reachable
+    } else {
+        Coverage::Unreachable
+    }
+}
+
+/// Bitset of the registers whose value differs between `before` and `after`
+fn changed_registers(before: &[u8], after: &[u8]) -> u64 {
+    before
+        .iter()
+        .zip(after)
+        .enumerate()
+        .filter(|(_, (b, a))| b != a)
+        .fold(0, |changed, (r, _)| changed | 1 << r)
+}
+
+/// Keyboard key to press for CHIP-8 key `key`, if it is one
+fn physical_key(keymap: &Keymap, key: u8) -> Option<char> {
+    keymap.get(key as usize).copied()
+}
+
+/// e.g. "v3 → key C → physical '4'", for register `reg` holding `key`
+fn checked_register_label(keymap: &Keymap, reg: u8, key: u8) -> String {
+    match physical_key(keymap, key) {
+        Some(physical) => format!("v{:X} → key {:X} → physical '{}'", reg, key, physical),
+        None => format!("v{:X} → {:#04x} (not a key)", reg, key),
     }
 }
 
@@ -261,3 +943,119 @@ fn key_for_char(value: char) -> Option<egui::Key> {
         _ => None,
     }
 }
+
+#[test]
+fn stack_gauge_fraction() {
+    assert_eq!(stack_fraction(0, 16), 0.);
+    assert_eq!(stack_fraction(4, 16), 0.25);
+    assert_eq!(stack_fraction(16, 16), 1.);
+    assert_eq!(stack_fraction(20, 16), 1.);
+    assert_eq!(stack_fraction(0, 0), 1.);
+}
+
+#[test]
+fn repaint_when_paused_and_idle() {
+    assert!(!should_repaint(true, false));
+    assert!(should_repaint(true, true));
+    assert!(should_repaint(false, false));
+}
+
+#[test]
+fn scroll_offset_clamps() {
+    assert_eq!(scroll_offset(0x200, 2, 0xFEE), 0x202);
+    assert_eq!(scroll_offset(0x200, -18, 0xFEE), 0x1EE);
+    assert_eq!(scroll_offset(4, -18, 0xFEE), 0);
+    assert_eq!(scroll_offset(0xFEC, 18, 0xFEE), 0xFEE);
+}
+
+#[test]
+fn parse_goto_address() {
+    assert_eq!(parse_address("0x2A0"), Ok(0x2A0));
+    assert_eq!(parse_address(" 2a0 "), Ok(0x2A0));
+    assert_eq!(parse_address("0"), Ok(0));
+    assert_eq!(parse_address("fffe"), Ok(0xFFFE));
+    assert!(parse_address("ffff").is_err());
+    assert!(parse_address("0x10000").is_err());
+    assert!(parse_address("main").is_err());
+    assert!(parse_address("").is_err());
+}
+
+#[test]
+fn checked_register_physical_key() {
+    let keymap = default_keymap();
+    assert_eq!(physical_key(&keymap, 0xC), Some('4'));
+    assert_eq!(physical_key(&keymap, 0x0), Some('X'));
+    assert_eq!(physical_key(&keymap, 0x10), None);
+    assert_eq!(
+        checked_register_label(&keymap, 3, 0xC),
+        "v3 → key C → physical '4'"
+    );
+    assert_eq!(
+        checked_register_label(&keymap, 0xA, 0x42),
+        "vA → 0x42 (not a key)"
+    );
+}
+
+#[test]
+fn register_changes() {
+    let before = [0; 16];
+    let mut after = before;
+    assert_eq!(changed_registers(&before, &after), 0);
+
+    after[0] = 1;
+    after[0xF] = 1;
+    assert_eq!(changed_registers(&before, &after), 0x8001);
+}
+
+#[test]
+fn frame_limiter_spaces_frames() {
+    let mut limiter = FrameLimiter::new(50);
+    let start = Instant::now();
+    let ms = Duration::from_millis;
+
+    assert_eq!(limiter.wait(start), Duration::ZERO);
+    // Requested 5ms later: wait out the rest of the 20ms frame
+    assert_eq!(limiter.wait(start + ms(5)), ms(15));
+    // Next frame is due at 40ms
+    assert_eq!(limiter.wait(start + ms(30)), ms(10));
+    // Requests after the frame was due draw straight away
+    assert_eq!(limiter.wait(start + ms(100)), Duration::ZERO);
+    assert_eq!(limiter.wait(start + ms(101)), ms(19));
+}
+
+#[test]
+fn inverted_pixel_colors() {
+    assert_eq!(pixel_colors(false, false), (Color32::WHITE, Color32::BLACK));
+    assert_eq!(pixel_colors(false, true), (Color32::BLACK, Color32::WHITE));
+    assert_eq!(pixel_colors(true, false), (Color32::BLACK, Color32::WHITE));
+    assert_eq!(pixel_colors(true, true), (Color32::WHITE, Color32::BLACK));
+}
+
+#[test]
+fn four_plane_colors() {
+    let (off, on) = (Color32::BLACK, Color32::WHITE);
+    assert_eq!(plane_color(off, on, 0.0, false), off);
+    assert_eq!(plane_color(off, on, 1.0, false), on);
+    assert_eq!(plane_color(off, on, 0.0, true), SECOND_PLANE_COLOR);
+    assert_eq!(plane_color(off, on, 1.0, true), BOTH_PLANES_COLOR);
+}
+
+#[test]
+fn coverage_classification() {
+    let reachable = BTreeSet::from([0x200, 0x202, 0x204]);
+    let mut executed = [false; 0x300];
+    executed[0x200] = true;
+    executed[0x280] = true;
+
+    assert_eq!(coverage(0x200, &reachable, &executed), Coverage::Executed);
+    assert_eq!(coverage(0x202, &reachable, &executed), Coverage::Reachable);
+    assert_eq!(
+        coverage(0x206, &reachable, &executed),
+        Coverage::Unreachable
+    );
+    assert_eq!(coverage(0x280, &reachable, &executed), Coverage::Executed);
+    assert_eq!(
+        coverage(0xFFE, &reachable, &executed),
+        Coverage::Unreachable
+    );
+}
@@ -1,17 +1,59 @@
 use std::fmt;
 
+use crate::symbols::Symbols;
+
 pub type Addr = u16;
 // type MemVal = u16;
 pub type Reg = u8;
 pub type RegVal = u8;
 pub type ShortVal = u8;
 
-#[derive(Debug, Copy, Clone)]
+/// Why memory doesn't decode to an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chip8Error {
+    /// No instruction has this opcode
+    InvalidOpcode(u16),
+    /// `LOADILONG` (F000) without the address word after it
+    MissingAddress,
+    /// Less than a word of memory left
+    Truncated,
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Chip8Error::InvalidOpcode(x) => write!(f, "Invalid Instruction: {:#x}", x),
+            Chip8Error::MissingAddress => write!(f, "LOADILONG is missing its address"),
+            Chip8Error::Truncated => write!(f, "Instruction cut off at end of memory"),
+        }
+    }
+}
+
+impl From<Chip8Error> for String {
+    fn from(e: Chip8Error) -> String {
+        e.to_string()
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Instruction {
     /// Opcode: 00E0
     CLR,
     /// Opcode: 00EE
     RTS,
+    /// Opcode: 00FE (SUPER-CHIP). Switch to the 64x32 display
+    LORES,
+    /// Opcode: 00FF (SUPER-CHIP). Switch to the 128x64 display
+    HIRES,
+    /// Opcode: 00Cn (SUPER-CHIP). Scroll the display down n pixels
+    SCD(ShortVal),
+    /// Opcode: 00FB (SUPER-CHIP). Scroll the display right 4 pixels
+    SCR,
+    /// Opcode: 00FC (SUPER-CHIP). Scroll the display left 4 pixels
+    SCL,
+    /// Opcode: Fn01 (XO-CHIP). Select the display planes drawn to, bit 0 for the first
+    PLANE(ShortVal),
 
     /// Opcode: Dxyn
     DRAW(ShortVal, Reg, Reg),
@@ -24,6 +66,8 @@ pub enum Instruction {
     CALL(Addr),
     /// Opcode: Annn
     LOADI(Addr),
+    /// Opcode: F000 nnnn (XO-CHIP). Two words long, the address is the second
+    LOADILONG(Addr),
     /// Opcode: Bnnn
     JUMPI(Addr),
 
@@ -56,6 +100,8 @@ pub enum Instruction {
     SUB(Reg, Reg),
     /// Opcode: 8xy6
     SHR(Reg, Reg),
+    /// Opcode: 8xy7
+    SUBN(Reg, Reg),
     /// Opcode: 8xyE
     SHL(Reg, Reg),
 
@@ -83,53 +129,225 @@ pub enum Instruction {
     READ(Reg),
 }
 
-impl fmt::Display for Instruction {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl Instruction {
+    /// One instruction of every kind, with zeroed operands
+    pub const ALL_KINDS: [Instruction; 42] = {
+        use Instruction::*;
+        [
+            CLR,
+            RTS,
+            LORES,
+            HIRES,
+            SCD(0),
+            SCR,
+            SCL,
+            PLANE(0),
+            DRAW(0, 0, 0),
+            SYS(0),
+            JUMP(0),
+            CALL(0),
+            LOADI(0),
+            LOADILONG(0),
+            JUMPI(0),
+            SKE(0, 0),
+            SKNE(0, 0),
+            LOAD(0, 0),
+            ADD(0, 0),
+            RAND(0, 0),
+            SKRE(0, 0),
+            SKRNE(0, 0),
+            MOVE(0, 0),
+            OR(0, 0),
+            AND(0, 0),
+            XOR(0, 0),
+            ADDR(0, 0),
+            SUB(0, 0),
+            SHR(0, 0),
+            SUBN(0, 0),
+            SHL(0, 0),
+            SKPR(0),
+            SKUP(0),
+            MOVED(0),
+            KEYD(0),
+            LOADD(0),
+            LOADS(0),
+            ADDI(0),
+            LDSPR(0),
+            BCD(0),
+            STOR(0),
+            READ(0),
+        ]
+    };
+
+    pub fn mnemonic(&self) -> &'static str {
         use Instruction::*;
         match self {
-            CLR => write!(f, "CLR"),
-            RTS => write!(f, "RTS"),
-
-            DRAW(x, y, n) => write!(f, "DRAW  v{:X}, v{:X}, {:#x}", x, y, n),
-
-            SYS(addr) => write!(f, "SYS   {:#x}", addr),
-            JUMP(addr) => write!(f, "JUMP  {:#x}", addr),
-            CALL(addr) => write!(f, "CALL  {:#x}", addr),
-            LOADI(addr) => write!(f, "LOADI {:#x}", addr),
-            JUMPI(addr) => write!(f, "JUMPI {:#x}", addr),
-
-            SKE(x, n) => write!(f, "SKE   v{:X}, {:#x}", x, n),
-            SKNE(x, n) => write!(f, "SKNE  v{:X}, {:#x}", x, n),
-            LOAD(x, n) => write!(f, "LOAD  v{:X}, {:#x}", x, n),
-            ADD(x, n) => write!(f, "ADD   v{:X}, {:#x}", x, n),
-            RAND(x, n) => write!(f, "RAND  v{:X}, {:#x}", x, n),
-
-            SKRE(x, y) => write!(f, "SKRE  v{:X}, v{:X}", x, y),
-            SKRNE(x, y) => write!(f, "SKRNE v{:X}, v{:X}", x, y),
-            MOVE(x, y) => write!(f, "MOVE  v{:X}, v{:X}", x, y),
-            OR(x, y) => write!(f, "OR    v{:X}, v{:X}", x, y),
-            AND(x, y) => write!(f, "AND   v{:X}, v{:X}", x, y),
-            XOR(x, y) => write!(f, "XOR   v{:X}, v{:X}", x, y),
-            ADDR(x, y) => write!(f, "ADDR  v{:X}, v{:X}", x, y),
-            SUB(x, y) => write!(f, "SUB   v{:X}, v{:X}", x, y),
-            SHR(x, y) => write!(f, "SHR   v{:X}, v{:X}", x, y),
-            SHL(x, y) => write!(f, "SHL   v{:X}, v{:X}", x, y),
-
-            SKPR(x) => write!(f, "SKPR  v{:X}", x),
-            SKUP(x) => write!(f, "SKUP  v{:X}", x),
-            MOVED(x) => write!(f, "MOVED v{:X}", x),
-            KEYD(x) => write!(f, "KEYD  v{:X}", x),
-            LOADD(x) => write!(f, "LOADD v{:X}", x),
-            LOADS(x) => write!(f, "LOADS v{:X}", x),
-            ADDI(x) => write!(f, "ADDI  v{:X}", x),
-            LDSPR(x) => write!(f, "LDSPR v{:X}", x),
-            BCD(x) => write!(f, "BCD   v{:X}", x),
-            STOR(x) => write!(f, "STOR  v{:X}", x),
-            READ(x) => write!(f, "READ  v{:X}", x),
+            CLR => "CLR",
+            RTS => "RTS",
+            LORES => "LORES",
+            HIRES => "HIRES",
+            SCD(_) => "SCD",
+            SCR => "SCR",
+            SCL => "SCL",
+            PLANE(_) => "PLANE",
+            DRAW(_, _, _) => "DRAW",
+            SYS(_) => "SYS",
+            JUMP(_) => "JUMP",
+            CALL(_) => "CALL",
+            LOADI(_) => "LOADI",
+            LOADILONG(_) => "LOADILONG",
+            JUMPI(_) => "JUMPI",
+            SKE(_, _) => "SKE",
+            SKNE(_, _) => "SKNE",
+            LOAD(_, _) => "LOAD",
+            ADD(_, _) => "ADD",
+            RAND(_, _) => "RAND",
+            SKRE(_, _) => "SKRE",
+            SKRNE(_, _) => "SKRNE",
+            MOVE(_, _) => "MOVE",
+            OR(_, _) => "OR",
+            AND(_, _) => "AND",
+            XOR(_, _) => "XOR",
+            ADDR(_, _) => "ADDR",
+            SUB(_, _) => "SUB",
+            SHR(_, _) => "SHR",
+            SUBN(_, _) => "SUBN",
+            SHL(_, _) => "SHL",
+            SKPR(_) => "SKPR",
+            SKUP(_) => "SKUP",
+            MOVED(_) => "MOVED",
+            KEYD(_) => "KEYD",
+            LOADD(_) => "LOADD",
+            LOADS(_) => "LOADS",
+            ADDI(_) => "ADDI",
+            LDSPR(_) => "LDSPR",
+            BCD(_) => "BCD",
+            STOR(_) => "STOR",
+            READ(_) => "READ",
+        }
+    }
+
+    /// The instruction kind with this mnemonic (operands zeroed), case-insensitive
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Instruction> {
+        Self::ALL_KINDS
+            .into_iter()
+            .find(|i| i.mnemonic().eq_ignore_ascii_case(mnemonic))
+    }
+
+    /// Registers whose value this instruction depends on, including implicit reads (e.g. `V0` for
+    /// `JUMPI`)
+    pub fn reads(&self) -> Vec<Reg> {
+        use Instruction::*;
+        match *self {
+            CLR | RTS | LORES | HIRES | SCD(_) | SCR | SCL | PLANE(_) => vec![],
+            SYS(_) | JUMP(_) | CALL(_) | LOADI(_) | LOADILONG(_) => vec![],
+            JUMPI(_) => vec![0],
+            DRAW(x, y, _) => vec![x, y],
+            SKE(x, _) | SKNE(x, _) | ADD(x, _) => vec![x],
+            LOAD(_, _) | RAND(_, _) => vec![],
+            SKRE(x, y) | SKRNE(x, y) => vec![x, y],
+            MOVE(_, y) => vec![y],
+            OR(x, y) | AND(x, y) | XOR(x, y) | ADDR(x, y) | SUB(x, y) | SUBN(x, y) => vec![x, y],
+            SHR(x, y) | SHL(x, y) => vec![x, y],
+            SKPR(x) | SKUP(x) | LOADD(x) | LOADS(x) | ADDI(x) | LDSPR(x) | BCD(x) => vec![x],
+            MOVED(_) | KEYD(_) => vec![],
+            STOR(x) => (0..=x).collect(),
+            READ(_) => vec![],
+        }
+    }
+
+    /// Registers that this instruction (may) overwrite, including implicit `VF` writes
+    pub fn writes(&self) -> Vec<Reg> {
+        use Instruction::*;
+        match *self {
+            CLR | RTS | LORES | HIRES | SCD(_) | SCR | SCL | PLANE(_) => vec![],
+            SYS(_) | JUMP(_) | CALL(_) | LOADI(_) | LOADILONG(_) | JUMPI(_) => vec![],
+            DRAW(_, _, _) => vec![0xF],
+            SKE(_, _) | SKNE(_, _) | SKRE(_, _) | SKRNE(_, _) => vec![],
+            LOAD(x, _) | ADD(x, _) | RAND(x, _) => vec![x],
+            MOVE(x, _) | OR(x, _) | AND(x, _) | XOR(x, _) => vec![x],
+            ADDR(x, _) | SUB(x, _) | SHR(x, _) | SUBN(x, _) | SHL(x, _) => vec![x, 0xF],
+            MOVED(x) | KEYD(x) => vec![x],
+            SKPR(_) | SKUP(_) | LOADD(_) | LOADS(_) | ADDI(_) | LDSPR(_) | BCD(_) => vec![],
+            STOR(_) => vec![],
+            READ(x) => (0..=x).collect(),
         }
     }
 }
 
+/// Something an instruction operates on, for formatting
+enum Operand {
+    Reg(Reg),
+    Addr(Addr),
+    Imm(u8),
+}
+
+impl Instruction {
+    fn operands(&self) -> Vec<Operand> {
+        use Instruction::*;
+        use Operand::{Addr as A, Imm as I, Reg as R};
+        match *self {
+            CLR | RTS | LORES | HIRES | SCR | SCL => vec![],
+            SCD(n) | PLANE(n) => vec![I(n)],
+            DRAW(x, y, n) => vec![R(x), R(y), I(n)],
+            SYS(addr) | JUMP(addr) | CALL(addr) | LOADI(addr) | LOADILONG(addr) | JUMPI(addr) => {
+                vec![A(addr)]
+            }
+            SKE(x, n) | SKNE(x, n) | LOAD(x, n) | ADD(x, n) | RAND(x, n) => vec![R(x), I(n)],
+            SKRE(x, y)
+            | SKRNE(x, y)
+            | MOVE(x, y)
+            | OR(x, y)
+            | AND(x, y)
+            | XOR(x, y)
+            | ADDR(x, y)
+            | SUB(x, y)
+            | SHR(x, y)
+            | SUBN(x, y)
+            | SHL(x, y) => vec![R(x), R(y)],
+            SKPR(x) | SKUP(x) | MOVED(x) | KEYD(x) | LOADD(x) | LOADS(x) | ADDI(x) | LDSPR(x)
+            | BCD(x) | STOR(x) | READ(x) => vec![R(x)],
+        }
+    }
+
+    /// Like `Display`, with the names from `symbols` in place of registers and addresses
+    pub fn format_with(&self, symbols: &Symbols) -> String {
+        let mut out = String::new();
+        self.write_with(&mut out, Some(symbols))
+            .expect("Writing to a String can't fail");
+        out
+    }
+
+    fn write_with(&self, out: &mut impl fmt::Write, symbols: Option<&Symbols>) -> fmt::Result {
+        let operands = self.operands();
+        if operands.is_empty() {
+            return out.write_str(self.mnemonic());
+        }
+        write!(out, "{:<5}", self.mnemonic())?;
+        for (i, op) in operands.into_iter().enumerate() {
+            out.write_str(if i == 0 { " " } else { ", " })?;
+            let name = symbols.and_then(|symbols| match op {
+                Operand::Reg(r) => symbols.registers.get(&r),
+                Operand::Addr(a) => symbols.addresses.get(&a),
+                Operand::Imm(_) => None,
+            });
+            match (name, op) {
+                (Some(name), _) => out.write_str(name)?,
+                (None, Operand::Reg(r)) => write!(out, "v{:X}", r)?,
+                (None, Operand::Addr(a)) => write!(out, "{:#x}", a)?,
+                (None, Operand::Imm(n)) => write!(out, "{:#x}", n)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_with(f, None)
+    }
+}
+
 fn addr(x: u16) -> Addr {
     x & 0x0FFF
 }
@@ -146,8 +364,60 @@ fn r2(x: u16) -> Reg {
     ((x & 0x00F0) >> 4) as Reg
 }
 
+/// Decode each word of `mem`, which is located at address `base`. A trailing odd byte is ignored.
+/// The address word of a `LOADILONG` is also decoded on its own, like any other word.
+pub fn disassemble(
+    mem: &[u8],
+    base: Addr,
+) -> impl Iterator<Item = (Addr, Result<Instruction, Chip8Error>)> + '_ {
+    (0..mem.len() / 2).map(move |i| (base + 2 * i as Addr, Instruction::decode(&mem[2 * i..])))
+}
+
+/// The bytes of `code`, one instruction after another
+#[cfg(test)]
+pub fn encode(code: &[Instruction]) -> Vec<u8> {
+    code.iter().flat_map(|i| i.to_bytes()).collect()
+}
+
+impl Instruction {
+    /// Decode the instruction at the start of `bytes`, which may be one or two words long
+    pub fn decode(bytes: &[u8]) -> Result<Instruction, Chip8Error> {
+        let word = |i: usize| {
+            bytes
+                .get(i..i + 2)
+                .map(|w| u16::from_be_bytes([w[0], w[1]]))
+        };
+        match word(0) {
+            Some(0xF000) => word(2)
+                .map(Instruction::LOADILONG)
+                .ok_or(Chip8Error::MissingAddress),
+            Some(x) => Instruction::try_from(x),
+            None => Err(Chip8Error::Truncated),
+        }
+    }
+
+    /// Length of the encoded instruction in bytes
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::LOADILONG(_) => 4,
+            _ => 2,
+        }
+    }
+
+    /// The encoded instruction, as it's laid out in memory
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = u16::from(self).to_be_bytes().to_vec();
+        if let Instruction::LOADILONG(addr) = self {
+            bytes.extend(addr.to_be_bytes());
+        }
+        bytes
+    }
+}
+
+/// Decode a single word. `LOADILONG` needs the word after it, so decode it with
+/// `Instruction::decode` instead
 impl TryFrom<u16> for Instruction {
-    type Error = String;
+    type Error = Chip8Error;
 
     fn try_from(x: u16) -> Result<Self, Self::Error> {
         use Instruction::*;
@@ -155,6 +425,11 @@ impl TryFrom<u16> for Instruction {
             0x0000 => match x {
                 0x00E0 => Ok(CLR),
                 0x00EE => Ok(RTS),
+                0x00FE => Ok(LORES),
+                0x00FF => Ok(HIRES),
+                0x00FB => Ok(SCR),
+                0x00FC => Ok(SCL),
+                0x00C0..=0x00CF => Ok(SCD((x & 0x000F) as ShortVal)),
                 _ => Ok(SYS(addr(x))),
             },
             0x1000 => Ok(JUMP(addr(x))),
@@ -163,7 +438,7 @@ impl TryFrom<u16> for Instruction {
             0x4000 => Ok(SKNE(r1(x), imm(x))),
             0x5000 => match x & 0x000F {
                 0x0 => Ok(SKRE(r1(x), r2(x))),
-                _ => Err(format!("Invalid Instruction: {:#x}", x)),
+                _ => Err(Chip8Error::InvalidOpcode(x)),
             },
             0x6000 => Ok(LOAD(r1(x), imm(x))),
             0x7000 => Ok(ADD(r1(x), imm(x))),
@@ -175,12 +450,13 @@ impl TryFrom<u16> for Instruction {
                 0x4 => Ok(ADDR(r1(x), r2(x))),
                 0x5 => Ok(SUB(r1(x), r2(x))),
                 0x6 => Ok(SHR(r1(x), r2(x))),
+                0x7 => Ok(SUBN(r1(x), r2(x))),
                 0xE => Ok(SHL(r1(x), r2(x))),
-                _ => Err(format!("Invalid Instruction: {:#x}", x)),
+                _ => Err(Chip8Error::InvalidOpcode(x)),
             },
             0x9000 => match x & 0x000F {
                 0x0 => Ok(SKRNE(r1(x), r2(x))),
-                _ => Err(format!("Invalid Instruction: {:#x}", x)),
+                _ => Err(Chip8Error::InvalidOpcode(x)),
             },
             0xA000 => Ok(LOADI(addr(x))),
             0xB000 => Ok(JUMPI(addr(x))),
@@ -189,9 +465,11 @@ impl TryFrom<u16> for Instruction {
             0xE000 => match x & 0x00FF {
                 0x9E => Ok(SKPR(r1(x))),
                 0xA1 => Ok(SKUP(r1(x))),
-                _ => Err(format!("Invalid Instruction: {:#x}", x)),
+                _ => Err(Chip8Error::InvalidOpcode(x)),
             },
             0xF000 => match x & 0x00FF {
+                0x00 if x == 0xF000 => Err(Chip8Error::MissingAddress),
+                0x01 => Ok(PLANE(r1(x))),
                 0x07 => Ok(MOVED(r1(x))),
                 0x0A => Ok(KEYD(r1(x))),
                 0x15 => Ok(LOADD(r1(x))),
@@ -201,19 +479,27 @@ impl TryFrom<u16> for Instruction {
                 0x33 => Ok(BCD(r1(x))),
                 0x55 => Ok(STOR(r1(x))),
                 0x65 => Ok(READ(r1(x))),
-                _ => Err(format!("Invalid Instruction: {:#x}", x)),
+                _ => Err(Chip8Error::InvalidOpcode(x)),
             },
-            _ => Err(format!("Invalid Instruction: {:#x}", x)),
+            _ => Err(Chip8Error::InvalidOpcode(x)),
         }
     }
 }
 
+/// The first word of the instruction. `LOADILONG`'s address is in a second word, see
+/// `Instruction::to_bytes`
 impl From<Instruction> for u16 {
     fn from(instr: Instruction) -> Self {
         use Instruction::*;
         match instr {
             CLR => 0x00E0,
             RTS => 0x00EE,
+            LORES => 0x00FE,
+            HIRES => 0x00FF,
+            SCD(n) => 0x00C0 | ((n as u16) & 0x000F),
+            SCR => 0x00FB,
+            SCL => 0x00FC,
+            PLANE(n) => 0xF001 | 0x0F00 & ((n as u16) << 8),
 
             DRAW(x, y, n) => {
                 0xD000
@@ -222,10 +508,11 @@ impl From<Instruction> for u16 {
                     | ((n as u16) & 0x000F)
             }
 
-            SYS(addr) => 0x0000 | (addr & 0x0FFF),
+            SYS(addr) => addr & 0x0FFF,
             JUMP(addr) => 0x1000 | (addr & 0x0FFF),
             CALL(addr) => 0x2000 | (addr & 0x0FFF),
             LOADI(addr) => 0xA000 | (addr & 0x0FFF),
+            LOADILONG(_) => 0xF000,
             JUMPI(addr) => 0xB000 | (addr & 0x0FFF),
 
             SKE(r, v) => 0x3000 | 0x0F00 & ((r as u16) << 8) | (0x00FF & v as u16),
@@ -234,16 +521,17 @@ impl From<Instruction> for u16 {
             ADD(r, v) => 0x7000 | 0x0F00 & ((r as u16) << 8) | (0x00FF & v as u16),
             RAND(r, v) => 0xC000 | 0x0F00 & ((r as u16) << 8) | (0x00FF & v as u16),
 
-            SKRE(r1, r2) => 0x5000 | 0x0F00 & ((r1 as u16) << 8) | (0x00F0 & r2 as u16),
-            SKRNE(r1, r2) => 0x9000 | 0x0F00 & ((r1 as u16) << 8) | (0x00F0 & r2 as u16),
-            MOVE(r1, r2) => 0x8000 | 0x0F00 & ((r1 as u16) << 8) | (0x00F0 & r2 as u16),
-            OR(r1, r2) => 0x8001 | 0x0F00 & ((r1 as u16) << 8) | (0x00F0 & r2 as u16),
-            AND(r1, r2) => 0x8002 | 0x0F00 & ((r1 as u16) << 8) | (0x00F0 & r2 as u16),
-            XOR(r1, r2) => 0x8003 | 0x0F00 & ((r1 as u16) << 8) | (0x00F0 & r2 as u16),
-            ADDR(r1, r2) => 0x8004 | 0x0F00 & ((r1 as u16) << 8) | (0x00F0 & r2 as u16),
-            SUB(r1, r2) => 0x8005 | 0x0F00 & ((r1 as u16) << 8) | (0x00F0 & r2 as u16),
-            SHR(r1, r2) => 0x8006 | 0x0F00 & ((r1 as u16) << 8) | (0x00F0 & r2 as u16),
-            SHL(r1, r2) => 0x800E | 0x0F00 & ((r1 as u16) << 8) | (0x00F0 & r2 as u16),
+            SKRE(r1, r2) => 0x5000 | 0x0F00 & ((r1 as u16) << 8) | (0x00F0 & ((r2 as u16) << 4)),
+            SKRNE(r1, r2) => 0x9000 | 0x0F00 & ((r1 as u16) << 8) | (0x00F0 & ((r2 as u16) << 4)),
+            MOVE(r1, r2) => 0x8000 | 0x0F00 & ((r1 as u16) << 8) | (0x00F0 & ((r2 as u16) << 4)),
+            OR(r1, r2) => 0x8001 | 0x0F00 & ((r1 as u16) << 8) | (0x00F0 & ((r2 as u16) << 4)),
+            AND(r1, r2) => 0x8002 | 0x0F00 & ((r1 as u16) << 8) | (0x00F0 & ((r2 as u16) << 4)),
+            XOR(r1, r2) => 0x8003 | 0x0F00 & ((r1 as u16) << 8) | (0x00F0 & ((r2 as u16) << 4)),
+            ADDR(r1, r2) => 0x8004 | 0x0F00 & ((r1 as u16) << 8) | (0x00F0 & ((r2 as u16) << 4)),
+            SUB(r1, r2) => 0x8005 | 0x0F00 & ((r1 as u16) << 8) | (0x00F0 & ((r2 as u16) << 4)),
+            SHR(r1, r2) => 0x8006 | 0x0F00 & ((r1 as u16) << 8) | (0x00F0 & ((r2 as u16) << 4)),
+            SUBN(r1, r2) => 0x8007 | 0x0F00 & ((r1 as u16) << 8) | (0x00F0 & ((r2 as u16) << 4)),
+            SHL(r1, r2) => 0x800E | 0x0F00 & ((r1 as u16) << 8) | (0x00F0 & ((r2 as u16) << 4)),
 
             SKPR(r) => 0xE09E | 0x0F00 & ((r as u16) << 8),
             SKUP(r) => 0xE0A1 | 0x0F00 & ((r as u16) << 8),
@@ -259,3 +547,167 @@ impl From<Instruction> for u16 {
         }
     }
 }
+
+#[test]
+fn def_use_arithmetic() {
+    use Instruction::*;
+    assert_eq!(ADDR(1, 2).reads(), vec![1, 2]);
+    assert_eq!(ADDR(1, 2).writes(), vec![1, 0xF]);
+    assert_eq!(ADD(3, 0x10).reads(), vec![3]);
+    assert_eq!(ADD(3, 0x10).writes(), vec![3]);
+    assert_eq!(MOVE(4, 5).reads(), vec![5]);
+    assert_eq!(MOVE(4, 5).writes(), vec![4]);
+}
+
+#[test]
+fn def_use_implicit() {
+    use Instruction::*;
+    assert_eq!(JUMPI(0x300).reads(), vec![0]);
+    assert!(JUMPI(0x300).writes().is_empty());
+    assert_eq!(DRAW(1, 2, 5).reads(), vec![1, 2]);
+    assert_eq!(DRAW(1, 2, 5).writes(), vec![0xF]);
+}
+
+#[test]
+fn def_use_memory() {
+    use Instruction::*;
+    assert_eq!(STOR(2).reads(), vec![0, 1, 2]);
+    assert!(STOR(2).writes().is_empty());
+    assert!(READ(2).reads().is_empty());
+    assert_eq!(READ(2).writes(), vec![0, 1, 2]);
+    assert!(CLR.reads().is_empty() && CLR.writes().is_empty());
+}
+
+/// `template` with a few different operands. The match makes sure new variants get covered.
+#[cfg(test)]
+fn with_operands(template: Instruction) -> Vec<Instruction> {
+    use Instruction::*;
+    const REGS: [Reg; 3] = [0, 0x5, 0xF];
+    const VALS: [RegVal; 3] = [0, 0x5A, 0xFF];
+    // Not 0x0E0, 0x0EE, 0x0Cn or 0x0FB to 0x0FF, which are other instructions as SYS calls
+    const ADDRS: [Addr; 3] = [0, 0x2A4, 0xFFF];
+
+    let reg = |f: fn(Reg) -> Instruction| REGS.map(f).to_vec();
+    let addr = |f: fn(Addr) -> Instruction| ADDRS.map(f).to_vec();
+    let reg_val = |f: fn(Reg, RegVal) -> Instruction| {
+        REGS.iter()
+            .flat_map(|&r| VALS.map(|v| f(r, v)))
+            .collect::<Vec<_>>()
+    };
+    let reg_reg = |f: fn(Reg, Reg) -> Instruction| {
+        REGS.iter()
+            .flat_map(|&x| REGS.map(|y| f(x, y)))
+            .collect::<Vec<_>>()
+    };
+    match template {
+        CLR | RTS | LORES | HIRES | SCR | SCL => vec![template],
+        SCD(_) => [0, 1, 0xF].map(SCD).to_vec(),
+        PLANE(_) => [0, 1, 3].map(PLANE).to_vec(),
+        DRAW(..) => REGS
+            .iter()
+            .flat_map(|&x| {
+                REGS.iter()
+                    .flat_map(move |&y| [0, 7, 0xF].map(|n| DRAW(x, y, n)))
+            })
+            .collect(),
+        SYS(_) => addr(SYS),
+        JUMP(_) => addr(JUMP),
+        CALL(_) => addr(CALL),
+        LOADI(_) => addr(LOADI),
+        LOADILONG(_) => [0, 0x1234, 0xFFFF].map(LOADILONG).to_vec(),
+        JUMPI(_) => addr(JUMPI),
+        SKE(..) => reg_val(SKE),
+        SKNE(..) => reg_val(SKNE),
+        LOAD(..) => reg_val(LOAD),
+        ADD(..) => reg_val(ADD),
+        RAND(..) => reg_val(RAND),
+        SKRE(..) => reg_reg(SKRE),
+        SKRNE(..) => reg_reg(SKRNE),
+        MOVE(..) => reg_reg(MOVE),
+        OR(..) => reg_reg(OR),
+        AND(..) => reg_reg(AND),
+        XOR(..) => reg_reg(XOR),
+        ADDR(..) => reg_reg(ADDR),
+        SUB(..) => reg_reg(SUB),
+        SHR(..) => reg_reg(SHR),
+        SUBN(..) => reg_reg(SUBN),
+        SHL(..) => reg_reg(SHL),
+        SKPR(_) => reg(SKPR),
+        SKUP(_) => reg(SKUP),
+        MOVED(_) => reg(MOVED),
+        KEYD(_) => reg(KEYD),
+        LOADD(_) => reg(LOADD),
+        LOADS(_) => reg(LOADS),
+        ADDI(_) => reg(ADDI),
+        LDSPR(_) => reg(LDSPR),
+        BCD(_) => reg(BCD),
+        STOR(_) => reg(STOR),
+        READ(_) => reg(READ),
+    }
+}
+
+#[test]
+fn encoding_round_trips() {
+    for template in Instruction::ALL_KINDS {
+        for instr in with_operands(template) {
+            let bytes = instr.to_bytes();
+            assert_eq!(bytes.len(), instr.size() as usize);
+            assert_eq!(
+                Instruction::decode(&bytes),
+                Ok(instr),
+                "{} encoded as {:02x?}",
+                instr,
+                bytes
+            );
+        }
+    }
+}
+
+#[test]
+fn display_format() {
+    use Instruction::*;
+    assert_eq!(CLR.to_string(), "CLR");
+    assert_eq!(DRAW(1, 0xA, 5).to_string(), "DRAW  v1, vA, 0x5");
+    assert_eq!(LOADI(0x2a0).to_string(), "LOADI 0x2a0");
+    assert_eq!(SKRNE(3, 4).to_string(), "SKRNE v3, v4");
+    assert_eq!(SUBN(0xA, 2).to_string(), "SUBN  vA, v2");
+    assert_eq!(Instruction::try_from(0x8A27), Ok(SUBN(0xA, 2)));
+    assert_eq!(BCD(0xF).to_string(), "BCD   vF");
+    assert_eq!(SCD(4).to_string(), "SCD   0x4");
+    assert_eq!(Instruction::try_from(0x00C4), Ok(SCD(4)));
+    assert_eq!(Instruction::try_from(0x00FB), Ok(SCR));
+    assert_eq!(Instruction::try_from(0xF201), Ok(PLANE(2)));
+    assert_eq!(PLANE(3).to_string(), "PLANE 0x3");
+}
+
+#[test]
+fn decode_loadilong() {
+    use Instruction::*;
+    assert_eq!(
+        Instruction::decode(&[0xF0, 0x00, 0xAB, 0xCD]),
+        Ok(LOADILONG(0xABCD))
+    );
+    assert_eq!(LOADILONG(0xABCD).to_string(), "LOADILONG 0xabcd");
+    assert!(Instruction::decode(&[0xF0, 0x00]).is_err());
+    assert!(Instruction::try_from(0xF000).is_err());
+    assert_eq!(Instruction::decode(&[0xA2, 0x34, 0xF0]), Ok(LOADI(0x234)));
+    assert!(Instruction::decode(&[0xA2]).is_err());
+
+    let words: Vec<_> = disassemble(&[0xF0, 0x00, 0x12, 0x34, 0x00, 0xE0], 0x200).collect();
+    assert_eq!(words[0], (0x200, Ok(LOADILONG(0x1234))));
+    assert_eq!(words[2], (0x204, Ok(CLR)));
+}
+
+#[test]
+fn format_with_symbols() {
+    use Instruction::*;
+    let symbols: Symbols = "score = v3\nmain_loop = 0x2A0".parse().unwrap();
+    assert_eq!(ADD(3, 1).format_with(&symbols), "ADD   score, 0x1");
+    assert_eq!(SKRE(3, 4).format_with(&symbols), "SKRE  score, v4");
+    assert_eq!(JUMP(0x2A0).format_with(&symbols), "JUMP  main_loop");
+    assert_eq!(JUMP(0x2A2).format_with(&symbols), "JUMP  0x2a2");
+
+    // Without symbols it's the same as Display
+    let draw = DRAW(1, 0xA, 5);
+    assert_eq!(draw.format_with(&Symbols::default()), draw.to_string());
+}
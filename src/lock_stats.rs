@@ -0,0 +1,76 @@
+use std::fmt::{self, Display};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Running totals of how long one thread waited to take locks
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WaitStats {
+    pub count: u32,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+impl WaitStats {
+    pub fn record(&mut self, wait: Duration) {
+        self.count += 1;
+        self.total += wait;
+        self.max = self.max.max(wait);
+    }
+
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            Duration::ZERO
+        } else {
+            self.total / self.count
+        }
+    }
+}
+
+impl Display for WaitStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} locks | mean {:?} | max {:?}",
+            self.count,
+            self.mean(),
+            self.max
+        )
+    }
+}
+
+/// Lock wait times of the CPU and GUI threads, to measure contention between them
+#[derive(Debug, Default)]
+pub struct LockProfiler {
+    pub cpu_thread: Mutex<WaitStats>,
+    pub gui_thread: Mutex<WaitStats>,
+}
+
+/// Lock `mutex`, adding the time spent waiting for it to `stats` if given
+pub fn lock_timed<'a, T>(
+    mutex: &'a Mutex<T>,
+    stats: Option<&Mutex<WaitStats>>,
+) -> MutexGuard<'a, T> {
+    match stats {
+        None => mutex.lock().unwrap(),
+        Some(stats) => {
+            let start = Instant::now();
+            let guard = mutex.lock().unwrap();
+            stats.lock().unwrap().record(start.elapsed());
+            guard
+        }
+    }
+}
+
+#[test]
+fn wait_stats_accumulate() {
+    let mut stats = WaitStats::default();
+    assert_eq!(stats.mean(), Duration::ZERO);
+
+    for micros in [10, 30, 20] {
+        stats.record(Duration::from_micros(micros));
+    }
+    assert_eq!(stats.count, 3);
+    assert_eq!(stats.total, Duration::from_micros(60));
+    assert_eq!(stats.mean(), Duration::from_micros(20));
+    assert_eq!(stats.max, Duration::from_micros(30));
+}
@@ -1,66 +1,376 @@
 mod analyze;
+mod assembler;
+mod audio;
+mod clock;
+mod condition;
+#[cfg(test)]
+mod conformance;
 mod cpu;
+mod display;
 mod gui;
 mod instruction;
+mod lock_stats;
+mod monitor;
+mod opcode_docs;
+mod quirks;
+mod replay;
+mod rom;
+mod runner;
+mod screenshot;
+mod selftest;
+mod sidecar;
+mod stream;
+mod symbols;
+mod trace;
+#[cfg(feature = "hot-reload")]
+mod watch;
 
-use std::sync::atomic::{self, AtomicU64};
+use std::fs;
+use std::mem;
+use std::net::TcpListener;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::AtomicU64;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Instant;
-use std::{fs, time::Duration};
+use std::time::Duration;
 
-use analyze::analyze;
+use analyze::{analyze, listing, rom_diff, sprites};
 use clap::Parser;
 
-use crate::cpu::{Chip8, Chip8IO, StepResult};
+use crate::audio::{Beep, Waveform, BEEP_FREQ, BEEP_FREQ_RANGE, BEEP_VOLUME};
+use crate::clock::SystemClock;
+use crate::condition::Condition;
+use crate::cpu::{
+    BreakAction, Chip8, Chip8IO, DecodeErrorPolicy, SysPolicy, DEFAULT_FONT_BASE,
+    DEFAULT_HISTORY_LEN, DEFAULT_REGISTERS, FIRST_DRAW_MAX_STEPS, MAX_ROM_SIZE, VIP_FONT_BASE,
+};
+use crate::display::{HIRES_COLS, HIRES_ROWS};
 use crate::gui::Chip8Gui;
-use crate::instruction::Instruction;
+use crate::instruction::{disassemble, Instruction, Reg};
+use crate::lock_stats::LockProfiler;
+use crate::quirks::{PrintDiagnostics, Profile};
+use crate::replay::KeyReplay;
+use crate::symbols::Symbols;
+use crate::trace::{PrintTrace, TraceFilter};
 
-/// Call this in a loop to limit how many times per second the loop runs
-pub fn rate_limit(ticks_per_sec: u64, ticker: &mut Instant) -> (Duration, Duration) {
-    let last_tick = *ticker;
-    let task_end = Instant::now();
-    let busy_elapsed = task_end - *ticker;
-    let target = Duration::from_nanos(1_000_000_000 / ticks_per_sec);
+/// Instructions per second, unless the command line or the ROM's sidecar say otherwise
+const DEFAULT_IPS: u64 = 1000;
 
-    if target > busy_elapsed {
-        thread::sleep(target - busy_elapsed);
+/// Most instructions to run without a window, unless --steps says otherwise
+const DEFAULT_HEADLESS_STEPS: u64 = 1_000_000;
+
+fn parse_register(s: &str) -> Result<Reg, String> {
+    let digits = s.trim_start_matches(['v', 'V']);
+    match u8::from_str_radix(digits, 16) {
+        Ok(reg) if reg < 16 => Ok(reg),
+        _ => Err(format!("Invalid register: {}", s)),
     }
+}
 
-    let loop_end = Instant::now();
-    let full_elapsed = loop_end - last_tick;
+fn load_key_script(path: &str) -> Result<KeyReplay, String> {
+    let script = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    replay::parse_script(&script).map(KeyReplay::new)
+}
 
-    *ticker = loop_end;
+fn load_symbols(path: &str) -> Result<Symbols, String> {
+    fs::read_to_string(path)
+        .map_err(|e| format!("{}: {}", path, e))?
+        .parse()
+}
 
-    (busy_elapsed, full_elapsed)
+fn parse_max_steps(s: &str) -> Result<u64, String> {
+    match s.parse() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(steps) => Ok(steps),
+        Err(_) => Err(format!("Invalid number of steps: {}", s)),
+    }
+}
+
+fn parse_beep_freq(s: &str) -> Result<f32, String> {
+    match s.parse() {
+        Ok(freq) if BEEP_FREQ_RANGE.contains(&freq) => Ok(freq),
+        Ok(_) => Err(format!(
+            "must be between {} and {} Hz",
+            BEEP_FREQ_RANGE.start(),
+            BEEP_FREQ_RANGE.end()
+        )),
+        Err(_) => Err(format!("Invalid frequency: {}", s)),
+    }
+}
+
+fn parse_timeout(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(secs) if secs.is_finite() && secs >= 0.0 => Ok(secs),
+        _ => Err(format!("Invalid number of seconds: {}", s)),
+    }
 }
 
+fn parse_mnemonic(s: &str) -> Result<Instruction, String> {
+    Instruction::from_mnemonic(s).ok_or_else(|| format!("Unknown instruction: {}", s))
+}
+
+// Only ever one of these, parsed once
+#[allow(clippy::large_enum_variant)]
 #[derive(Parser, Debug)]
 enum Args {
     /// What can we learn from the ROM file?
     Analyze {
+        /// Also list every instruction that reads or writes this register (e.g. vA)
+        #[clap(long, parse(try_from_str = parse_register))]
+        register: Option<Reg>,
+
+        /// Print the number of blocks, reachable instructions and branch points
+        #[clap(long)]
+        stats: bool,
+
+        /// Name registers and addresses with the lines in this file (see `dump --symbols`)
+        #[clap(long, parse(try_from_str = load_symbols))]
+        symbols: Option<Symbols>,
+
+        /// Path to the rom file to load
+        rom: String,
+    },
+    /// Print every sprite drawn by the ROM
+    Sprites {
+        /// Also save each sprite as a PNG in this directory, named after its address
+        #[clap(long)]
+        png: Option<String>,
+
+        /// Path to the rom file to load
+        rom: String,
+    },
+    /// List the instructions and data that differ between two versions of a ROM
+    Diff {
+        /// Path to the old version of the rom
+        rom: String,
+
+        /// Path to the new version of the rom
+        new: String,
+    },
+    /// Print the SHA-1 of the ROM, to identify it
+    Hash {
+        /// Path to the rom file to load
+        rom: String,
+    },
+    /// Assemble Octo (.8o) source into a ROM
+    Assemble {
+        /// Where to write the ROM
+        #[clap(long, short)]
+        output: String,
+
+        /// Path to the source file
+        source: String,
+    },
+    /// Run the ROM without a window until a condition holds, printing the number of steps taken
+    Trace {
+        /// Condition to stop at, e.g. "v0 == 5", "pc == 0x2A0" or "stack.len() > 3"
+        #[clap(long)]
+        until: Condition,
+
+        /// Give up after this many steps
+        #[clap(long, default_value_t = 1_000_000)]
+        max_steps: u64,
+
+        /// Print the CPU state after every step
+        #[clap(long)]
+        trace_cpu: bool,
+
+        /// Only trace instructions with this mnemonic (e.g. DRAW), at these addresses (e.g.
+        /// 0x200..0x240) or using this register (e.g. v3)
+        #[clap(long, multiple_occurrences = true, requires = "trace-cpu")]
+        trace_filter: Vec<TraceFilter>,
+
+        /// Replay the key presses in this script (see `replay.rs` for the format)
+        #[clap(long, parse(try_from_str = load_key_script))]
+        keys: Option<KeyReplay>,
+
+        /// Path to the rom file to load
+        rom: String,
+    },
+    /// Run a self-test ROM without a window and check the values it expects (see `selftest.rs`)
+    Check {
+        /// Fail if the program hasn't ended after this many steps
+        #[clap(long, default_value_t = 1_000_000, parse(try_from_str = parse_max_steps))]
+        max_steps: u64,
+
         /// Path to the rom file to load
         rom: String,
     },
     /// Dump instructions
     Dump {
+        /// After each jump, call or skip target, list the instructions that lead to it
+        #[clap(long)]
+        xref: bool,
+
+        /// After each DRAW whose sprite address is known, draw the sprite
+        #[clap(long)]
+        render_sprites: bool,
+
+        /// Name registers and addresses with the `name = v3` / `name = 0x2A0` (or
+        /// `0x2A0 name`) lines in this file
+        #[clap(long, parse(try_from_str = load_symbols))]
+        symbols: Option<Symbols>,
+
         /// Path to the rom file to load
         rom: String,
     },
     /// Run the ROM
     Run {
-        /// Instructions per second
-        #[clap(long, default_value_t = 1000)]
-        ips: u64,
+        /// Instructions per second [default: 1000]
+        #[clap(long)]
+        ips: Option<u64>,
 
         /// Output CPU debug information to the terminal
         #[clap(long)]
         trace_cpu: bool,
 
+        /// Only trace instructions with this mnemonic (e.g. DRAW), at these addresses (e.g.
+        /// 0x200..0x240) or using this register (e.g. v3)
+        #[clap(long, multiple_occurrences = true, requires = "trace-cpu")]
+        trace_filter: Vec<TraceFilter>,
+
+        /// Pitch of the beep, in Hz
+        #[clap(long, default_value_t = BEEP_FREQ, parse(try_from_str = parse_beep_freq))]
+        beep_freq: f32,
+
+        /// Shape of the beep: square, sine or triangle
+        #[clap(long, default_value = "square")]
+        waveform: Waveform,
+
+        /// Loudness of the beep, from 0 to 1
+        #[clap(long, default_value_t = BEEP_VOLUME)]
+        beep_volume: f32,
+
         /// Use dark mode
         #[clap(long)]
         dark_mode: bool,
 
+        /// Draw pixels that are on in the background color, and the rest in the foreground color
+        #[clap(long)]
+        invert: bool,
+
+        /// Repaint at most this many times per second, however often the ROM draws
+        #[clap(long)]
+        max_fps: Option<u32>,
+
+        /// Fill registers that are read before being written with random values from this seed
+        #[clap(long)]
+        uninit_seed: Option<u64>,
+
+        /// Seed RAND, so it gives the same numbers on every run
+        #[clap(long)]
+        rand_seed: Option<u64>,
+
+        /// Load the font at 0x050 like the COSMAC VIP, instead of at 0x000
+        #[clap(long)]
+        vip_font: bool,
+
+        /// What to do on an invalid instruction: halt, skip-word or skip-byte
+        #[clap(long, default_value = "halt")]
+        on_invalid: DecodeErrorPolicy,
+
+        /// What to do on SYS calls other than SYS 0: error, ignore or end
+        #[clap(long, default_value = "error")]
+        on_sys: SysPolicy,
+
+        /// Stop with an error if the program jumps to an odd address
+        #[clap(long)]
+        strict_alignment: bool,
+
+        /// Stop with an error when ADD, ADDR, SUB or SUBN overflow, instead of wrapping around
+        #[clap(long)]
+        trap_overflow: bool,
+
+        /// Interpreter quirks to emulate: chip8, vip or schip. Known ROMs pick theirs by default
+        #[clap(long)]
+        profile: Option<Profile>,
+
+        /// Print every instruction whose result depends on a quirk, and the setting it ran with
+        #[clap(long)]
+        log_quirks: bool,
+
+        /// Pause whenever an instruction with this mnemonic (e.g. DRAW) is about to execute
+        #[clap(long, multiple_occurrences = true, parse(try_from_str = parse_mnemonic))]
+        break_on: Vec<Instruction>,
+
+        /// Append the CPU state to --breakpoint-log whenever an instruction with this mnemonic is
+        /// about to execute, without pausing
+        #[clap(long, multiple_occurrences = true, requires = "breakpoint-log", parse(try_from_str = parse_mnemonic))]
+        log_on: Vec<Instruction>,
+
+        /// File that --log-on breakpoints append to
+        #[clap(long)]
+        breakpoint_log: Option<String>,
+
+        /// Reload and reset whenever the ROM file changes
+        #[cfg(feature = "hot-reload")]
+        #[clap(long)]
+        watch: bool,
+
+        /// Replay the key presses in this script (see `replay.rs` for the format)
+        #[clap(long, parse(try_from_str = load_key_script))]
+        keys: Option<KeyReplay>,
+
+        /// Keep the display when resetting, to compare runs
+        #[clap(long)]
+        persist_display: bool,
+
+        /// Make KEYD wait for a held key to be released before returning it again, for ROMs
+        /// whose menus move too fast
+        #[clap(long)]
+        keyd_needs_release: bool,
+
+        /// Start with this screen: a 256 byte (64x32) or 1024 byte (128x64) file with one bit per
+        /// pixel, row by row
+        #[clap(long)]
+        display: Option<String>,
+
+        /// Start paused on the first frame the ROM draws, instead of on a blank screen
+        #[clap(long, conflicts_with = "steps")]
+        first_draw: bool,
+
+        /// Run without a window until the program ends, loops or fails, or --steps instructions
+        /// have run or --timeout has passed, then print the registers and why it stopped
+        #[clap(long, conflicts_with = "first-draw")]
+        headless: bool,
+
+        /// Run at most this many instructions without a window, like --headless [default:
+        /// 1000000]
+        #[clap(long)]
+        steps: Option<u64>,
+
+        /// Stop running without a window after this many seconds, like --steps
+        #[clap(long, parse(try_from_str = parse_timeout))]
+        timeout: Option<f64>,
+
+        /// Run without a window, like --headless, and save the display to this PNG file at the
+        /// end
+        #[clap(long)]
+        screenshot: Option<String>,
+
+        /// Show how long the CPU and GUI threads wait for each other's locks
+        #[clap(long)]
+        profile_locks: bool,
+
+        /// Number of executed instructions to keep in the history panel
+        #[clap(long, default_value_t = DEFAULT_HISTORY_LEN)]
+        history: usize,
+
+        /// Number of registers, for experimenting. Instructions can only use the first 16
+        #[clap(long, default_value_t = DEFAULT_REGISTERS)]
+        registers: usize,
+
+        /// Name registers and addresses in the debugger with the lines in this file (see
+        /// `dump --symbols`)
+        #[clap(long, parse(try_from_str = load_symbols))]
+        symbols: Option<Symbols>,
+
+        /// Serve the display over TCP on this address (e.g. 127.0.0.1:6464), taking key presses
+        /// back from the client (see `stream.rs` for the protocol)
+        #[clap(long)]
+        stream: Option<String>,
+
         /// Path to the rom file to load
         rom: String,
     },
@@ -72,10 +382,26 @@ impl Args {
             Args::Analyze { rom, .. } => rom,
             Args::Run { rom, .. } => rom,
             Args::Dump { rom, .. } => rom,
+            Args::Sprites { rom, .. } => rom,
+            Args::Hash { rom, .. } => rom,
+            Args::Diff { rom, .. } => rom,
+            Args::Trace { rom, .. } => rom,
+            Args::Check { rom, .. } => rom,
+            Args::Assemble { source, .. } => source,
         };
 
         println!("Reading file {}", rom);
-        fs::read(&rom).expect("open input file")
+        let bytes = fs::read(rom).expect("open input file");
+        if !matches!(self, Args::Assemble { .. }) && bytes.len() > MAX_ROM_SIZE {
+            println!(
+                "{} is {} bytes, but only {} fit in memory",
+                rom,
+                bytes.len(),
+                MAX_ROM_SIZE
+            );
+            process::exit(1);
+        }
+        bytes
     }
 }
 
@@ -83,66 +409,315 @@ fn main() {
     let args = Args::parse();
     let instruction_mem: Vec<u8> = args.rom_bytes();
     match args {
-        Args::Dump { .. } => {
-            let instructions = instruction_mem
-                .chunks_exact(2)
-                .into_iter()
-                .map(|a| u16::from_be_bytes([a[0], a[1]]))
-                .map(|x| (x, Instruction::try_from(x)))
-                .collect::<Vec<_>>();
-
+        Args::Dump {
+            xref,
+            render_sprites,
+            symbols,
+            ..
+        } => {
             println!("Initial RAM: ");
-            let mut addr = 0x200;
-            for (bits, m_instruction) in instructions {
-                if let Ok(i) = m_instruction {
-                    println!("{:#x}: {:x} - {}", addr, bits, i);
-                } else {
-                    println!("{:#x}: {:x} - ????", addr, bits);
+            print!(
+                "{}",
+                listing(
+                    &instruction_mem,
+                    xref,
+                    render_sprites,
+                    &symbols.unwrap_or_default()
+                )
+            );
+        }
+
+        Args::Assemble { output, .. } => {
+            let source = String::from_utf8(instruction_mem).expect("source is not UTF-8");
+            match assembler::octo::assemble(&source) {
+                Ok(rom) => fs::write(output, rom).expect("write output file"),
+                Err(e) => println!("Assembly failed: {}", e),
+            }
+        }
+
+        Args::Trace {
+            until,
+            max_steps,
+            trace_cpu,
+            trace_filter,
+            keys,
+            ..
+        } => {
+            let io = Arc::new(Mutex::new(Chip8IO::new()));
+            let mut chip8 = Chip8::new(&instruction_mem, io, false);
+            chip8.key_replay = keys;
+            if trace_cpu {
+                chip8.trace = Some(Box::new(PrintTrace));
+                chip8.trace_filters = trace_filter;
+            }
+            let result = chip8.run_until(|cpu| until.holds(cpu), max_steps, |_| ());
+            match result {
+                Ok(Some(steps)) => println!("Condition met after {} steps", steps),
+                Ok(None) => println!("Condition not met"),
+                Err(e) => println!("CPU error: {}", e),
+            }
+        }
+
+        Args::Check { max_steps, .. } => {
+            let assertions = match selftest::parse_trailer(&instruction_mem) {
+                Ok(Some(assertions)) => assertions,
+                Ok(None) => {
+                    println!("Not a self-test ROM (no CHK8 trailer)");
+                    process::exit(2);
+                }
+                Err(e) => {
+                    println!("Invalid self-test trailer: {}", e);
+                    process::exit(2);
+                }
+            };
+            let io = Arc::new(Mutex::new(Chip8IO::new()));
+            let mut chip8 = Chip8::new(&instruction_mem, io, false);
+            match selftest::run(&mut chip8, &assertions, max_steps) {
+                Ok(failures) if failures.is_empty() => {
+                    println!("PASS ({} assertions)", assertions.len())
+                }
+                Ok(failures) => {
+                    for failure in &failures {
+                        println!("{}", failure);
+                    }
+                    println!("FAIL ({}/{} assertions)", failures.len(), assertions.len());
+                    process::exit(1);
                 }
+                Err(e) => {
+                    println!("FAIL: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
 
-                addr += 2;
+        Args::Diff { new, .. } => {
+            let new_mem = fs::read(&new).expect("open new rom file");
+            let changes = rom_diff(&instruction_mem, &new_mem);
+            for change in &changes {
+                println!("{}", change);
+            }
+            println!("{} words changed", changes.len());
+        }
+
+        Args::Hash { .. } => {
+            println!("{}", rom::hash(&instruction_mem));
+        }
+
+        Args::Sprites { png, .. } => {
+            for sprite in sprites(&instruction_mem) {
+                println!("{:#x} ({} rows):", sprite.addr, sprite.height);
+                println!("{}", sprite.render(&instruction_mem));
+                if let Some(dir) = &png {
+                    let path = format!("{}/sprite_{:03x}.png", dir, sprite.addr);
+                    if let Err(e) = screenshot::save_sprite_png(&sprite, &instruction_mem, &path) {
+                        println!("Saving sprite: {}", e);
+                    }
+                }
             }
         }
 
         Args::Run {
             trace_cpu,
+            trace_filter,
             ips,
             dark_mode,
+            beep_freq,
+            waveform,
+            beep_volume,
+            max_fps,
+            invert,
+            uninit_seed,
+            rand_seed,
+            break_on,
+            log_on,
+            breakpoint_log,
+            on_invalid,
+            strict_alignment,
+            trap_overflow,
+            on_sys,
+            vip_font,
+            profile,
+            log_quirks,
+            profile_locks,
+            history,
+            registers,
+            symbols,
+            stream,
+            persist_display,
+            keyd_needs_release,
+            display,
+            first_draw,
+            headless,
+            steps,
+            timeout,
+            screenshot,
+            keys,
+            #[cfg(feature = "hot-reload")]
+            watch,
+            ref rom,
             ..
         } => {
+            let config = match sidecar::load(rom) {
+                Ok(config) => config,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            };
+            let ips = ips.or(config.ips).unwrap_or(DEFAULT_IPS);
             let io = Arc::new(Mutex::new(Chip8IO::new()));
-            let cpu = Arc::new(Mutex::new(Chip8::new(&instruction_mem, io.clone(), true)));
+            let font_base = if vip_font {
+                VIP_FONT_BASE
+            } else {
+                DEFAULT_FONT_BASE
+            };
+            let mut chip8 = Chip8::builder(&instruction_mem, io.clone())
+                .rom_config(&config)
+                .paused(true)
+                .with_font(Some(font_base))
+                .history_len(history)
+                .registers(registers)
+                .build();
+            // After building, which resets the display
+            if let Some(path) = display {
+                let bitmap = fs::read(&path).expect("open display file");
+                let mut io = io.lock().unwrap();
+                // A full high resolution screen starts us in high resolution
+                io.set_hires(bitmap.len() == HIRES_ROWS * HIRES_COLS / 8);
+                if let Err(e) = io.set_display_from_bitmap(&bitmap) {
+                    println!("{}: {}", path, e);
+                    return;
+                }
+            }
+            if let Some(seed) = uninit_seed {
+                chip8.randomize_uninit_regs(seed);
+            }
+            if let Some(seed) = rand_seed {
+                chip8.seed_rand(seed);
+            }
+            chip8.decode_error_policy = on_invalid;
+            chip8.strict_alignment = strict_alignment;
+            chip8.trap_overflow = trap_overflow;
+            chip8.symbols = symbols.unwrap_or_default();
+            chip8.sys_policy = on_sys;
+            chip8.persist_display = persist_display;
+            chip8.keyd_needs_release = keyd_needs_release;
+            chip8.key_replay = keys;
+            if trace_cpu {
+                chip8.trace = Some(Box::new(PrintTrace));
+                chip8.trace_filters = trace_filter;
+            }
+            chip8.set_beep(Beep {
+                freq: beep_freq,
+                waveform,
+                volume: beep_volume.clamp(0.0, 1.0),
+            });
+            if let Some(profile) = profile {
+                chip8.quirks = profile.quirks();
+            }
+            if log_quirks {
+                chip8.diagnostics = Some(Box::new(PrintDiagnostics));
+            }
+            #[cfg(feature = "audio")]
+            match audio::CpalSink::new() {
+                Ok(sink) => chip8.audio = Some(Box::new(sink)),
+                Err(e) => println!("No sound: {}", e),
+            }
+            for instr in &break_on {
+                chip8
+                    .opcode_breakpoints
+                    .insert(mem::discriminant(instr), BreakAction::Pause);
+            }
+            for instr in &log_on {
+                chip8
+                    .opcode_breakpoints
+                    .insert(mem::discriminant(instr), BreakAction::Log);
+            }
+            if let Some(path) = breakpoint_log {
+                let log = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .expect("open breakpoint log");
+                chip8.breakpoint_log = Some(log);
+            }
+            if profile_locks {
+                chip8.lock_profiler = Some(Arc::new(LockProfiler::default()));
+            }
+            if headless || steps.is_some() || timeout.is_some() || screenshot.is_some() {
+                chip8.set_paused(false);
+                let steps = steps.unwrap_or(DEFAULT_HEADLESS_STEPS);
+                let timeout = timeout.map(Duration::from_secs_f64);
+                let ran = runner::run_headless(&mut chip8, steps, ips, timeout);
+                println!("{}", runner::headless_summary(&chip8, ran, steps));
+                if let Some(path) = screenshot {
+                    let display = io.lock().unwrap().display;
+                    if let Err(e) = screenshot::save_png(&display, &path) {
+                        println!("Saving screenshot: {}", e);
+                    }
+                }
+                return;
+            }
+
+            if first_draw {
+                match chip8.run_to_first_draw(FIRST_DRAW_MAX_STEPS) {
+                    Ok(true) => {}
+                    Ok(false) => println!("Nothing drawn, paused at {:#x}", chip8.pc),
+                    Err(e) => println!("CPU error: {}", e),
+                }
+            }
+
+            let cpu = Arc::new(Mutex::new(chip8));
             let target_ips = Arc::new(AtomicU64::new(ips));
-            let gui = Chip8Gui::new(cpu.clone(), io.clone(), target_ips.clone(), dark_mode);
+            let mut gui = Chip8Gui::new(
+                cpu.clone(),
+                io.clone(),
+                target_ips.clone(),
+                dark_mode,
+                invert,
+                max_fps,
+            );
+            if let Some(keymap) = config.keymap {
+                gui.set_keymap(keymap);
+            }
+            gui.set_state_path(Path::new(rom).with_extension("c8state"));
 
-            thread::spawn(move || {
-                let mut ticker = Instant::now();
-                loop {
-                    match cpu.lock().unwrap().step() {
-                        Ok(StepResult::Continue(_)) => {}
-                        _ => break,
-                    };
-
-                    if trace_cpu {
-                        println!("{}", cpu.lock().unwrap());
-                    }
+            #[cfg(feature = "hot-reload")]
+            let _watcher = if watch {
+                Some(watch::watch_rom(rom, cpu.clone()).expect("watch rom file"))
+            } else {
+                None
+            };
 
-                    rate_limit(target_ips.load(atomic::Ordering::Relaxed), &mut ticker);
+            if let Some(addr) = stream {
+                match TcpListener::bind(&addr) {
+                    Ok(listener) => stream::serve(listener, io.clone()),
+                    Err(e) => {
+                        println!("Can't stream the display on {}: {}", addr, e);
+                        return;
+                    }
                 }
+            }
+
+            thread::spawn(move || {
+                runner::run_cpu(cpu, target_ips, &SystemClock);
                 println!("CPU Stopped");
             });
 
             gui.run();
         }
 
-        Args::Analyze { .. } => {
+        Args::Analyze {
+            register,
+            stats,
+            symbols,
+            ..
+        } => {
             analyze(
-                &instruction_mem
-                    .chunks_exact(2)
-                    .into_iter()
-                    .map(|a| u16::from_be_bytes([a[0], a[1]]))
-                    .map(|x| (x, Instruction::try_from(x)))
-                    .collect::<Vec<_>>(),
+                &disassemble(&instruction_mem, 0x200).collect::<Vec<_>>(),
+                register,
+                stats,
+                &symbols.unwrap_or_default(),
             );
         }
     };
@@ -0,0 +1,192 @@
+use std::str::FromStr;
+
+use crate::cpu::{Chip8, RunStatus};
+use crate::symbols::Symbols;
+
+/// Most matches `find` lists
+const MAX_FIND_RESULTS: usize = 16;
+
+/// A command typed into the debugger's monitor prompt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Show whether the CPU is running, and if not, why
+    Status,
+    /// Show the delay and sound timers
+    Timer,
+    /// Overwrite one of the timers
+    SetTimer(Timer, u8),
+    /// List the addresses where this byte sequence appears in memory
+    Find(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timer {
+    Delay,
+    Sound,
+}
+
+impl FromStr for Timer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "delay" => Ok(Timer::Delay),
+            "sound" => Ok(Timer::Sound),
+            _ => Err(format!("Unknown timer {} (expected delay or sound)", s)),
+        }
+    }
+}
+
+/// Parse a byte given in decimal, or in hex with a 0x prefix
+fn parse_byte(s: &str) -> Result<u8, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| format!("Invalid value: {}", s))
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        match words[..] {
+            ["status"] => Ok(Command::Status),
+            ["timer"] => Ok(Command::Timer),
+            ["settimer", timer, value] => Ok(Command::SetTimer(timer.parse()?, parse_byte(value)?)),
+            ["settimer", ..] => Err("Usage: settimer <delay|sound> <value>".to_string()),
+            ["find"] => Err("Usage: find <byte> [<byte>...]".to_string()),
+            ["find", ref bytes @ ..] => Ok(Command::Find(
+                bytes
+                    .iter()
+                    .map(|b| parse_byte(b))
+                    .collect::<Result<_, _>>()?,
+            )),
+            [] => Err("No command".to_string()),
+            [cmd, ..] => Err(format!("Unknown command: {}", cmd)),
+        }
+    }
+}
+
+impl Command {
+    /// Run the command against a paused CPU, returning what to show the user
+    pub fn run(self, cpu: &mut Chip8) -> Result<String, String> {
+        match self {
+            // Only reads memory, so it's fine while running
+            Command::Status => return Ok(cpu.status().to_string()),
+            Command::Find(pattern) => {
+                return Ok(format_matches(&cpu.find_bytes(&pattern), &cpu.symbols))
+            }
+            _ if cpu.status() == RunStatus::Running => {
+                return Err("Pause the CPU first".to_string())
+            }
+            Command::Timer => {}
+            Command::SetTimer(Timer::Delay, value) => cpu.delay = value,
+            Command::SetTimer(Timer::Sound, value) => cpu.sound = value,
+        }
+        Ok(format!("delay: {} | sound: {}", cpu.delay, cpu.sound))
+    }
+}
+
+fn format_matches(addrs: &[u16], symbols: &Symbols) -> String {
+    if addrs.is_empty() {
+        return "Not found".to_string();
+    }
+    let mut out = addrs
+        .iter()
+        .take(MAX_FIND_RESULTS)
+        .map(|&addr| symbols.describe(addr))
+        .collect::<Vec<_>>()
+        .join(" ");
+    if addrs.len() > MAX_FIND_RESULTS {
+        out += &format!(" (and {} more)", addrs.len() - MAX_FIND_RESULTS);
+    }
+    out
+}
+
+#[cfg(test)]
+fn paused_cpu() -> Chip8 {
+    use std::sync::{Arc, Mutex};
+
+    use crate::cpu::Chip8IO;
+
+    Chip8::new(&[], Arc::new(Mutex::new(Chip8IO::new())), true)
+}
+
+#[test]
+fn parse_timer_commands() {
+    assert_eq!("timer".parse(), Ok(Command::Timer));
+    assert_eq!(
+        "settimer delay 30".parse(),
+        Ok(Command::SetTimer(Timer::Delay, 30))
+    );
+    assert_eq!(
+        " settimer  sound 0x10 ".parse(),
+        Ok(Command::SetTimer(Timer::Sound, 16))
+    );
+    assert!("settimer delay 300".parse::<Command>().is_err());
+    assert!("settimer pc 3".parse::<Command>().is_err());
+    assert!("settimer delay".parse::<Command>().is_err());
+    assert!("frobnicate".parse::<Command>().is_err());
+}
+
+#[test]
+fn settimer_changes_cpu() {
+    let mut cpu = paused_cpu();
+    let out = "settimer delay 30"
+        .parse::<Command>()
+        .unwrap()
+        .run(&mut cpu)
+        .unwrap();
+    assert_eq!(cpu.delay, 30);
+    assert_eq!(out, "delay: 30 | sound: 0");
+
+    "settimer sound 7"
+        .parse::<Command>()
+        .unwrap()
+        .run(&mut cpu)
+        .unwrap();
+    assert_eq!(cpu.sound, 7);
+    assert_eq!(
+        Command::Timer.run(&mut cpu),
+        Ok("delay: 30 | sound: 7".to_string())
+    );
+}
+
+#[test]
+fn monitor_needs_pause() {
+    let mut cpu = paused_cpu();
+    cpu.paused = false;
+    assert!(Command::SetTimer(Timer::Delay, 1).run(&mut cpu).is_err());
+    assert_eq!(cpu.delay, 0);
+    assert_eq!(Command::Status.run(&mut cpu), Ok("Running".to_string()));
+}
+
+#[test]
+fn find_command() {
+    assert_eq!("find 0xF0 144".parse(), Ok(Command::Find(vec![0xF0, 0x90])));
+    assert!("find".parse::<Command>().is_err());
+    assert!("find 0x100".parse::<Command>().is_err());
+
+    let mut cpu = paused_cpu();
+    cpu.paused = false;
+    cpu.mem[0x300..0x302].copy_from_slice(&[0x12, 0x34]);
+    assert_eq!(
+        Command::Find(vec![0x12, 0x34]).run(&mut cpu),
+        Ok("0x300".to_string())
+    );
+    cpu.symbols = "0x300 sprites".parse().unwrap();
+    assert_eq!(
+        Command::Find(vec![0x12, 0x34]).run(&mut cpu),
+        Ok("0x300 (sprites)".to_string())
+    );
+    assert_eq!(
+        Command::Find(vec![0x56, 0x78]).run(&mut cpu),
+        Ok("Not found".to_string())
+    );
+    assert!(Command::Find(vec![0])
+        .run(&mut cpu)
+        .unwrap()
+        .ends_with("more)"));
+}
@@ -0,0 +1,151 @@
+use std::fmt::{self, Display};
+use std::mem;
+
+use crate::instruction::Instruction;
+
+/// What an instruction does, for showing next to the disassembly
+#[derive(Debug, Clone, Copy)]
+pub struct OpcodeDoc {
+    /// Any instruction of the kind being described
+    pub kind: Instruction,
+    /// The opcode, with operands as letters, e.g. `Dxyn`
+    pub pattern: &'static str,
+    pub description: &'static str,
+}
+
+impl Display for OpcodeDoc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}: {}",
+            self.pattern,
+            self.kind.mnemonic(),
+            self.description
+        )
+    }
+}
+
+macro_rules! doc {
+    ($kind:expr, $pattern:literal, $description:literal) => {
+        OpcodeDoc {
+            kind: $kind,
+            pattern: $pattern,
+            description: $description,
+        }
+    };
+}
+
+static DOCS: [OpcodeDoc; Instruction::ALL_KINDS.len()] = {
+    use Instruction::*;
+    [
+        doc!(CLR, "00E0", "clear the display"),
+        doc!(RTS, "00EE", "return from a subroutine"),
+        doc!(LORES, "00FE", "switch to the 64x32 display"),
+        doc!(HIRES, "00FF", "switch to the 128x64 display"),
+        doc!(SCD(0), "00Cn", "scroll the display down n pixels"),
+        doc!(SCR, "00FB", "scroll the display right 4 pixels"),
+        doc!(SCL, "00FC", "scroll the display left 4 pixels"),
+        doc!(
+            PLANE(0),
+            "Fn01",
+            "draw to, clear and scroll the display planes in bitmask n"
+        ),
+        doc!(
+            DRAW(0, 0, 0),
+            "Dxyn",
+            "draw n-byte sprite from I at (Vx,Vy), set VF on collision"
+        ),
+        doc!(SYS(0), "0nnn", "call machine code at nnn"),
+        doc!(JUMP(0), "1nnn", "jump to nnn"),
+        doc!(CALL(0), "2nnn", "call the subroutine at nnn"),
+        doc!(LOADI(0), "Annn", "set I to nnn"),
+        doc!(
+            LOADILONG(0),
+            "F000",
+            "set I to the 16-bit address in the next word"
+        ),
+        doc!(JUMPI(0), "Bnnn", "jump to nnn + V0"),
+        doc!(SKE(0, 0), "3xnn", "skip the next instruction if Vx == nn"),
+        doc!(SKNE(0, 0), "4xnn", "skip the next instruction if Vx != nn"),
+        doc!(LOAD(0, 0), "6xnn", "set Vx to nn"),
+        doc!(ADD(0, 0), "7xnn", "add nn to Vx, without setting VF"),
+        doc!(RAND(0, 0), "Cxnn", "set Vx to a random byte AND nn"),
+        doc!(SKRE(0, 0), "5xy0", "skip the next instruction if Vx == Vy"),
+        doc!(SKRNE(0, 0), "9xy0", "skip the next instruction if Vx != Vy"),
+        doc!(MOVE(0, 0), "8xy0", "set Vx to Vy"),
+        doc!(OR(0, 0), "8xy1", "set Vx to Vx OR Vy"),
+        doc!(AND(0, 0), "8xy2", "set Vx to Vx AND Vy"),
+        doc!(XOR(0, 0), "8xy3", "set Vx to Vx XOR Vy"),
+        doc!(ADDR(0, 0), "8xy4", "add Vy to Vx, set VF on carry"),
+        doc!(
+            SUB(0, 0),
+            "8xy5",
+            "set Vx to Vx - Vy, set VF when there's no borrow"
+        ),
+        doc!(
+            SHR(0, 0),
+            "8xy6",
+            "shift Vx right by one, set VF to the bit shifted out"
+        ),
+        doc!(
+            SUBN(0, 0),
+            "8xy7",
+            "set Vx to Vy - Vx, set VF when there's no borrow"
+        ),
+        doc!(
+            SHL(0, 0),
+            "8xyE",
+            "shift Vx left by one, set VF to the bit shifted out"
+        ),
+        doc!(
+            SKPR(0),
+            "Ex9E",
+            "skip the next instruction if key Vx is pressed"
+        ),
+        doc!(
+            SKUP(0),
+            "ExA1",
+            "skip the next instruction if key Vx isn't pressed"
+        ),
+        doc!(MOVED(0), "Fx07", "set Vx to the delay timer"),
+        doc!(
+            KEYD(0),
+            "Fx0A",
+            "wait for a key press and put the key in Vx"
+        ),
+        doc!(LOADD(0), "Fx15", "set the delay timer to Vx"),
+        doc!(LOADS(0), "Fx18", "set the sound timer to Vx"),
+        doc!(ADDI(0), "Fx1E", "add Vx to I"),
+        doc!(LDSPR(0), "Fx29", "point I at the font sprite for digit Vx"),
+        doc!(
+            BCD(0),
+            "Fx33",
+            "store the decimal digits of Vx at I, I+1 and I+2"
+        ),
+        doc!(STOR(0), "Fx55", "store V0 to Vx in memory starting at I"),
+        doc!(READ(0), "Fx65", "load V0 to Vx from memory starting at I"),
+    ]
+};
+
+/// The description of instructions of the same kind as `instr`, whatever their operands
+pub fn lookup(instr: &Instruction) -> Option<&'static OpcodeDoc> {
+    DOCS.iter()
+        .find(|doc| mem::discriminant(&doc.kind) == mem::discriminant(instr))
+}
+
+#[test]
+fn every_instruction_documented() {
+    for kind in Instruction::ALL_KINDS {
+        let doc = lookup(&kind).unwrap_or_else(|| panic!("{} has no description", kind));
+        // The pattern's fixed digits match the encoding
+        let opcode = format!("{:04X}", u16::from(kind));
+        for (p, o) in doc.pattern.chars().zip(opcode.chars()) {
+            assert!(p.is_lowercase() || p == o, "{} vs {}", doc.pattern, opcode);
+        }
+    }
+
+    assert_eq!(
+        lookup(&Instruction::DRAW(5, 1, 2)).unwrap().to_string(),
+        "Dxyn DRAW: draw n-byte sprite from I at (Vx,Vy), set VF on collision"
+    );
+}
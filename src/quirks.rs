@@ -0,0 +1,203 @@
+use std::fmt::{self, Debug, Display};
+use std::str::FromStr;
+
+use crate::instruction::Instruction;
+
+/// Behaviours that differ between CHIP-8 interpreters. ROMs written for one interpreter may
+/// misbehave on another, so these can be switched to match what the ROM expects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Quirks {
+    /// Pixels that fall off the right or bottom edge of the screen are dropped instead of
+    /// wrapping around to the other side. The start position always wraps.
+    pub clip_sprites: bool,
+    /// `SHR` and `SHL` shift Vy into Vx, instead of shifting Vx in place
+    pub shift_uses_vy: bool,
+    /// `JUMPI` (Bnnn) adds Vx, where x is the high nibble of the address, instead of V0
+    pub jump_with_vx: bool,
+    /// When sprites wrap, pixels that wrapped around to the other side don't set VF when they
+    /// collide. Has no effect with `clip_sprites`
+    pub ignore_wrapped_collisions: bool,
+    /// `OR`, `AND` and `XOR` reset VF to 0
+    pub vf_reset: bool,
+    /// `DRAW` waits for the next 60Hz frame, so at most one sprite is drawn per frame
+    pub display_wait: bool,
+    /// `LORES` and `HIRES` clear the display
+    pub resolution_switch_clears: bool,
+    /// `ADDI` (Fx1E) sets VF to 1 when I goes past 0x0FFF, like the Amiga interpreter.
+    /// Spacefight 2091! relies on this to detect the end of its sprite data
+    pub addi_sets_vf: bool,
+    /// `STOR` and `READ` leave I pointing after the last register they copied, like the COSMAC
+    /// VIP, instead of leaving it unchanged
+    pub load_store_increments_i: bool,
+    /// `SCD`, `SCR` and `SCL` scroll half as far in low resolution, like SUPER-CHIP 1.1, which
+    /// counts in high resolution pixels. Odd amounts round down
+    pub half_scroll_in_lores: bool,
+}
+
+impl Quirks {
+    /// The quirk with this field name, e.g. `clip_sprites`
+    pub fn by_name(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "clip_sprites" => Some(&mut self.clip_sprites),
+            "shift_uses_vy" => Some(&mut self.shift_uses_vy),
+            "jump_with_vx" => Some(&mut self.jump_with_vx),
+            "ignore_wrapped_collisions" => Some(&mut self.ignore_wrapped_collisions),
+            "vf_reset" => Some(&mut self.vf_reset),
+            "display_wait" => Some(&mut self.display_wait),
+            "resolution_switch_clears" => Some(&mut self.resolution_switch_clears),
+            "addi_sets_vf" => Some(&mut self.addi_sets_vf),
+            "load_store_increments_i" => Some(&mut self.load_store_increments_i),
+            "half_scroll_in_lores" => Some(&mut self.half_scroll_in_lores),
+            _ => None,
+        }
+    }
+
+    /// Every quirk with a short name, for building UIs
+    pub fn toggles(&mut self) -> Vec<(&'static str, &mut bool)> {
+        vec![
+            ("Clip sprites", &mut self.clip_sprites),
+            ("Shift Vy into Vx", &mut self.shift_uses_vy),
+            ("Jump with Vx", &mut self.jump_with_vx),
+            (
+                "Ignore wrapped collisions",
+                &mut self.ignore_wrapped_collisions,
+            ),
+            ("Logic ops reset VF", &mut self.vf_reset),
+            ("Wait for display", &mut self.display_wait),
+            (
+                "Resolution switch clears",
+                &mut self.resolution_switch_clears,
+            ),
+            ("ADDI overflow sets VF", &mut self.addi_sets_vf),
+            ("STOR/READ increment I", &mut self.load_store_increments_i),
+            ("Half scroll in low res", &mut self.half_scroll_in_lores),
+        ]
+    }
+}
+
+/// A named set of quirks, matching a particular interpreter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    /// What most CHIP-8 ROMs expect. This is the default
+    Chip8,
+    /// The original COSMAC VIP interpreter
+    Vip,
+    /// SUPER-CHIP (and CHIP-48) on the HP48 calculators
+    SuperChip,
+}
+
+impl Profile {
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Profile::Chip8 => Quirks::default(),
+            Profile::Vip => Quirks {
+                clip_sprites: true,
+                shift_uses_vy: true,
+                jump_with_vx: false,
+                ignore_wrapped_collisions: false,
+                vf_reset: true,
+                display_wait: true,
+                resolution_switch_clears: false,
+                addi_sets_vf: false,
+                load_store_increments_i: true,
+                half_scroll_in_lores: false,
+            },
+            Profile::SuperChip => Quirks {
+                clip_sprites: true,
+                shift_uses_vy: false,
+                jump_with_vx: true,
+                ignore_wrapped_collisions: false,
+                vf_reset: false,
+                display_wait: false,
+                resolution_switch_clears: false,
+                addi_sets_vf: false,
+                load_store_increments_i: false,
+                half_scroll_in_lores: true,
+            },
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chip8" => Ok(Profile::Chip8),
+            "vip" => Ok(Profile::Vip),
+            "schip" => Ok(Profile::SuperChip),
+            _ => Err(format!(
+                "Unknown profile {} (expected chip8, vip or schip)",
+                s
+            )),
+        }
+    }
+}
+
+/// An executed instruction whose result depended on a quirk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuirkUse {
+    pub pc: u16,
+    pub instruction: Instruction,
+    /// Name of the `Quirks` field
+    pub quirk: &'static str,
+    pub enabled: bool,
+}
+
+impl Display for QuirkUse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#05x}: {} ran with {} = {}",
+            self.pc, self.instruction, self.quirk, self.enabled
+        )
+    }
+}
+
+/// Where the CPU reports instructions that behave differently between interpreters
+pub trait DiagnosticSink: Debug + Send {
+    fn quirk_used(&mut self, diagnostic: QuirkUse);
+}
+
+/// Prints diagnostics to the terminal
+#[derive(Debug)]
+pub struct PrintDiagnostics;
+
+impl DiagnosticSink for PrintDiagnostics {
+    fn quirk_used(&mut self, diagnostic: QuirkUse) {
+        println!("{}", diagnostic);
+    }
+}
+
+#[test]
+fn toggles_change_quirks() {
+    let mut quirks = Quirks::default();
+    for (name, enabled) in quirks.toggles() {
+        if name == "Jump with Vx" {
+            *enabled = true;
+        }
+    }
+    assert!(quirks.jump_with_vx);
+    assert!(!quirks.clip_sprites);
+
+    // Every field has a toggle
+    for (_, enabled) in quirks.toggles() {
+        *enabled = true;
+    }
+    assert_eq!(
+        quirks,
+        Quirks {
+            clip_sprites: true,
+            shift_uses_vy: true,
+            jump_with_vx: true,
+            ignore_wrapped_collisions: true,
+            vf_reset: true,
+            display_wait: true,
+            resolution_switch_clears: true,
+            addi_sets_vf: true,
+            load_store_increments_i: true,
+            half_scroll_in_lores: true,
+        }
+    );
+}
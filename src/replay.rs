@@ -0,0 +1,138 @@
+//! Scripted key presses, to drive input-dependent ROMs without a keyboard. A script has one event
+//! per line: the number of instructions executed when it happens, `press` or `release`, and the
+//! CHIP-8 key in hex. Blank lines and lines starting with `#` are ignored.
+//!
+//! ```text
+//! # Start the game, then hold left for a while
+//! 100 press 5
+//! 110 release 5
+//! 500 press 4
+//! 900 release 4
+//! ```
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    /// Instructions executed before the event happens
+    pub step: u64,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+pub fn parse_script(script: &str) -> Result<Vec<KeyEvent>, String> {
+    script
+        .lines()
+        .enumerate()
+        .map(|(n, line)| (n + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(n, line)| parse_event(line).map_err(|e| format!("Line {}: {}", n, e)))
+        .collect()
+}
+
+fn parse_event(line: &str) -> Result<KeyEvent, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let [step, action, key] = words[..] else {
+        return Err("expected <step> <press|release> <key>".to_string());
+    };
+    let step = step
+        .parse()
+        .map_err(|_| format!("Invalid step: {}", step))?;
+    let pressed = match action {
+        "press" => true,
+        "release" => false,
+        _ => return Err(format!("Unknown action: {}", action)),
+    };
+    let key = match u8::from_str_radix(key, 16) {
+        Ok(key) if key < 16 => key,
+        _ => return Err(format!("Invalid key: {}", key)),
+    };
+    Ok(KeyEvent { step, key, pressed })
+}
+
+/// Plays a script back into the keypad as the CPU runs
+#[derive(Debug, Clone)]
+pub struct KeyReplay {
+    /// Sorted by step
+    events: Vec<KeyEvent>,
+    next: usize,
+    steps: u64,
+}
+
+impl KeyReplay {
+    pub fn new(mut events: Vec<KeyEvent>) -> KeyReplay {
+        events.sort_by_key(|e| e.step);
+        KeyReplay {
+            events,
+            next: 0,
+            steps: 0,
+        }
+    }
+
+    /// Start again from the first event
+    pub fn rewind(&mut self) {
+        self.next = 0;
+        self.steps = 0;
+    }
+
+    /// Apply the events due before the next instruction runs, and count it
+    pub fn advance(&mut self, keystate: &mut [bool; 16]) {
+        while let Some(event) = self.events.get(self.next) {
+            if event.step > self.steps {
+                break;
+            }
+            keystate[event.key as usize] = event.pressed;
+            self.next += 1;
+        }
+        self.steps += 1;
+    }
+}
+
+#[test]
+fn parse_sample_script() {
+    let script = "
+        # Start the game
+        100 press 5
+        110 release 5
+
+        500 press A
+    ";
+    assert_eq!(
+        parse_script(script),
+        Ok(vec![
+            KeyEvent {
+                step: 100,
+                key: 5,
+                pressed: true
+            },
+            KeyEvent {
+                step: 110,
+                key: 5,
+                pressed: false
+            },
+            KeyEvent {
+                step: 500,
+                key: 0xA,
+                pressed: true
+            },
+        ])
+    );
+
+    assert_eq!(
+        parse_script("1 press 5\n2 hold 5"),
+        Err("Line 2: Unknown action: hold".to_string())
+    );
+    assert!(parse_script("1 press 10").is_err());
+    assert!(parse_script("press 1").is_err());
+}
+
+#[test]
+fn replay_applies_events_in_order() {
+    let mut replay = KeyReplay::new(parse_script("2 release 1\n1 press 1\n1 press 2").unwrap());
+    let mut keys = [false; 16];
+
+    replay.advance(&mut keys);
+    assert!(!keys[1]);
+    replay.advance(&mut keys);
+    assert!(keys[1] && keys[2]);
+    replay.advance(&mut keys);
+    assert!(!keys[1] && keys[2]);
+}
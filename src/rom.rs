@@ -0,0 +1,26 @@
+use phf::phf_map;
+
+use crate::quirks::Profile;
+
+/// Stable fingerprint of a ROM: the hex SHA-1 of its bytes, as used by the CHIP-8 community
+/// database
+pub fn hash(rom: &[u8]) -> String {
+    sha1_smol::Sha1::from(rom).digest().to_string()
+}
+
+/// ROMs known to need something other than the default profile, by `hash`
+pub const KNOWN_ROMS: phf::Map<&'static str, Profile> = phf_map! {
+  // BLINKY [Hans Christian Egeberg, 1991], written for CHIP-48
+  "d40abc54374e4343639f993e897e00904ddf85d9" => Profile::SuperChip,
+};
+
+/// The profile this ROM was written for, if we know it
+pub fn known_profile(rom: &[u8]) -> Option<Profile> {
+    KNOWN_ROMS.get(hash(rom).as_str()).copied()
+}
+
+#[test]
+fn hash_known_bytes() {
+    assert_eq!(hash(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    assert_eq!(hash(&[]), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+}
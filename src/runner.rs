@@ -0,0 +1,230 @@
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::clock::Clock;
+use crate::cpu::{Chip8, RunStatus, StepResult, TIMER_PERIOD};
+use crate::lock_stats::lock_timed;
+
+/// How many times per second the CPU thread takes the lock, when the target IPS allows it
+const LOCKS_PER_SEC: u64 = 1000;
+
+/// Most instructions run per lock. Past this, higher IPS means taking the lock more often, and we
+/// yield after each batch so the GUI always gets a turn.
+const MAX_STEPS_PER_LOCK: u64 = 100;
+
+/// Instructions to run per lock at this target IPS
+fn steps_per_lock(ips: u64) -> u64 {
+    (ips / LOCKS_PER_SEC).clamp(1, MAX_STEPS_PER_LOCK)
+}
+
+/// Run the CPU at the target IPS until the program ends or fails, ticking its timers at 60Hz
+/// however fast or slow that is
+pub fn run_cpu(cpu: Arc<Mutex<Chip8>>, target_ips: Arc<AtomicU64>, clock: &dyn Clock) {
+    let profiler = {
+        let mut cpu = cpu.lock().unwrap();
+        cpu.external_timers = true;
+        cpu.lock_profiler.clone()
+    };
+    let mut next_frame = clock.now();
+    let mut next_batch = clock.now();
+    loop {
+        let ips = target_ips.load(atomic::Ordering::Relaxed).max(1);
+        let batch = steps_per_lock(ips);
+        let now = clock.now();
+        {
+            let mut cpu = lock_timed(&cpu, profiler.as_ref().map(|p| &p.cpu_thread));
+            while now >= next_frame {
+                cpu.end_frame();
+                next_frame += TIMER_PERIOD;
+            }
+
+            if now >= next_batch {
+                match cpu.step_batch(batch as usize).stopped {
+                    Some(RunStatus::Halted(e)) => {
+                        println!("CPU error: {}", e);
+                        return;
+                    }
+                    Some(_) => return,
+                    None => {}
+                }
+                // Don't run a burst of batches to catch up after falling behind
+                next_batch = (next_batch + batch_period(ips, batch)).max(now);
+            }
+        }
+        thread::yield_now();
+
+        let wake = next_frame.min(next_batch);
+        clock.sleep(wake.saturating_duration_since(clock.now()));
+    }
+}
+
+/// Run up to `steps` instructions as fast as possible, without a window, giving up once
+/// `timeout` has passed. The timers tick as if running at `ips`, so the result doesn't depend on
+/// how fast this machine is. Returns how many instructions ran before the program ended, looped
+/// or failed; `Chip8::status` says which
+pub fn run_headless(cpu: &mut Chip8, steps: u64, ips: u64, timeout: Option<Duration>) -> u64 {
+    cpu.external_timers = true;
+    let steps_per_frame = (ips / 60).max(1);
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    for step in 0..steps {
+        if step.is_multiple_of(steps_per_frame) {
+            // Once a frame, so the clock doesn't slow every instruction down
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return step;
+            }
+            if step > 0 {
+                cpu.end_frame();
+            }
+        }
+        match cpu.step() {
+            Ok(StepResult::Continue(_)) => {}
+            // Nobody is there to resume it
+            Ok(StepResult::Breakpoint) => cpu.set_paused(false),
+            Ok(StepResult::Loop | StepResult::End) | Err(_) => return step + 1,
+        }
+    }
+    steps
+}
+
+/// Why `run_headless` stopped, how many instructions it ran out of `max_steps`, and the CPU
+/// state it left
+pub fn headless_summary(cpu: &Chip8, steps: u64, max_steps: u64) -> String {
+    let reason = match cpu.status() {
+        RunStatus::Running if steps < max_steps => "Reached the time limit".to_string(),
+        RunStatus::Running => "Reached the step limit".to_string(),
+        status => status.to_string(),
+    };
+    format!("{} after {} instructions\n{}", reason, steps, cpu)
+}
+
+/// Time to spend on each batch of `batch` instructions at this IPS
+fn batch_period(ips: u64, batch: u64) -> Duration {
+    Duration::from_nanos((1_000_000_000 * batch as u128 / ips as u128) as u64)
+}
+
+#[test]
+fn steps_per_lock_capped() {
+    assert_eq!(steps_per_lock(1), 1);
+    assert_eq!(steps_per_lock(1000), 1);
+    assert_eq!(steps_per_lock(3000), 3);
+    assert_eq!(steps_per_lock(u64::MAX), MAX_STEPS_PER_LOCK);
+}
+
+#[test]
+fn gui_not_starved_at_high_ips() {
+    use std::time::Instant;
+
+    use crate::clock::SystemClock;
+    use crate::cpu::Chip8IO;
+    use crate::instruction::encode;
+    use crate::instruction::Instruction::*;
+
+    // ADD v0, 1; JUMP 0x200; SYS 0 (to stop the thread at the end)
+    let rom = encode(&[ADD(0, 1), JUMP(0x200), SYS(0)]);
+    let cpu = Arc::new(Mutex::new(Chip8::new(
+        &rom,
+        Arc::new(Mutex::new(Chip8IO::new())),
+        false,
+    )));
+    let runner = {
+        let cpu = cpu.clone();
+        thread::spawn(move || run_cpu(cpu, Arc::new(AtomicU64::new(u64::MAX)), &SystemClock))
+    };
+
+    // Like the GUI, take the lock once per frame
+    let mut worst = Duration::ZERO;
+    for _ in 0..50 {
+        let start = Instant::now();
+        drop(cpu.lock().unwrap());
+        worst = worst.max(start.elapsed());
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    cpu.lock().unwrap().pc = 0x204;
+    runner.join().unwrap();
+    assert!(cpu.lock().unwrap().reg[0] > 0);
+    assert!(worst < Duration::from_millis(100), "waited {:?}", worst);
+}
+
+#[test]
+fn timers_run_at_60hz_at_any_ips() {
+    use crate::clock::ManualClock;
+    use crate::cpu::Chip8IO;
+    use crate::instruction::encode;
+    use crate::instruction::Instruction::*;
+
+    // Wait for the delay timer to run out from 60, then stop
+    let rom = encode(&[
+        LOAD(0, 60),
+        LOADD(0),
+        MOVED(1),
+        SKE(1, 0),
+        JUMP(0x204),
+        SYS(0),
+    ]);
+    for ips in [60, 1000, 100_000] {
+        let clock = ManualClock::new();
+        let cpu = Chip8::builder(&rom, Arc::new(Mutex::new(Chip8IO::new())))
+            .clock(Box::new(clock.clone()))
+            .build();
+        let start = clock.now();
+        run_cpu(
+            Arc::new(Mutex::new(cpu)),
+            Arc::new(AtomicU64::new(ips)),
+            &clock,
+        );
+
+        // The timer runs out after a second whatever the IPS. At low IPS it takes a few more
+        // instructions to notice
+        let elapsed = clock.now() - start;
+        let slack = Duration::from_secs(5) / ips as u32;
+        assert!(
+            elapsed >= Duration::from_millis(980) && elapsed <= Duration::from_secs(1) + slack,
+            "{} IPS took {:?}",
+            ips,
+            elapsed
+        );
+    }
+}
+
+#[test]
+fn headless_run_reports_exit_reason() {
+    use crate::cpu::Chip8IO;
+    use crate::instruction::encode;
+    use crate::instruction::Instruction::*;
+
+    let run = |code: &[crate::instruction::Instruction], steps| {
+        let rom = encode(code);
+        let mut cpu = Chip8::new(&rom, Arc::new(Mutex::new(Chip8IO::new())), false);
+        let ran = run_headless(&mut cpu, steps, 1000, None);
+        (cpu, ran)
+    };
+
+    let (cpu, ran) = run(&[LOAD(0, 7), JUMP(0x202)], 100);
+    assert_eq!((cpu.status(), ran), (RunStatus::Looping, 2));
+    assert_eq!(cpu.reg[0], 7);
+    assert!(headless_summary(&cpu, ran, 100).starts_with("Looping after 2 instructions\n"));
+
+    let (cpu, ran) = run(&[LOAD(0, 7), SYS(0)], 100);
+    assert_eq!((cpu.status(), ran), (RunStatus::Ended, 2));
+
+    let (cpu, ran) = run(&[RTS], 100);
+    assert_eq!(
+        cpu.status(),
+        RunStatus::Halted("Return from empty stack".to_string())
+    );
+    assert_eq!(ran, 1);
+
+    let (cpu, ran) = run(&[ADD(0, 1), JUMP(0x200)], 10);
+    assert_eq!((cpu.status(), ran), (RunStatus::Running, 10));
+    assert!(headless_summary(&cpu, ran, 10).starts_with("Reached the step limit after 10"));
+
+    // Out of time before the first instruction
+    let rom = encode(&[ADD(0, 1), JUMP(0x200)]);
+    let mut cpu = Chip8::new(&rom, Arc::new(Mutex::new(Chip8IO::new())), false);
+    let ran = run_headless(&mut cpu, 10, 1000, Some(Duration::ZERO));
+    assert_eq!((cpu.status(), ran), (RunStatus::Running, 0));
+    assert!(headless_summary(&cpu, ran, 10).starts_with("Reached the time limit after 0"));
+}
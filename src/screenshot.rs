@@ -0,0 +1,102 @@
+use std::fs;
+use std::io::Write;
+
+use crate::analyze::Sprite;
+use crate::display::Framebuffer;
+
+/// Encode `pixels`, row by row, as a `width` by `height` greyscale PNG, white where they're on
+fn write_pixels(
+    width: u32,
+    height: u32,
+    pixels: impl Iterator<Item = bool>,
+    out: impl Write,
+) -> Result<(), String> {
+    let mut encoder = png::Encoder::new(out, width, height);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let pixels: Vec<u8> = pixels.map(|on| if on { 0xFF } else { 0 }).collect();
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&pixels))
+        .map_err(|e| e.to_string())
+}
+
+/// Encode the display as a greyscale PNG, one image pixel per display pixel, white where it's on
+pub fn write_png(display: &Framebuffer, out: impl Write) -> Result<(), String> {
+    write_pixels(
+        display.width() as u32,
+        display.height() as u32,
+        display.rows().flatten(),
+        out,
+    )
+}
+
+pub fn save_png(display: &Framebuffer, path: &str) -> Result<(), String> {
+    let file = fs::File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+    write_png(display, file)
+}
+
+/// Encode a sprite from `rom` as an 8 pixel wide greyscale PNG, like `write_png`
+pub fn write_sprite_png(sprite: &Sprite, rom: &[u8], out: impl Write) -> Result<(), String> {
+    let rows = sprite.rows(rom);
+    let pixels = rows
+        .iter()
+        .flat_map(|byte| (0..8).map(move |bitidx| byte & (0x80 >> bitidx) != 0));
+    write_pixels(8, rows.len() as u32, pixels, out)
+}
+
+pub fn save_sprite_png(sprite: &Sprite, rom: &[u8], path: &str) -> Result<(), String> {
+    let file = fs::File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+    write_sprite_png(sprite, rom, file)
+}
+
+#[test]
+fn png_matches_display() {
+    use std::sync::{Arc, Mutex};
+
+    use crate::cpu::{Chip8, Chip8IO};
+    use crate::runner::run_headless;
+
+    let mut cpu = Chip8::new(
+        include_bytes!("../GAMES/TEST/IBM.ch8"),
+        Arc::new(Mutex::new(Chip8IO::new())),
+        false,
+    );
+    run_headless(&mut cpu, 1000, 1000, None);
+    let display = cpu.io.lock().unwrap().display;
+
+    let mut png = Vec::new();
+    write_png(&display, &mut png).unwrap();
+
+    let mut reader = png::Decoder::new(&png[..]).read_info().unwrap();
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels).unwrap();
+    assert_eq!((info.width, info.height), (64, 32));
+    let decoded: Vec<bool> = pixels.iter().map(|&p| p == 0xFF).collect();
+    let expected: Vec<bool> = display.rows().flatten().collect();
+    assert_eq!(decoded, expected);
+    assert!(expected.contains(&true));
+}
+
+#[test]
+fn sprite_png_matches_rows() {
+    // A 3 row sprite at 0x202
+    let rom = [0x00, 0xE0, 0xF0, 0x81, 0x3C];
+    let sprite = Sprite {
+        addr: 0x202,
+        height: 3,
+    };
+
+    let mut png = Vec::new();
+    write_sprite_png(&sprite, &rom, &mut png).unwrap();
+
+    let mut reader = png::Decoder::new(&png[..]).read_info().unwrap();
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels).unwrap();
+    assert_eq!((info.width, info.height), (8, 3));
+    let rows: Vec<u8> = pixels
+        .chunks(8)
+        .map(|row| row.iter().fold(0, |byte, &p| byte << 1 | (p == 0xFF) as u8))
+        .collect();
+    assert_eq!(rows, [0xF0, 0x81, 0x3C]);
+}
@@ -0,0 +1,154 @@
+//! Self-checking ROMs, so test ROMs can say what they expect and be verified without a window. A
+//! self-test ROM ends with a table of expected values, then the number of entries in the table
+//! (one byte), then the 4 bytes `CHK8`. Each entry is 4 bytes:
+//!
+//! ```text
+//! 'V' 0x00 <register> <value>    register == value
+//! 'M' <addr hi> <addr lo> <value> mem[addr] == value
+//! ```
+//!
+//! The table is checked once the program ends, at `SYS 0` or a jump to itself. The trailer is
+//! loaded into memory with the rest of the ROM, so it should come after the last instruction.
+
+use std::fmt::{self, Display};
+
+use crate::cpu::{Chip8, StepResult, MEMORY_SIZE};
+use crate::instruction::Reg;
+
+const MARKER: &[u8; 4] = b"CHK8";
+const ENTRY_LEN: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Register(Reg),
+    Memory(u16),
+}
+
+/// A value the ROM expects to find when it ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Assertion {
+    pub target: Target,
+    pub expected: u8,
+}
+
+impl Assertion {
+    fn actual(&self, cpu: &Chip8) -> u8 {
+        match self.target {
+            Target::Register(r) => cpu.reg[r as usize],
+            Target::Memory(addr) => cpu.mem[addr as usize],
+        }
+    }
+}
+
+impl Display for Assertion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.target {
+            Target::Register(r) => write!(f, "v{:X} == {:#04x}", r, self.expected),
+            Target::Memory(addr) => write!(f, "mem[{:#05x}] == {:#04x}", addr, self.expected),
+        }
+    }
+}
+
+/// The assertions at the end of the ROM, or None if it isn't a self-test ROM
+pub fn parse_trailer(rom: &[u8]) -> Result<Option<Vec<Assertion>>, String> {
+    let Some(rest) = rom.strip_suffix(MARKER) else {
+        return Ok(None);
+    };
+    let Some((&count, rest)) = rest.split_last() else {
+        return Err("Missing assertion count before CHK8".to_string());
+    };
+    let table_len = count as usize * ENTRY_LEN;
+    if rest.len() < table_len {
+        return Err(format!("{} assertions don't fit in the ROM", count));
+    }
+    rest[rest.len() - table_len..]
+        .chunks(ENTRY_LEN)
+        .map(|entry| {
+            let target = match entry[0] {
+                b'V' if entry[1] == 0 && entry[2] < 16 => Target::Register(entry[2]),
+                b'M' => match u16::from_be_bytes([entry[1], entry[2]]) {
+                    addr if (addr as usize) < MEMORY_SIZE => Target::Memory(addr),
+                    addr => return Err(format!("Address out of range: {:#x}", addr)),
+                },
+                _ => return Err(format!("Invalid assertion: {:02x?}", entry)),
+            };
+            Ok(Assertion {
+                target,
+                expected: entry[3],
+            })
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+/// Run `cpu` until the program ends and check `assertions` against it. Returns a message for
+/// each one that failed.
+pub fn run(
+    cpu: &mut Chip8,
+    assertions: &[Assertion],
+    max_steps: u64,
+) -> Result<Vec<String>, String> {
+    let mut steps = 0;
+    while let StepResult::Continue(_) | StepResult::Breakpoint = cpu.step()? {
+        steps += 1;
+        if steps == max_steps {
+            return Err(format!("Program didn't end after {} steps", max_steps));
+        }
+    }
+    Ok(assertions
+        .iter()
+        .filter(|a| a.actual(cpu) != a.expected)
+        .map(|a| format!("Expected {}, got {:#04x}", a, a.actual(cpu)))
+        .collect())
+}
+
+/// A self-test ROM running `code`, then checking `table`
+#[cfg(test)]
+pub fn self_test_rom(code: &[crate::instruction::Instruction], table: &[[u8; 4]]) -> Vec<u8> {
+    let mut rom: Vec<u8> = code.iter().flat_map(|i| i.to_bytes()).collect();
+    rom.extend(table.iter().flatten());
+    rom.push(table.len() as u8);
+    rom.extend(MARKER);
+    rom
+}
+
+#[test]
+fn tiny_self_test_rom() {
+    use std::sync::{Arc, Mutex};
+
+    use crate::cpu::Chip8IO;
+    use crate::instruction::Instruction::*;
+
+    let code = [LOAD(0, 7), ADD(0, 3), LOADI(0x300), STOR(0), SYS(0)];
+    let table = [*b"V\x00\x00\x0A", *b"M\x03\x00\x0A"];
+    let rom = self_test_rom(&code, &table);
+    let assertions = parse_trailer(&rom).unwrap().unwrap();
+    assert_eq!(
+        assertions,
+        [
+            Assertion {
+                target: Target::Register(0),
+                expected: 10
+            },
+            Assertion {
+                target: Target::Memory(0x300),
+                expected: 10
+            }
+        ]
+    );
+
+    let mut cpu = Chip8::new(&rom, Arc::new(Mutex::new(Chip8IO::new())), false);
+    assert_eq!(run(&mut cpu, &assertions, 100), Ok(vec![]));
+
+    let rom = self_test_rom(&code, &[*b"V\x00\x00\x0B"]);
+    let assertions = parse_trailer(&rom).unwrap().unwrap();
+    let mut cpu = Chip8::new(&rom, Arc::new(Mutex::new(Chip8IO::new())), false);
+    assert_eq!(
+        run(&mut cpu, &assertions, 100),
+        Ok(vec!["Expected v0 == 0x0b, got 0x0a".to_string()])
+    );
+
+    assert_eq!(parse_trailer(&rom[..rom.len() - 1]), Ok(None));
+    assert!(parse_trailer(b"\x02CHK8").is_err());
+    assert!(parse_trailer(b"X\x00\x00\x00\x01CHK8").is_err());
+}
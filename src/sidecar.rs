@@ -0,0 +1,163 @@
+//! Per-ROM settings, read from an optional `<rom>.toml` next to the ROM (e.g. `PONG.ch8.toml`),
+//! so they don't have to be given on the command line every time:
+//!
+//! ```toml
+//! profile = "schip"
+//! ips = 700
+//! # QWERTY keys for CHIP-8 keys 0 to F
+//! keymap = "x123qweasdzc4rfv"
+//!
+//! [quirks]
+//! clip_sprites = false
+//! ```
+//!
+//! Only this subset of TOML is understood: one `key = value` per line, comments on their own
+//! line. Quirks are named after the `Quirks` fields and apply on top of the profile. Command line
+//! flags win over the sidecar.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::str::FromStr;
+
+use crate::cpu::Keymap;
+use crate::quirks::{Profile, Quirks};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RomConfig {
+    pub profile: Option<Profile>,
+    /// Quirks to switch on or off after applying the profile, by field name
+    pub quirks: Vec<(String, bool)>,
+    pub ips: Option<u64>,
+    pub keymap: Option<Keymap>,
+}
+
+impl RomConfig {
+    /// `base` (the quirks we'd use otherwise) with the sidecar's profile and quirks applied
+    pub fn quirks(&self, base: Quirks) -> Quirks {
+        let mut quirks = self.profile.map(Profile::quirks).unwrap_or(base);
+        for (name, enabled) in &self.quirks {
+            if let Some(quirk) = quirks.by_name(name) {
+                *quirk = *enabled;
+            }
+        }
+        quirks
+    }
+}
+
+fn parse_string(value: &str) -> Result<&str, String> {
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .ok_or_else(|| format!("expected a quoted string, got {}", value))
+}
+
+fn parse_keymap(value: &str) -> Result<Keymap, String> {
+    let keys: Vec<char> = parse_string(value)?.chars().collect();
+    match <Keymap>::try_from(keys) {
+        Ok(keymap) if keymap.iter().all(char::is_ascii_alphanumeric) => Ok(keymap),
+        _ => Err(format!(
+            "keymap should be 16 letters or digits, for keys 0 to F, got {}",
+            value
+        )),
+    }
+}
+
+impl FromStr for RomConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = RomConfig::default();
+        let mut in_quirks = false;
+        for (n, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let err = |e: String| format!("Line {}: {}", n + 1, e);
+            if line.starts_with('[') {
+                in_quirks = match line {
+                    "[quirks]" => true,
+                    _ => return Err(err(format!("unknown section {}", line))),
+                };
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| err("expected <key> = <value>".to_string()))?;
+            let (key, value) = (key.trim(), value.trim());
+            if in_quirks {
+                if Quirks::default().by_name(key).is_none() {
+                    return Err(err(format!("unknown quirk {}", key)));
+                }
+                let enabled = value
+                    .parse()
+                    .map_err(|_| err(format!("expected true or false, got {}", value)))?;
+                config.quirks.push((key.to_string(), enabled));
+                continue;
+            }
+            match key {
+                "profile" => config.profile = Some(parse_string(value).and_then(str::parse)?),
+                "ips" => {
+                    config.ips = Some(
+                        value
+                            .parse()
+                            .map_err(|_| err(format!("invalid ips {}", value)))?,
+                    )
+                }
+                "keymap" => config.keymap = Some(parse_keymap(value).map_err(err)?),
+                _ => return Err(err(format!("unknown setting {}", key))),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// The settings in the sidecar of the ROM at `rom_path`, or the defaults if it doesn't have one
+pub fn load(rom_path: &str) -> Result<RomConfig, String> {
+    let path = format!("{}.toml", rom_path);
+    match fs::read_to_string(&path) {
+        Ok(text) => text.parse().map_err(|e| format!("{}: {}", path, e)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(RomConfig::default()),
+        Err(e) => Err(format!("{}: {}", path, e)),
+    }
+}
+
+#[test]
+fn sidecar_configures_cpu() {
+    use std::sync::{Arc, Mutex};
+
+    use crate::cpu::{Chip8, Chip8IO};
+
+    let config: RomConfig = "
+        # Written for SUPER-CHIP, but expects sprites to wrap
+        profile = \"schip\"
+        ips = 700
+        keymap = \"x123qweasdzc4rfv\"
+
+        [quirks]
+        clip_sprites = false
+    "
+    .parse()
+    .unwrap();
+    assert_eq!(config.ips, Some(700));
+    assert_eq!(config.keymap.unwrap()[0xC], '4');
+
+    let cpu = Chip8::builder(&[], Arc::new(Mutex::new(Chip8IO::new())))
+        .rom_config(&config)
+        .build();
+    assert_eq!(
+        cpu.quirks,
+        Quirks {
+            clip_sprites: false,
+            ..Profile::SuperChip.quirks()
+        }
+    );
+
+    assert!("ips = fast".parse::<RomConfig>().is_err());
+    assert!("keymap = \"abc\"".parse::<RomConfig>().is_err());
+    assert!("[quirks]\nno_such_quirk = true"
+        .parse::<RomConfig>()
+        .is_err());
+    assert!("[display]".parse::<RomConfig>().is_err());
+    assert_eq!(load("/nonexistent/rom.ch8"), Ok(RomConfig::default()));
+}
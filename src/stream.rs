@@ -0,0 +1,143 @@
+//! Serves the display over TCP, so another machine can watch (and play) the ROM.
+//!
+//! Each message is a big-endian `u16` length followed by that many bytes. The server sends a
+//! message whenever the display changes, holding the display as packed by
+//! `Chip8IO::as_bitmap`: 256 bytes for 64x32, or 1024 bytes for 128x64. The client can send
+//! its keypad at any time as a 2 byte message, with bit n set while key n is held. One client
+//! is served at a time; the next one is accepted when it disconnects.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::cpu::{Chip8IO, TIMER_PERIOD};
+
+pub fn write_message(out: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(payload.len())
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "message too long"))?;
+    out.write_all(&len.to_be_bytes())?;
+    out.write_all(payload)
+}
+
+#[cfg(test)]
+pub fn read_message(input: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0; 2];
+    input.read_exact(&mut len)?;
+    let mut payload = vec![0; u16::from_be_bytes(len) as usize];
+    input.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+#[cfg(test)]
+pub fn encode_keys(keystate: &[bool; 16]) -> [u8; 2] {
+    let bits = (0..16)
+        .filter(|&key| keystate[key])
+        .fold(0u16, |bits, key| bits | 1 << key);
+    bits.to_be_bytes()
+}
+
+pub fn decode_keys(bytes: &[u8]) -> Result<[bool; 16], String> {
+    let bits = <[u8; 2]>::try_from(bytes)
+        .map(u16::from_be_bytes)
+        .map_err(|_| format!("Expected 2 bytes of keys, got {}", bytes.len()))?;
+    Ok(std::array::from_fn(|key| bits & (1 << key) != 0))
+}
+
+/// Collects messages from a stream that times out between reads, without losing partial ones
+#[derive(Debug, Default)]
+struct MessageReader {
+    buf: Vec<u8>,
+}
+
+impl MessageReader {
+    /// The next whole message, or `None` if it hasn't all arrived before the read timed out
+    fn poll(&mut self, input: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+        let mut chunk = [0; 64];
+        match input.read(&mut chunk) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => self.buf.extend(&chunk[..n]),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => return Err(e),
+        }
+        if self.buf.len() < 2 {
+            return Ok(None);
+        }
+        let len = u16::from_be_bytes([self.buf[0], self.buf[1]]) as usize;
+        if self.buf.len() < 2 + len {
+            return Ok(None);
+        }
+        let message = self.buf[2..2 + len].to_vec();
+        self.buf.drain(..2 + len);
+        Ok(Some(message))
+    }
+}
+
+/// Send frames to `client` and apply its keys until it disconnects
+fn serve_client(mut client: TcpStream, io: &Mutex<Chip8IO>) -> io::Result<()> {
+    client.set_read_timeout(Some(TIMER_PERIOD))?;
+    let mut reader = MessageReader::default();
+    let mut sent = Vec::new();
+    loop {
+        let bitmap = io.lock().unwrap().as_bitmap();
+        if bitmap != sent {
+            write_message(&mut client, &bitmap)?;
+            sent = bitmap;
+        }
+        // Also waits for the next frame
+        if let Some(message) = reader.poll(&mut client)? {
+            let keys =
+                decode_keys(&message).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            io.lock().unwrap().remote_keys = keys;
+        }
+    }
+}
+
+/// Serve the display on `listener` in the background, one client at a time
+pub fn serve(listener: TcpListener, io: Arc<Mutex<Chip8IO>>) {
+    thread::spawn(move || {
+        for client in listener.incoming() {
+            let result = client.and_then(|client| serve_client(client, &io));
+            if let Err(e) = result {
+                if e.kind() != ErrorKind::UnexpectedEof {
+                    println!("Display stream: {}", e);
+                }
+            }
+            // Don't leave the last client's keys held down
+            io.lock().unwrap().remote_keys = [false; 16];
+        }
+    });
+}
+
+#[test]
+fn stream_frame_and_keys_over_loopback() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let io = Arc::new(Mutex::new(Chip8IO::new()));
+    io.lock().unwrap().display.set(0, 0, true);
+    serve(listener, io.clone());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    let frame = read_message(&mut client).unwrap();
+    assert_eq!(frame, io.lock().unwrap().as_bitmap());
+    let mut copy = Chip8IO::new();
+    copy.set_display_from_bitmap(&frame).unwrap();
+    assert!(copy.display.get(0, 0));
+
+    // A change to the display is sent as a new frame
+    io.lock().unwrap().display.set(1, 1, true);
+    let frame = read_message(&mut client).unwrap();
+    copy.set_display_from_bitmap(&frame).unwrap();
+    assert!(copy.display.get(1, 1));
+
+    let mut keys = [false; 16];
+    keys[0xA] = true;
+    write_message(&mut client, &encode_keys(&keys)).unwrap();
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while io.lock().unwrap().remote_keys != keys {
+        assert!(std::time::Instant::now() < deadline, "keys never arrived");
+        thread::sleep(TIMER_PERIOD);
+    }
+    assert_eq!(decode_keys(&encode_keys(&keys)), Ok(keys));
+    assert!(decode_keys(&[1]).is_err());
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::instruction::{Addr, Reg};
+
+/// Names for registers and addresses, to make disassembly easier to read. Parsed from one
+/// `name = value` per line, where the value is a register (`v3`) or an address (`0x2A0`), or
+/// `0x2A0 name` for addresses. Blank lines and lines starting with `#` are ignored.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Symbols {
+    pub registers: HashMap<Reg, String>,
+    pub addresses: HashMap<Addr, String>,
+}
+
+impl FromStr for Symbols {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut symbols = Symbols::default();
+        for (n, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = match (line.split_once('='), line.split_once(char::is_whitespace)) {
+                (Some((name, value)), _) => (name.trim().to_string(), value.trim()),
+                (None, Some((addr, name))) if addr.starts_with("0x") => {
+                    (name.trim().to_string(), addr)
+                }
+                _ => {
+                    return Err(format!(
+                        "Line {}: expected <name> = <value> or <address> <name>",
+                        n + 1
+                    ))
+                }
+            };
+            if let Some(reg) = value.strip_prefix(['v', 'V']) {
+                match u8::from_str_radix(reg, 16) {
+                    Ok(reg) if reg < 16 => symbols.registers.insert(reg, name),
+                    _ => return Err(format!("Line {}: invalid register {}", n + 1, value)),
+                };
+            } else {
+                let digits = value.strip_prefix("0x").unwrap_or(value);
+                match u16::from_str_radix(digits, 16) {
+                    Ok(addr) => symbols.addresses.insert(addr, name),
+                    _ => return Err(format!("Line {}: invalid address {}", n + 1, value)),
+                };
+            }
+        }
+        Ok(symbols)
+    }
+}
+
+impl Symbols {
+    /// `addr` in hex, followed by its name if it has one
+    pub fn describe(&self, addr: Addr) -> String {
+        match self.addresses.get(&addr) {
+            Some(name) => format!("{:#05x} ({})", addr, name),
+            None => format!("{:#05x}", addr),
+        }
+    }
+}
+
+#[test]
+fn parse_symbols() {
+    let symbols: Symbols = "
+        # Player state
+        score = v3
+        main_loop = 0x2A0
+        0x2B4 draw_score
+    "
+    .parse()
+    .unwrap();
+    assert_eq!(symbols.registers, HashMap::from([(3, "score".to_string())]));
+    assert_eq!(
+        symbols.addresses,
+        HashMap::from([
+            (0x2A0, "main_loop".to_string()),
+            (0x2B4, "draw_score".to_string())
+        ])
+    );
+    assert_eq!(symbols.describe(0x2B4), "0x2b4 (draw_score)");
+    assert_eq!(symbols.describe(0x2B6), "0x2b6");
+
+    assert!("score v3".parse::<Symbols>().is_err());
+    assert!("score = vG".parse::<Symbols>().is_err());
+    assert!("far = 0x10000".parse::<Symbols>().is_err());
+    assert!("main_loop 0x2A0".parse::<Symbols>().is_err());
+}
@@ -0,0 +1,93 @@
+use std::fmt::Debug;
+use std::mem::{self, Discriminant};
+use std::str::FromStr;
+
+use crate::instruction::{Instruction, Reg};
+
+/// Which executed instructions to trace, so the trace can focus on e.g. drawing or input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFilter {
+    /// Every instruction of this kind, e.g. `DRAW`
+    Opcode(Discriminant<Instruction>),
+    /// Instructions at addresses from the first up to (not including) the second, e.g.
+    /// `0x200..0x240`
+    PcRange(u16, u16),
+    /// Instructions that read or write this register, e.g. `v3`
+    Register(Reg),
+}
+
+impl TraceFilter {
+    pub fn matches(&self, pc: u16, instr: Instruction) -> bool {
+        match *self {
+            TraceFilter::Opcode(kind) => mem::discriminant(&instr) == kind,
+            TraceFilter::PcRange(start, end) => (start..end).contains(&pc),
+            TraceFilter::Register(reg) => {
+                instr.reads().contains(&reg) || instr.writes().contains(&reg)
+            }
+        }
+    }
+}
+
+fn parse_addr(s: &str) -> Result<u16, String> {
+    let digits = s.trim_start_matches("0x");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Invalid address: {}", s))
+}
+
+impl FromStr for TraceFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((start, end)) = s.split_once("..") {
+            return Ok(TraceFilter::PcRange(parse_addr(start)?, parse_addr(end)?));
+        }
+        if s.starts_with(['v', 'V']) {
+            return crate::parse_register(s).map(TraceFilter::Register);
+        }
+        Instruction::from_mnemonic(s)
+            .map(|kind| TraceFilter::Opcode(mem::discriminant(&kind)))
+            .ok_or_else(|| {
+                format!(
+                    "Unknown trace filter {} (expected a mnemonic, register or address range)",
+                    s
+                )
+            })
+    }
+}
+
+/// Where the CPU sends the instructions it traces
+pub trait TraceSink: Debug + Send {
+    /// `instruction` at `pc` was executed, leaving the CPU in `state`
+    fn traced(&mut self, pc: u16, instruction: Instruction, state: String);
+}
+
+/// Prints the CPU state after each traced instruction
+#[derive(Debug)]
+pub struct PrintTrace;
+
+impl TraceSink for PrintTrace {
+    fn traced(&mut self, _pc: u16, _instruction: Instruction, state: String) {
+        println!("{}", state);
+    }
+}
+
+#[test]
+fn parse_and_match_filters() {
+    use Instruction::*;
+
+    let draw: TraceFilter = "draw".parse().unwrap();
+    assert!(draw.matches(0x200, DRAW(1, 2, 3)));
+    assert!(!draw.matches(0x200, CLR));
+
+    let range: TraceFilter = "0x200..0x204".parse().unwrap();
+    assert_eq!(range, TraceFilter::PcRange(0x200, 0x204));
+    assert!(range.matches(0x202, CLR));
+    assert!(!range.matches(0x204, CLR));
+
+    let v3: TraceFilter = "v3".parse().unwrap();
+    assert!(v3.matches(0x200, ADDR(1, 3)));
+    assert!(v3.matches(0x200, LOAD(3, 0)));
+    assert!(!v3.matches(0x200, LOAD(2, 0)));
+
+    assert!("vG".parse::<TraceFilter>().is_err());
+    assert!("BLAH".parse::<TraceFilter>().is_err());
+}
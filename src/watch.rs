@@ -0,0 +1,53 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::cpu::Chip8;
+
+/// Reload the ROM into `cpu` whenever the file at `rom` changes. The watch stops when the
+/// returned watcher is dropped.
+pub fn watch_rom(rom: &str, cpu: Arc<Mutex<Chip8>>) -> notify::Result<RecommendedWatcher> {
+    let rom_path = PathBuf::from(rom);
+    // Watch the directory rather than the file itself, since many editors and assemblers replace
+    // the file instead of writing to it in place
+    let dir = match rom_path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let file_name = rom_path.file_name().map(|n| n.to_owned());
+
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+        let event = match res {
+            Ok(event) => event,
+            Err(e) => {
+                println!("Watch error: {}", e);
+                return;
+            }
+        };
+        if !(event.kind.is_modify() || event.kind.is_create()) {
+            return;
+        }
+        if !event
+            .paths
+            .iter()
+            .any(|p| p.file_name() == file_name.as_deref())
+        {
+            return;
+        }
+
+        match fs::read(&rom_path) {
+            Ok(bytes) => {
+                println!("Reloading {}", rom_path.display());
+                if let Err(e) = cpu.lock().unwrap().load_rom(&bytes) {
+                    println!("Could not reload {}: {}", rom_path.display(), e);
+                }
+            }
+            Err(e) => println!("Could not reload {}: {}", rom_path.display(), e),
+        }
+    })?;
+    watcher.watch(Path::new(&dir), RecursiveMode::NonRecursive)?;
+
+    Ok(watcher)
+}